@@ -1,28 +1,29 @@
-use crate::bus::Bus32;
+use crate::bus::{Bus, Bus32};
 use crate::decoders::decoder_2x4;
-use crate::main_memory::{fast_decode, fast_encode};
+use crate::logic::Logic;
+use crate::main_memory::{decode, encode};
 
-fn adder(a: bool, b: bool, carry_in: bool) -> (bool, bool) {
+fn adder<T: Logic>(a: T, b: T, carry_in: T) -> (T, T) {
     let (sum1, carry1) = half_adder(a, b);
     let (sum, carry2) = half_adder(sum1, carry_in);
-    let carry_out = carry1 || carry2;
+    let carry_out = carry1 | carry2;
     (sum, carry_out)
 }
 
 
-fn half_adder(a: bool, b: bool) -> (bool, bool) { (a ^ b, a && b) }
+fn half_adder<T: Logic>(a: T, b: T) -> (T, T) { (a ^ b, a & b) }
 
-pub struct AluControl {
-    f0: bool,
-    f1: bool,
-    en_a: bool,
-    en_b: bool,
-    inv_a: bool,
-    inc: bool,
+pub struct AluControl<T: Logic = bool> {
+    f0: T,
+    f1: T,
+    en_a: T,
+    en_b: T,
+    inv_a: T,
+    inc: T,
 }
 
-impl AluControl {
-    pub fn from(code: [bool; 6]) -> AluControl {
+impl<T: Logic> AluControl<T> {
+    pub fn from(code: [T; 6]) -> AluControl<T> {
         AluControl {
             f0: code[0],
             f1: code[1],
@@ -33,6 +34,11 @@ impl AluControl {
         }
     }
 
+    /// ALU takes the B bus
+    pub fn en_b(&self) -> T { self.en_b }
+}
+
+impl AluControl {
     fn new() -> AluControl { AluControl { f0: false, f1: false, en_a: false, en_b: false, inv_a: false, inc: false } }
 
     fn f0(&mut self) -> &mut AluControl {
@@ -71,63 +77,186 @@ impl AluControl {
     fn alu_a() -> AluControl { AluControl { f0: false, f1: true, en_a: true, en_b: false, inv_a: false, inc: false } }
 }
 
-fn alu_unit(a: bool, b: bool, inv_a: bool, en_a: bool, en_b: bool, carry_in: bool, f0: bool, f1: bool) -> (bool, bool) {
-    let a_enabled = a && en_a;
-    let b_signal = b && en_b;
-    let a_signal = a_enabled ^ inv_a;
+/// Adder used by the ALU, all of them compute the same sum
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Adder {
+    /// Carry goes through every one-bit adder
+    Ripple,
+    /// Carries inside of 4-bit groups are computed from generate and propagate signals
+    CarryLookahead,
+    /// Every 4-bit group computes the sum for both carries, the real carry selects one of them
+    CarrySelect,
+}
 
-    // Decode allow signals
-    // f1 and f0 should be in this order because mic-1 uses different bit ordering
-    let allowed = decoder_2x4(f1, f0);
+impl Adder {
+    /// Sum and carry out. Faster adders work with 4-bit groups, so the width should be a multiple of 4.
+    pub fn add<T: Logic, const W: usize>(self, a: [T; W], b: [T; W], carry_in: T) -> ([T; W], T) {
+        match self {
+            Adder::Ripple => ripple_add(&a, &b, carry_in),
+            Adder::CarryLookahead => lookahead_add(&a, &b, carry_in),
+            Adder::CarrySelect => select_add(&a, &b, carry_in),
+        }
+    }
+}
+
+fn ripple_add<T: Logic, const W: usize>(a: &[T], b: &[T], carry_in: T) -> ([T; W], T) {
+    let mut result = [T::constant(false); W];
+    let mut carry = carry_in;
+    for i in 0..a.len() {
+        let (sum, carry_out) = adder(a[i], b[i], carry);
+        result[i] = sum;
+        carry = carry_out;
+    }
+    (result, carry)
+}
+
+fn lookahead_add<T: Logic, const W: usize>(a: &[T; W], b: &[T; W], carry_in: T) -> ([T; W], T) {
+    let mut result = [T::constant(false); W];
+    let mut carry = carry_in;
+    for group in 0..W / 4 {
+        let mut generate = [T::constant(false); 4];
+        let mut propagate = [T::constant(false); 4];
+        for i in 0..4 {
+            let (half_sum, half_carry) = half_adder(a[group * 4 + i], b[group * 4 + i]);
+            propagate[i] = half_sum;
+            generate[i] = half_carry;
+        }
+
+        // c[i] = g[i - 1] | p[i - 1] & g[i - 2] | ... | p[i - 1] & ... & p[0] & c[0]
+        let mut carries = [carry; 5];
+        for i in 1..5 {
+            let mut term = generate[i - 1];
+            let mut all_propagate = propagate[i - 1];
+            for k in (0..i - 1).rev() {
+                term = term | all_propagate & generate[k];
+                all_propagate = all_propagate & propagate[k];
+            }
+            carries[i] = term | all_propagate & carry;
+        }
+
+        for i in 0..4 {
+            result[group * 4 + i] = propagate[i] ^ carries[i];
+        }
+        carry = carries[4];
+    }
+    (result, carry)
+}
 
+fn select_add<T: Logic, const W: usize>(a: &[T; W], b: &[T; W], carry_in: T) -> ([T; W], T) {
+    let mut result = [T::constant(false); W];
+    let mut carry = carry_in;
+    for group in 0..W / 4 {
+        let bits = group * 4..group * 4 + 4;
+        let (sum_0, carry_0): ([T; 4], T) = ripple_add(&a[bits.clone()], &b[bits.clone()], T::constant(false));
+        let (sum_1, carry_1): ([T; 4], T) = ripple_add(&a[bits.clone()], &b[bits], T::constant(true));
+        for i in 0..4 {
+            result[group * 4 + i] = sum_0[i] & !carry | sum_1[i] & carry;
+        }
+        carry = carry_0 & !carry | carry_1 & carry;
+    }
+    (result, carry)
+}
+
+fn alu_unit<T: Logic>(a_signal: T, b_signal: T, sum: T, allowed: [T; 4]) -> T {
     // Compute simple resultes
-    let a_and_b_res = (a_signal && b_signal) && allowed[0];
-    let a_or_b_res = (a_signal || b_signal) && allowed[1];
-    let not_b_res = !b_signal && allowed[2];
+    let a_and_b_res = (a_signal & b_signal) & allowed[0];
+    let a_or_b_res = (a_signal | b_signal) & allowed[1];
+    let not_b_res = !b_signal & allowed[2];
 
     // A and B sum
-    let (a_plus_b_res_temp, carry_temp) = adder(a_signal, b_signal, carry_in);
-    let a_plus_b_res = a_plus_b_res_temp && allowed[3];
-    let carry_out = carry_temp && allowed[3];
+    let a_plus_b_res = sum & allowed[3];
 
     // Final result
-    let res = a_and_b_res || a_or_b_res || not_b_res || a_plus_b_res;
+    a_and_b_res | a_or_b_res | not_b_res | a_plus_b_res
+}
+
+/// Negative, zero, carry out and signed overflow. Carry and overflow are set only by the sum.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AluFlags<T: Logic = bool> {
+    pub n: T,
+    pub z: T,
+    pub c: T,
+    pub v: T,
+}
 
-    (res, carry_out)
+pub fn alu_32(a: Bus32, b: Bus32, control: AluControl, adder: Adder) -> (Bus32, AluFlags) {
+    alu(a, b, control, adder)
 }
 
-pub fn alu_32(a: Bus32, b: Bus32, control: AluControl) -> (Bus32, bool, bool) {
-    let mut result = [false; 32];
+pub fn alu<const W: usize>(a: Bus<W>, b: Bus<W>, control: AluControl, adder: Adder) -> (Bus<W>, AluFlags) {
+    let (result, flags) = alu_word(a.data, b.data, &control, adder);
+    (Bus::from(result), flags)
+}
 
-    let mut carry = control.inc;
-    for i in 0..32 {
-        let (res, alu_carry) = alu_unit(a.data[i], b.data[i], control.inv_a, control.en_a, control.en_b, carry, control.f0, control.f1);
-        result[i] = res;
-        carry = alu_carry;
+pub fn alu_word<T: Logic, const W: usize>(a: [T; W], b: [T; W], control: &AluControl<T>, adder: Adder) -> ([T; W], AluFlags<T>) {
+    let mut a_signal = [T::constant(false); W];
+    let mut b_signal = [T::constant(false); W];
+    for i in 0..W {
+        a_signal[i] = a[i] & control.en_a ^ control.inv_a;
+        b_signal[i] = b[i] & control.en_b;
     }
 
-    let n_bit = result[31];
-    let mut z_bit = false;
-    for i in 0..32 {
-        z_bit |= result[i];
+    // Decode allow signals
+    // f1 and f0 should be in this order because mic-1 uses different bit ordering
+    let allowed = decoder_2x4(control.f1, control.f0);
+
+    let (sum, carry) = adder.add(a_signal, b_signal, control.inc);
+
+    let mut result = [T::constant(false); W];
+    for i in 0..W {
+        result[i] = alu_unit(a_signal[i], b_signal[i], sum[i], allowed);
+    }
+
+    let sign = W - 1;
+    let n_bit = result[sign];
+    let mut z_bit = T::constant(false);
+    for i in 0..W {
+        z_bit = z_bit | result[i];
     }
     z_bit = !z_bit;
 
-    (Bus32::from(result), n_bit, z_bit)
+    // Operands of the same sign give the sum of the other sign
+    let overflow = !(a_signal[sign] ^ b_signal[sign]) & (a_signal[sign] ^ sum[sign]);
+
+    let flags = AluFlags { n: n_bit, z: z_bit, c: carry & allowed[3], v: overflow & allowed[3] };
+    (result, flags)
 }
 
-fn alu_32_i(a: i32, b: i32, control: AluControl) -> (i32, bool, bool) {
-    let a_bus = Bus32::from(fast_decode(a));
-    let b_bus = Bus32::from(fast_decode(b));
+fn alu_i<const W: usize>(a: i64, b: i64, control: AluControl, adder: Adder) -> (i64, AluFlags) {
+    let (alu_res, flags) = alu::<W>(Bus::from(decode(a)), Bus::from(decode(b)), control, adder);
+    (encode(&alu_res.data), flags)
+}
 
-    let (alu_res, n, z) = alu_32(a_bus, b_bus, control);
-    return (fast_encode(&alu_res.data), n, z);
+fn alu_32_i(a: i32, b: i32, control: AluControl) -> (i32, bool, bool) {
+    let (res, flags) = alu_i::<32>(a as i64, b as i64, control, Adder::Ripple);
+    (res as i32, flags.n, flags.z)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Value of the number in the `W`-bit word
+    fn wrap<const W: usize>(value: i64) -> i64 { encode(&decode::<W>(value)) }
+
+    fn unsigned<const W: usize>(value: i64) -> u128 { value as u128 & (u128::MAX >> (128 - W)) }
+
+    fn check_width<const W: usize>(a: i64, b: i64, control: fn() -> AluControl, operation: fn(i64, i64) -> i64) {
+        let (a, b) = (wrap::<W>(a), wrap::<W>(b));
+        let (res, flags) = alu_i::<W>(a, b, control(), Adder::Ripple);
+        let expected = wrap::<W>(operation(a, b));
+        assert_eq!(expected, res, "Width: {}", W);
+        assert_eq!(expected < 0, flags.n, "Width: {}", W);
+        assert_eq!(expected == 0, flags.z, "Width: {}", W);
+    }
+
+    /// Checks the ALU of 16, 32 and 64 bits against the operation on numbers
+    fn check_widths(a: i64, b: i64, control: fn() -> AluControl, operation: fn(i64, i64) -> i64) {
+        check_width::<16>(a, b, control, operation);
+        check_width::<32>(a, b, control, operation);
+        check_width::<64>(a, b, control, operation);
+    }
+
     #[test]
     fn decrement() {
         let (res, n, z) = alu_32_i(0, 1, AluControl::alu_b_dec());
@@ -201,83 +330,125 @@ mod tests {
     }
 
     #[quickcheck]
-    fn quick_sum(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_sum());
-        let res = a + b;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_sum(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_sum, |a, b| a.wrapping_add(b));
+    }
+
+    #[quickcheck]
+    fn quick_dec(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_b_dec, |_, b| b.wrapping_sub(1));
+    }
+
+    #[quickcheck]
+    fn quick_inc(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_b_inc, |_, b| b.wrapping_add(1));
+    }
+
+    #[quickcheck]
+    fn quick_sum_inc(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_sum_inc, |a, b| a.wrapping_add(b).wrapping_add(1));
     }
 
     #[quickcheck]
-    fn quick_dec(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_b_dec());
-        let res = b - 1;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_sub(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_sub, |a, b| b.wrapping_sub(a));
     }
 
     #[quickcheck]
-    fn quick_inc(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_b_inc());
-        let res = b + 1;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_and(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_and, |a, b| b & a);
     }
 
     #[quickcheck]
-    fn quick_sum_inc(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_sum_inc());
-        let res = a + b + 1;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_or(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_or, |a, b| b | a);
     }
 
     #[quickcheck]
-    fn quick_sub(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_sub());
-        let res = b - a;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_b(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_b, |_, b| b);
     }
 
     #[quickcheck]
-    fn quick_and(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_and());
-        let res = b & a;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_a(a: i64, b: i64) {
+        check_widths(a, b, AluControl::alu_a, |a, _| a);
+    }
+
+    fn adders_are_equivalent_width<const W: usize>(a: i64, b: i64, bits: [bool; 6]) {
+        let (a, b) = (wrap::<W>(a), wrap::<W>(b));
+        let ripple = alu_i::<W>(a, b, AluControl::from(bits), Adder::Ripple);
+        assert_eq!(ripple, alu_i::<W>(a, b, AluControl::from(bits), Adder::CarryLookahead), "Width: {}", W);
+        assert_eq!(ripple, alu_i::<W>(a, b, AluControl::from(bits), Adder::CarrySelect), "Width: {}", W);
+    }
+
+    #[quickcheck]
+    fn adders_are_equivalent(a: i64, b: i64, code: u8) {
+        let mut bits = [false; 6];
+        for i in 0..6 {
+            bits[i] = code & (1 << i) != 0;
+        }
+        adders_are_equivalent_width::<16>(a, b, bits);
+        adders_are_equivalent_width::<32>(a, b, bits);
+        adders_are_equivalent_width::<64>(a, b, bits);
+    }
+
+    fn adders_carry_out_width<const W: usize>(a: i64, b: i64, carry_in: bool) {
+        let expected = unsigned::<W>(a) + unsigned::<W>(b) + carry_in as u128;
+        for adder in [Adder::Ripple, Adder::CarryLookahead, Adder::CarrySelect].iter() {
+            let (sum, carry) = adder.add(decode::<W>(a), decode::<W>(b), carry_in);
+            assert_eq!(wrap::<W>(expected as i64), encode(&sum), "{:?}, width: {}", adder, W);
+            assert_eq!(expected >> W != 0, carry, "{:?}, width: {}", adder, W);
+        }
     }
 
     #[quickcheck]
-    fn quick_or(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_or());
-        let res = b | a;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn adders_carry_out(a: i64, b: i64, carry_in: bool) {
+        adders_carry_out_width::<16>(a, b, carry_in);
+        adders_carry_out_width::<32>(a, b, carry_in);
+        adders_carry_out_width::<64>(a, b, carry_in);
+    }
+
+    fn carry_and_overflow_width<const W: usize>(a: i64, b: i64) {
+        let (a, b) = (wrap::<W>(a), wrap::<W>(b));
+        let (_, flags) = alu_i::<W>(a, b, AluControl::alu_sum(), Adder::Ripple);
+        assert_eq!((unsigned::<W>(a) + unsigned::<W>(b)) >> W != 0, flags.c, "Width: {}", W);
+        let sum = a as i128 + b as i128;
+        assert_eq!(sum != wrap::<W>(sum as i64) as i128, flags.v, "Width: {}", W);
     }
 
     #[quickcheck]
-    fn quick_b(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_b());
-        let res = b;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_carry_and_overflow(a: i64, b: i64) {
+        carry_and_overflow_width::<16>(a, b);
+        carry_and_overflow_width::<32>(a, b);
+        carry_and_overflow_width::<64>(a, b);
+    }
+
+    fn sub_carry_width<const W: usize>(a: i64, b: i64) {
+        let (a, b) = (wrap::<W>(a), wrap::<W>(b));
+        // b - a = b + !a + 1, no borrow means carry
+        let (_, flags) = alu_i::<W>(a, b, AluControl::alu_sub(), Adder::CarryLookahead);
+        assert_eq!(unsigned::<W>(b) >= unsigned::<W>(a), flags.c, "Width: {}", W);
+        let difference = b as i128 - a as i128;
+        assert_eq!(difference != wrap::<W>(difference as i64) as i128, flags.v, "Width: {}", W);
+    }
+
+    #[quickcheck]
+    fn quick_sub_carry(a: i64, b: i64) {
+        sub_carry_width::<16>(a, b);
+        sub_carry_width::<32>(a, b);
+        sub_carry_width::<64>(a, b);
+    }
+
+    fn no_carry_in_logic_width<const W: usize>(a: i64, b: i64) {
+        let (_, flags) = alu_i::<W>(wrap::<W>(a), wrap::<W>(b), AluControl::alu_or(), Adder::Ripple);
+        assert_eq!(false, flags.c, "Width: {}", W);
+        assert_eq!(false, flags.v, "Width: {}", W);
     }
 
     #[quickcheck]
-    fn quick_a(a: i32, b: i32) {
-        let (res, n, z) = alu_32_i(a, b, AluControl::alu_a());
-        let res = a;
-        assert_eq!(res, res);
-        assert_eq!(res < 0, n);
-        assert_eq!(res == 0, z);
+    fn quick_no_carry_in_logic(a: i64, b: i64) {
+        no_carry_in_logic_width::<16>(a, b);
+        no_carry_in_logic_width::<32>(a, b);
+        no_carry_in_logic_width::<64>(a, b);
     }
 }
@@ -0,0 +1,92 @@
+use crate::PROGRAM_START;
+
+/**
+ * Architectural state of the IJVM machine between two instructions.
+ *
+ * Both `Mic1` and `Ijvm` can be converted to and created from this state,
+ *   so a program can be moved from one model to another in the middle of the run.
+ * `pc` points to the opcode of the next instruction.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArchState {
+    pub pc: i32,
+    pub sp: i32,
+    pub lv: i32,
+    pub cpp: i32,
+    pub tos: i32,
+    /// The stack may only use the cells from `stack_base` to `stack_limit`
+    pub stack_base: i32,
+    pub stack_limit: i32,
+    pub memory: Vec<i32>,
+}
+
+/// Frame of the invoked method. The main program frame has no link pointer.
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub lv: i32,
+    pub link: Option<i32>,
+    pub return_address: Option<i32>,
+}
+
+impl ArchState {
+    /**
+     * Frame chain from the current method down to the main program.
+     *
+     * A frame is a method frame if its link pointer points into the frame itself and the saved
+     *   caller's LV and return address look valid. The first frame without such a link is the main program.
+     */
+    pub fn frames(&self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut lv = self.lv;
+        let mut top = self.sp;
+        loop {
+            let link = self.read(lv);
+            let return_address = self.read(link);
+            let caller_lv = self.read(link + 1);
+            if lv < link && link < top && caller_lv <= lv && return_address >= PROGRAM_START as i32 {
+                frames.push(Frame { lv, link: Some(link), return_address: Some(return_address) });
+                top = lv;
+                lv = caller_lv;
+            } else {
+                frames.push(Frame { lv, link: None, return_address: None });
+                return frames;
+            }
+        }
+    }
+
+    fn read(&self, address: i32) -> i32 { *self.memory.get(address as usize).unwrap_or(&0) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::STACK_START;
+
+    use super::*;
+
+    #[test]
+    fn main_frame() {
+        let state = ArchState { pc: 100, sp: 12, lv: STACK_START, cpp: 0, tos: 3, stack_base: STACK_START, stack_limit: 99, memory: vec![0; 20] };
+        assert_eq!(vec![Frame { lv: STACK_START, link: None, return_address: None }], state.frames());
+    }
+
+    #[test]
+    fn nested_frames() {
+        let mut memory = vec![0; 30];
+        // First method is called with an empty stack, so it has the same LV as the main program
+        memory[10] = 12;
+        memory[12] = 105;
+        memory[13] = STACK_START;
+        // Second method frame
+        memory[15] = 17;
+        memory[17] = 120;
+        memory[18] = 10;
+        let state = ArchState { pc: 130, sp: 18, lv: 15, cpp: 0, tos: 15, stack_base: STACK_START, stack_limit: 99, memory };
+
+        let expected = vec![
+            Frame { lv: 15, link: Some(17), return_address: Some(120) },
+            Frame { lv: 10, link: Some(12), return_address: Some(105) },
+            Frame { lv: STACK_START, link: None, return_address: None },
+        ];
+        assert_eq!(expected, state.frames());
+    }
+}
@@ -1,8 +1,12 @@
 #![allow(non_camel_case_types)]
 
+use std::fmt;
+
 use crate::asm::IjvmCommand::{*};
+use crate::asm::OperandKind::{Byte, Const, ConstantIndex, LabelOffset, MethodIndex, SignedByte, Varnum};
 
 //noinspection SpellCheckingInspection
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum IjvmCommand {
     BIPUSH = 0x10,
     DUP = 0x59,
@@ -17,8 +21,11 @@ pub enum IjvmCommand {
     INVOKEVIRTUAL = 0xB6,
     IOR = 0x80,
     IRETURN = 0xAC,
+    ISHL = 0x78,
+    ISHR = 0x7A,
     ISTORE = 0x36,
     ISUB = 0x64,
+    IUSHR = 0x7C,
     LDC_W = 0x13,
     NOP = 0x00,
     POP = 0x57,
@@ -28,28 +35,318 @@ pub enum IjvmCommand {
 
 impl IjvmCommand {
     pub fn parse(str: &str) -> Option<IjvmCommand> {
-        match str {
-            "BIPUSH" => Option::Some(BIPUSH),
-            "DUP" => Option::Some(DUP),
-            "GOTO" => Option::Some(GOTO),
-            "IADD" => Option::Some(IADD),
-            "IAND" => Option::Some(IAND),
-            "IFEQ" => Option::Some(IFEQ),
-            "IFLT" => Option::Some(IFLT),
-            "IF_ICMPEQ" => Option::Some(IF_ICMPEQ),
-            "IINC" => Option::Some(IINC),
-            "ILOAD" => Option::Some(ILOAD),
-            "INVOKEVIRTUAL" => Option::Some(INVOKEVIRTUAL),
-            "IOR" => Option::Some(IOR),
-            "IRETURN" => Option::Some(IRETURN),
-            "ISTORE" => Option::Some(ISTORE),
-            "ISUB" => Option::Some(ISUB),
-            "LDC_W" => Option::Some(LDC_W),
-            "NOP" => Option::Some(NOP),
-            "POP" => Option::Some(POP),
-            "SWAP" => Option::Some(SWAP),
-            "WIDE" => Option::Some(WIDE),
-            _ => Option::None
+        STANDARD.iter().find(|x| x.1 == str).map(|x| x.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OperandKind {
+    Byte,
+    /// Byte with a sign, e.g. the value of BIPUSH
+    SignedByte,
+    /// Index of the local variable, two bytes after WIDE
+    Varnum,
+    /// Increment of IINC, a signed byte or two signed bytes after WIDE, as the microcode adds it
+    Const,
+    /// Signed 16-bit offset of the branch target from the opcode
+    LabelOffset,
+    /// 16-bit index in the constant pool
+    ConstantIndex,
+    /// 16-bit index of the method address in the constant pool
+    MethodIndex,
+}
+
+impl OperandKind {
+    pub fn size(&self, wide: bool) -> usize {
+        match self {
+            Byte | SignedByte => 1,
+            Varnum | Const => if wide { 2 } else { 1 },
+            LabelOffset | ConstantIndex | MethodIndex => 2,
+        }
+    }
+
+    pub fn signed(&self) -> bool { [SignedByte, Const, LabelOffset].contains(self) }
+
+    /// Whether the value is in the range of the operand, signed operands don't take the unsigned notation here
+    pub fn fits(&self, value: i64, wide: bool) -> bool {
+        let bits = 8 * self.size(wide);
+        if self.signed() { (-(1 << (bits - 1))..1 << (bits - 1)).contains(&value) } else { (0..1 << bits).contains(&value) }
+    }
+
+    /// Big-endian bytes of the value. Signed operands can also be written as unsigned bytes, e.g. 0xFF for -1.
+    pub fn encode(&self, value: i64, wide: bool) -> Option<Vec<i32>> {
+        let bits = 8 * self.size(wide);
+        let min = if self.signed() { -(1 << (bits - 1)) } else { 0 };
+        if value < min || value >= 1 << bits {
+            return None;
+        }
+        Some((0..self.size(wide)).rev().map(|x| ((value >> (8 * x)) & 0xFF) as i32).collect())
+    }
+
+    pub fn decode(&self, bytes: &[i32]) -> i64 {
+        let value = bytes.iter().fold(0i64, |value, x| value << 8 | (*x as i64 & 0xFF));
+        let bits = 8 * bytes.len();
+        if self.signed() && value >= 1 << (bits - 1) { value - (1 << bits) } else { value }
+    }
+}
+
+/// Mnemonic, opcode and operands of an IJVM instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub name: String,
+    pub opcode: i32,
+    pub operands: Vec<OperandKind>,
+}
+
+impl Instruction {
+    /// Bytes of the instruction with the opcode, WIDE isn't included
+    pub fn size(&self, wide: bool) -> usize {
+        1 + self.operands.iter().map(|x| x.size(wide)).sum::<usize>()
+    }
+}
+
+const STANDARD: [(IjvmCommand, &str, &[OperandKind]); 23] = [
+    (BIPUSH, "BIPUSH", &[SignedByte]),
+    (DUP, "DUP", &[]),
+    (GOTO, "GOTO", &[LabelOffset]),
+    (IADD, "IADD", &[]),
+    (IAND, "IAND", &[]),
+    (IFEQ, "IFEQ", &[LabelOffset]),
+    (IFLT, "IFLT", &[LabelOffset]),
+    (IF_ICMPEQ, "IF_ICMPEQ", &[LabelOffset]),
+    (IINC, "IINC", &[Varnum, Const]),
+    (ILOAD, "ILOAD", &[Varnum]),
+    (INVOKEVIRTUAL, "INVOKEVIRTUAL", &[MethodIndex]),
+    (IOR, "IOR", &[]),
+    (IRETURN, "IRETURN", &[]),
+    (ISHL, "ISHL", &[]),
+    (ISHR, "ISHR", &[]),
+    (ISTORE, "ISTORE", &[Varnum]),
+    (ISUB, "ISUB", &[]),
+    (IUSHR, "IUSHR", &[]),
+    (LDC_W, "LDC_W", &[ConstantIndex]),
+    (NOP, "NOP", &[]),
+    (POP, "POP", &[]),
+    (SWAP, "SWAP", &[]),
+    (WIDE, "WIDE", &[]),
+];
+
+/// Instruction set: the table that the parser, the compiler, the disassembler and the validator use
+#[derive(Clone, Debug, PartialEq)]
+pub struct Isa {
+    pub instructions: Vec<Instruction>,
+}
+
+impl Isa {
+    /// Instructions of `IjvmCommand` that the microprogram implements
+    pub fn standard() -> Isa {
+        let instructions = STANDARD.iter()
+            .map(|(command, name, operands)| Instruction { name: name.to_string(), opcode: *command as i32, operands: operands.to_vec() })
+            .collect();
+        Isa { instructions }
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Instruction> {
+        self.instructions.iter().find(|x| x.name == name)
+    }
+
+    pub fn by_opcode(&self, opcode: i32) -> Option<&Instruction> {
+        self.instructions.iter().find(|x| x.opcode == opcode)
+    }
+
+    /// Instruction at the offset with the values of its operands and its size, WIDE is included into the instruction
+    pub fn decode(&self, program: &[i32], offset: usize) -> Result<Decoded, String> {
+        let wide = program[offset] == WIDE as i32;
+        let start = if wide { offset + 1 } else { offset };
+        let opcode = *program.get(start).ok_or_else(|| "WIDE at the end of the program".to_string())?;
+        let instruction = self.by_opcode(opcode).ok_or_else(|| format!("Unknown opcode 0x{:02X}", opcode))?;
+        if wide && !instruction.operands.contains(&Varnum) {
+            return Err(format!("WIDE before {}", instruction.name));
+        }
+        let size = instruction.size(wide);
+        if start + size > program.len() {
+            return Err(format!("{} is cut off by the end of the program", instruction.name));
+        }
+        let mut operands = Vec::new();
+        let mut position = start + 1;
+        for kind in &instruction.operands {
+            let bytes = &program[position..position + kind.size(wide)];
+            if let Some(byte) = bytes.iter().find(|x| !(0..=0xFF).contains(*x)) {
+                return Err(format!("Operand byte {} of {} is out of range", byte, instruction.name));
+            }
+            operands.push(kind.decode(bytes));
+            position += kind.size(wide);
         }
+        Ok(Decoded { instruction, wide, operands, size: position - offset })
+    }
+
+    /// Listing with an instruction on every line, it can be parsed back by `parser::parse`
+    pub fn disassemble(&self, program: &[i32]) -> String {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < program.len() {
+            match self.decode(program, offset) {
+                Ok(decoded) => {
+                    lines.push(decoded.to_string());
+                    offset += decoded.size;
+                }
+                Err(_) => {
+                    // Not an instruction, e.g. a method header
+                    lines.push(format!("0x{:02X}", program[offset] & 0xFF));
+                    offset += 1;
+                }
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Problems of the program: unknown opcodes, broken operands and branches to the middle of instructions
+    pub fn validate(&self, program: &[i32]) -> Vec<InvalidInstruction> {
+        let mut errors = Vec::new();
+        let mut starts = Vec::new();
+        let mut branches = Vec::new();
+        let mut offset = 0;
+        while offset < program.len() {
+            starts.push(offset);
+            match self.decode(program, offset) {
+                Ok(decoded) => {
+                    if decoded.instruction.operands == [LabelOffset] {
+                        branches.push((offset, offset as i64 + decoded.operands[0]));
+                    }
+                    offset += decoded.size;
+                }
+                Err(reason) => {
+                    errors.push(InvalidInstruction { offset, reason });
+                    offset += 1;
+                }
+            }
+        }
+        for (offset, target) in branches {
+            if target < 0 || !starts.contains(&(target as usize)) {
+                errors.push(InvalidInstruction { offset, reason: format!("Branch to {} is not at an instruction", target) });
+            }
+        }
+        errors.sort_by_key(|x| x.offset);
+        errors
+    }
+}
+
+pub struct Decoded<'a> {
+    pub instruction: &'a Instruction,
+    pub wide: bool,
+    pub operands: Vec<i64>,
+    /// Bytes with WIDE
+    pub size: usize,
+}
+
+impl fmt::Display for Decoded<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.wide {
+            write!(f, "WIDE ")?;
+        }
+        write!(f, "{}", self.instruction.name)?;
+        for operand in &self.operands {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidInstruction {
+    /// Offset from the beginning of the program
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Offset {}: {}", self.offset, self.reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_processor, PROGRAM_START, STACK_START};
+    use crate::parser::parse;
+
+    use super::*;
+
+    #[quickcheck]
+    fn disassembled_program_is_parsed_back(program: Vec<u8>) -> bool {
+        let program: Vec<i32> = program.iter().map(|x| *x as i32).collect();
+        parse(&Isa::standard().disassemble(&program)) == program
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(Some(vec![0xFF, 0xFD]), LabelOffset.encode(-3, false));
+        assert_eq!(-3, LabelOffset.decode(&[0xFF, 0xFD]));
+        assert_eq!(Some(vec![0xFF]), SignedByte.encode(0xFF, false));
+        assert_eq!(None, SignedByte.encode(-129, false));
+        assert_eq!(None, Varnum.encode(-1, false));
+        assert_eq!(Some(vec![0x01, 0x00]), Varnum.encode(0x100, true));
+        assert_eq!(None, Varnum.encode(0x100, false));
+        assert!(Const.fits(-128, false) && !Const.fits(0x80, false) && Const.fits(0x80, true));
+        assert!(Varnum.fits(0xFF, false) && !Varnum.fits(-1, true));
+    }
+
+    /// The table decodes the increment the same way as the microcode adds it
+    #[test]
+    fn increment_matches_microcode() {
+        let isa = Isa::standard();
+        for bytes in vec![vec![0x01], vec![0x7F], vec![0x80], vec![0xFF], vec![0x7F, 0xFF], vec![0x80, 0x00], vec![0xFF, 0xFE]] {
+            let mut program = if bytes.len() == 2 { vec![WIDE as i32, IINC as i32, 0x00, 0x00] } else { vec![IINC as i32, 0x00] };
+            program.extend(&bytes);
+            let increment = isa.decode(&program, 0).unwrap().operands[1];
+
+            let mut mic1 = create_processor(&program, vec![0], [0; 10]);
+            mic1.run(program.len(), PROGRAM_START);
+            assert_eq!(increment as i32, mic1.main_memory.read_number(STACK_START as usize), "{:?}", bytes);
+        }
+    }
+
+    /// The table decodes the operand of BIPUSH the same way as the microcode pushes it
+    #[test]
+    fn bipush_matches_microcode() {
+        let isa = Isa::standard();
+        for byte in [0x00, 0x01, 0x7F, 0x80, 0xFD, 0xFF] {
+            let program = vec![BIPUSH as i32, byte];
+            let value = isa.decode(&program, 0).unwrap().operands[0];
+
+            let mut mic1 = create_processor(&program, vec![0], [0; 10]);
+            mic1.run(program.len(), PROGRAM_START);
+            assert_eq!(value as i32, mic1.main_memory.read_number(STACK_START as usize + 1), "{:#04X}", byte);
+        }
+    }
+
+    #[test]
+    fn table() {
+        let isa = Isa::standard();
+        assert_eq!(vec![Varnum, Const], isa.by_name("IINC").unwrap().operands);
+        assert_eq!("IF_ICMPEQ", isa.by_opcode(IF_ICMPEQ as i32).unwrap().name);
+        assert_eq!(3, isa.by_name("INVOKEVIRTUAL").unwrap().size(false));
+        assert_eq!(None, isa.by_opcode(0x01));
+    }
+
+    #[test]
+    fn disassemble() {
+        let program = parse("BIPUSH -2\nIINC 0x01 -1\nWIDE\nILOAD 0x0102\nIFEQ -8");
+        assert_eq!("BIPUSH -2\nIINC 1 -1\nWIDE ILOAD 258\nIFEQ -8", Isa::standard().disassemble(&program));
+    }
+
+    #[test]
+    fn validate() {
+        let isa = Isa::standard();
+        assert_eq!(Vec::<InvalidInstruction>::new(), isa.validate(&parse("DUP\nIFEQ -1\nGOTO -4")));
+
+        let errors = isa.validate(&parse("GOTO 0x00 0x02\n0x01\nWIDE\nPOP\nBIPUSH"));
+        let errors: Vec<String> = errors.iter().map(|x| x.to_string()).collect();
+        assert_eq!(vec![
+            "Offset 0: Branch to 2 is not at an instruction",
+            "Offset 3: Unknown opcode 0x01",
+            "Offset 4: WIDE before POP",
+            "Offset 6: BIPUSH is cut off by the end of the program",
+        ], errors);
     }
 }
@@ -0,0 +1,317 @@
+use crate::alu::{alu_word, Adder};
+use crate::arch_state::ArchState;
+use crate::decoders::decoder_4x9;
+use crate::main_memory::{fast_decode, fast_encode, MainMemory};
+use crate::memory::{address_bits, ControlMemory, update_word};
+use crate::microasm::MicroAsm::{stack_overflow1, stack_underflow1, Main1};
+use crate::processor_elements::{stack_limits, BBusControls, BBusSources, MirFields};
+use crate::shifter::{barrel_word, sll8_word, sra1_word, srl1_word};
+
+pub const LANES: usize = 64;
+
+/// 32 wires, bit `i` of every wire belongs to lane `i`
+type Word = [u64; 32];
+
+/**
+ * Up to 64 independent `Mic1` machines simulated at once.
+ *
+ * Every signal of the datapath is a `u64`, so each gate is evaluated for all lanes with one operation.
+ *   The main memory isn't built from gates, so every lane has its own `MainMemory`.
+ * Lanes that are not active anymore (e.g. finished the program) don't update registers and memory.
+ */
+pub struct BatchMic1 {
+    mir: Vec<u64>,
+    mpc: Vec<u64>,
+
+    mar: Word,
+    mdr: Word,
+    pc: Word,
+    mbr: Word,
+    sp: Word,
+    lv: Word,
+    cpp: Word,
+    tos: Word,
+    opc: Word,
+    h: Word,
+    stack_base: Word,
+    stack_limit: Word,
+
+    control_memory: ControlMemory,
+
+    pub main_memories: Vec<MainMemory>,
+
+    /// Adder inside of the ALU
+    pub adder: Adder,
+
+    /// Mask of running lanes
+    pub active: u64,
+    /// Amount of executed microinstructions per lane
+    pub cycles: Vec<usize>,
+}
+
+impl BatchMic1 {
+    /// Every lane starts at Main1 about to execute the instruction at `pc` of its state
+    pub fn from_arch_states(states: &[ArchState], control_memory: ControlMemory) -> BatchMic1 {
+        assert!(states.len() <= LANES, "Only {} machines can be simulated at once", LANES);
+
+        let mut batch = BatchMic1 {
+            mir: vec![0; control_memory.layout().width()],
+            mpc: vec![0; control_memory.layout().address_width()],
+            mar: [0; 32],
+            mdr: [0; 32],
+            pc: [0; 32],
+            mbr: [0; 32],
+            sp: [0; 32],
+            lv: [0; 32],
+            cpp: [0; 32],
+            tos: [0; 32],
+            opc: [0; 32],
+            h: [0; 32],
+            stack_base: [0; 32],
+            stack_limit: [0; 32],
+            control_memory,
+            main_memories: states.iter().map(|x| MainMemory::from_cells(&x.memory)).collect(),
+            adder: Adder::Ripple,
+            active: 0,
+            cycles: vec![0; states.len()],
+        };
+
+        let main1 = address_bits(Main1 as usize, batch.mpc.len());
+        for (lane, state) in states.iter().enumerate() {
+            set_lane(&mut batch.pc, lane, state.pc);
+            set_lane(&mut batch.sp, lane, state.sp);
+            set_lane(&mut batch.lv, lane, state.lv);
+            set_lane(&mut batch.cpp, lane, state.cpp);
+            set_lane(&mut batch.tos, lane, state.tos);
+            set_lane(&mut batch.stack_base, lane, state.stack_base);
+            set_lane(&mut batch.stack_limit, lane, state.stack_limit);
+            // Main1 expects the opcode to be already fetched
+            set_lane(&mut batch.mbr, lane, *state.memory.get(state.pc as usize).unwrap_or(&0));
+            for (bits, bit) in batch.mpc.iter_mut().zip(&main1) {
+                *bits |= (*bit as u64) << lane;
+            }
+            batch.active |= 1 << lane;
+        }
+        batch
+    }
+
+    pub fn lanes(&self) -> usize { self.main_memories.len() }
+
+    /// Should be called when the lane is between instructions
+    pub fn arch_state(&self, lane: usize) -> ArchState {
+        ArchState {
+            pc: get_lane(&self.pc, lane),
+            sp: get_lane(&self.sp, lane),
+            lv: get_lane(&self.lv, lane),
+            cpp: get_lane(&self.cpp, lane),
+            tos: get_lane(&self.tos, lane),
+            stack_base: get_lane(&self.stack_base, lane),
+            stack_limit: get_lane(&self.stack_limit, lane),
+            memory: self.main_memories[lane].cells().to_vec(),
+        }
+    }
+
+    /// Runs until every lane reaches the stop instruction or the cycle limit is exceeded
+    pub fn run_until_stop(&mut self, stop_instruction: i32, max_cycles: usize) {
+        let mut cycle = 0;
+        self.stop_lanes(stop_instruction);
+        while self.active != 0 && cycle < max_cycles {
+            self.execute_command();
+            self.stop_lanes(stop_instruction);
+            cycle += 1;
+        }
+    }
+
+    /// Deactivates lanes that are at Main1 and are about to execute the stop instruction, and lanes halted by the stack checks
+    fn stop_lanes(&mut self, stop_instruction: i32) {
+        self.active &= !(self.lanes_at(stack_overflow1 as usize) | self.lanes_at(stack_underflow1 as usize));
+        let at_main1 = self.lanes_at(Main1 as usize);
+        for lane in 0..self.lanes() {
+            let mask = 1 << lane;
+            if self.active & at_main1 & mask == 0 {
+                continue;
+            }
+            let pc = get_lane(&self.pc, lane);
+            if self.main_memories[lane].read_number(pc as usize) == stop_instruction {
+                self.active &= !mask;
+            }
+        }
+    }
+
+    /// Mask of lanes which MPC is equal to the address
+    fn lanes_at(&self, address: usize) -> u64 {
+        let mut res = !0;
+        for i in 0..self.mpc.len() {
+            res &= if address & (1 << i) != 0 { self.mpc[i] } else { !self.mpc[i] };
+        }
+        res
+    }
+
+    pub fn execute_command(&mut self) {
+        let active = self.active;
+
+        // Update registers from the main memory
+        let (data, enabled) = self.check_reads(true);
+        update_word(&mut self.mdr, &data, enabled);
+        let (data, enabled) = self.check_reads(false);
+        update_word(&mut self.mbr, &data, enabled);
+
+        // Read new command
+        let new_command = self.control_memory.get_word(&self.mpc);
+        for (bits, new) in self.mir.iter_mut().zip(&new_command) {
+            *bits = new & active | *bits & !active;
+        }
+        let mir = MirFields::new(self.control_memory.layout(), self.mir.clone());
+
+        // Create B bus
+        let sources = BBusSources { mdr: self.mdr, pc: self.pc, mbr: self.mbr, sp: self.sp, lv: self.lv, cpp: self.cpp, tos: self.tos, opc: self.opc };
+        let b_bus = sources.drive(&BBusControls::new(decoder_4x9(mir.b_bus_controls())));
+
+        // Calculate C bus, A bus is H
+        let (mut c_bus, flags) = alu_word(self.h, b_bus, &mir.alu_controls(), self.adder);
+
+        // Shifting
+        c_bus = sll8_word(c_bus, mir.sll8());
+        c_bus = sra1_word(c_bus, mir.sra1());
+        c_bus = srl1_word(c_bus, mir.srl1());
+        c_bus = barrel_word(c_bus, mir.shift_op(), mir.shift_amount(&self.h));
+
+        // Write C bus into registers
+        let controls = mir.c_bus_controls();
+        update_word(&mut self.h, &c_bus, controls.h() & active);
+        update_word(&mut self.opc, &c_bus, controls.opc() & active);
+        update_word(&mut self.tos, &c_bus, controls.tos() & active);
+        update_word(&mut self.cpp, &c_bus, controls.cpp() & active);
+        update_word(&mut self.lv, &c_bus, controls.lv() & active);
+        update_word(&mut self.sp, &c_bus, controls.sp() & active);
+        update_word(&mut self.pc, &c_bus, controls.pc() & active);
+        update_word(&mut self.mdr, &c_bus, controls.mdr() & active);
+        update_word(&mut self.mar, &c_bus, controls.mar() & active);
+
+        // Memory operations are done lane by lane
+        let mut ready = 0;
+        for lane in 0..self.lanes() {
+            let mask = 1 << lane;
+            if active & mask == 0 {
+                continue;
+            }
+            let memory = &mut self.main_memories[lane];
+            memory.request_first_read(lane_bits(&self.mar, lane), mir.read() & mask != 0);
+            memory.request_second_read(lane_bits(&self.pc, lane), mir.fetch() & mask != 0);
+            memory.write(lane_bits(&self.mdr, lane), lane_bits(&self.mar, lane), mir.write() & mask != 0);
+            if memory.ready() {
+                ready |= mask;
+            }
+            self.cycles[lane] += 1;
+        }
+
+        // Select next command
+        let (below, above) = stack_limits(&c_bus, &self.stack_base, &self.stack_limit, self.adder);
+        let next_command = mir.next_address(&self.mbr, &flags, ready, below | above);
+        for (bits, next) in self.mpc.iter_mut().zip(&next_command) {
+            *bits = next & active | *bits & !active;
+        }
+    }
+
+    fn check_reads(&mut self, first: bool) -> (Word, u64) {
+        let mut data = [0; 32];
+        let mut enabled = 0;
+        for lane in 0..self.lanes() {
+            if self.active & (1 << lane) == 0 {
+                continue;
+            }
+            let memory = &mut self.main_memories[lane];
+            if first {
+                memory.tick();
+            }
+            let (value, lane_enabled) = if first { memory.check_first_read() } else { memory.check_second_read() };
+            if lane_enabled {
+                set_lane_bits(&mut data, lane, value);
+                enabled |= 1 << lane;
+            }
+        }
+        (data, enabled)
+    }
+}
+
+fn lane_bits(word: &Word, lane: usize) -> [bool; 32] {
+    let mut res = [false; 32];
+    for i in 0..32 {
+        res[i] = word[i] >> lane & 1 == 1;
+    }
+    res
+}
+
+fn set_lane_bits(word: &mut Word, lane: usize, bits: [bool; 32]) {
+    for i in 0..32 {
+        word[i] = word[i] & !(1 << lane) | (bits[i] as u64) << lane;
+    }
+}
+
+fn get_lane(word: &Word, lane: usize) -> i32 { fast_encode(&lane_bits(word, lane)) }
+
+fn set_lane(word: &mut Word, lane: usize, value: i32) { set_lane_bits(word, lane, fast_decode(value)) }
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_interpreter, make_control_memory};
+    use crate::parser::parse;
+    use crate::processor::Mic1;
+
+    use super::*;
+
+    /// Decrements the value on the stack down to zero
+    const COUNT_DOWN: &str = "DUP\nIFEQ 0x00 0x09\nBIPUSH 0x01\nISUB\nGOTO 0xFF 0xF9\n0xFF";
+
+    fn run_scalar(state: &ArchState, stop_instruction: i32) -> Mic1 {
+        let mut mic1 = Mic1::from_arch_state(state, make_control_memory());
+        while mic1.main_memory.read_number(fast_encode(&mic1.pc.get()) as usize) != stop_instruction {
+            mic1.step_instruction();
+        }
+        mic1
+    }
+
+    #[test]
+    fn lanes_finish_at_different_time() {
+        let commands = parse(COUNT_DOWN);
+        let states: Vec<ArchState> = (0..12).map(|x| create_interpreter(&commands, vec![7, x], [0; 10]).arch_state()).collect();
+
+        let mut batch = BatchMic1::from_arch_states(&states, make_control_memory());
+        batch.run_until_stop(0xFF, 10_000);
+
+        assert_eq!(0, batch.active);
+        for (lane, state) in states.iter().enumerate() {
+            let mic1 = run_scalar(state, 0xFF);
+            assert_eq!(mic1.arch_state(), batch.arch_state(lane), "Lane: {}", lane);
+            assert_eq!(mic1.cycles, batch.cycles[lane], "Lane: {}", lane);
+        }
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let commands = parse(COUNT_DOWN);
+        let states = vec![
+            create_interpreter(&commands, vec![0], [0; 10]).arch_state(),
+            create_interpreter(&commands, vec![100], [0; 10]).arch_state(),
+        ];
+
+        let mut batch = BatchMic1::from_arch_states(&states, make_control_memory());
+        batch.run_until_stop(0xFF, 100);
+
+        assert_eq!(0b10, batch.active);
+    }
+
+    #[quickcheck]
+    fn arithmetic(values: Vec<(i32, i32)>) {
+        let commands = parse("IADD\nDUP\nIOR\nISUB\n0xFF");
+        let states: Vec<ArchState> = values.iter().take(LANES).map(|(a, b)| create_interpreter(&commands, vec![5, *a, *b], [0; 10]).arch_state()).collect();
+
+        let mut batch = BatchMic1::from_arch_states(&states, make_control_memory());
+        batch.run_until_stop(0xFF, 1000);
+
+        for (lane, (a, b)) in values.iter().take(LANES).enumerate() {
+            let sum = a.wrapping_add(*b);
+            assert_eq!(5i32.wrapping_sub(sum | sum), batch.arch_state(lane).tos);
+        }
+    }
+}
@@ -1,30 +1,43 @@
-pub struct Bus36 {
-    pub data: [bool; 36]
+use crate::logic::Logic;
+
+pub struct MirBus {
+    pub data: Vec<bool>
 }
 
-impl Bus36 {
-    pub fn from(data: [bool; 36]) -> Bus36 { Bus36 { data } }
+impl MirBus {
+    pub fn from(data: Vec<bool>) -> MirBus { MirBus { data } }
 }
 
-pub struct Bus32 {
-    pub data: [bool; 32]
+/// Data bus of the `W`-bit datapath
+pub struct Bus<const W: usize> {
+    pub data: [bool; W]
 }
 
-impl Bus32 {
-    pub fn new() -> Bus32 { Bus32 { data: [false; 32] } }
-    pub fn from(data: [bool; 32]) -> Bus32 { Bus32 { data } }
-    pub fn connect(&mut self, lines: [bool; 32]) {
-        for i in 0..32 {
+pub type Bus32 = Bus<32>;
+
+impl<const W: usize> Bus<W> {
+    pub fn new() -> Bus<W> { Bus { data: [false; W] } }
+    pub fn from(data: [bool; W]) -> Bus<W> { Bus { data } }
+    pub fn connect(&mut self, lines: [bool; W]) {
+        for i in 0..W {
             self.data[i] = self.data[i] || lines[i];
         }
     }
 }
 
-pub struct Bus9 {
-    pub data: [bool; 9]
+/// Address of the control store
+pub struct AddressBus {
+    pub data: Vec<bool>
 }
 
-impl Bus9 {
-    pub fn from(data: [bool; 9]) -> Bus9 { Bus9 { data } }
+impl AddressBus {
+    pub fn from(data: Vec<bool>) -> AddressBus { AddressBus { data } }
 }
 
+
+/// Connects the lines to the bus word if enabled, the bus is an OR of all connected lines
+pub fn connect_word<T: Logic, const W: usize>(bus: &mut [T; W], lines: &[T; W], enabled: T) {
+    for i in 0..W {
+        bus[i] = bus[i] | lines[i] & enabled;
+    }
+}
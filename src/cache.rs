@@ -0,0 +1,235 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WritePolicy {
+    /// Every write goes to the memory through a write buffer, so it never stalls. Write misses don't allocate lines.
+    WriteThrough,
+    /// Writes allocate the line and make it dirty, dirty lines are written back when they are evicted
+    WriteBack,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Replacement {
+    /// The line that wasn't used for the longest time is evicted
+    Lru,
+    /// The line that was loaded first is evicted
+    Fifo,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CacheConfig {
+    pub sets: usize,
+    pub ways: usize,
+    /// Words in the line
+    pub line_size: usize,
+    pub write_policy: WritePolicy,
+    pub replacement: Replacement,
+    /// Cycles the processor waits for the line to be loaded or written back
+    pub miss_penalty: usize,
+}
+
+impl CacheConfig {
+    pub fn direct_mapped(lines: usize, line_size: usize) -> CacheConfig {
+        CacheConfig::set_associative(lines, 1, line_size)
+    }
+
+    pub fn set_associative(sets: usize, ways: usize, line_size: usize) -> CacheConfig {
+        if sets == 0 || ways == 0 || line_size == 0 {
+            panic!("Cache with {} sets of {} ways and {} words per line is empty", sets, ways, line_size);
+        }
+        CacheConfig { sets, ways, line_size, write_policy: WritePolicy::WriteThrough, replacement: Replacement::Lru, miss_penalty: 10 }
+    }
+
+    pub fn write_back(mut self) -> CacheConfig {
+        self.write_policy = WritePolicy::WriteBack;
+        self
+    }
+
+    pub fn fifo(mut self) -> CacheConfig {
+        self.replacement = Replacement::Fifo;
+        self
+    }
+
+    pub fn miss_penalty(mut self, cycles: usize) -> CacheConfig {
+        self.miss_penalty = cycles;
+        self
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub reads: usize,
+    pub read_misses: usize,
+    pub writes: usize,
+    pub write_misses: usize,
+    pub write_backs: usize,
+    /// Cycles the processor was stalled by this cache
+    pub stall_cycles: usize,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> usize { self.reads + self.writes - self.misses() }
+
+    pub fn misses(&self) -> usize { self.read_misses + self.write_misses }
+
+    pub fn hit_rate(&self) -> f64 {
+        let accesses = self.reads + self.writes;
+        if accesses == 0 { 0.0 } else { self.hits() as f64 / accesses as f64 }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Reads: {}, misses: {}", self.reads, self.read_misses)?;
+        writeln!(f, "Writes: {}, misses: {}", self.writes, self.write_misses)?;
+        writeln!(f, "Write backs: {}", self.write_backs)?;
+        write!(f, "Hit rate: {:.1}%, stall cycles: {}", self.hit_rate() * 100.0, self.stall_cycles)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: usize,
+    loaded: usize,
+    used: usize,
+}
+
+/**
+ * Timing model of a cache: it keeps tags of the lines, but not the data.
+ *
+ * Values always stay in the main memory cells, so a cache changes only the amount of cycles of the program.
+ */
+pub struct Cache {
+    pub config: CacheConfig,
+    pub stats: CacheStats,
+    lines: Vec<Line>,
+    /// Amount of accesses, used as the time for the replacement
+    time: usize,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        let line = Line { valid: false, dirty: false, tag: 0, loaded: 0, used: 0 };
+        Cache { config, stats: CacheStats::default(), lines: vec![line; config.sets * config.ways], time: 0 }
+    }
+
+    /// Accesses the word, returns the amount of cycles the processor should wait
+    pub fn access(&mut self, address: usize, write: bool) -> usize {
+        self.time += 1;
+        let block = address / self.config.line_size;
+        let set = block % self.config.sets;
+        let tag = block / self.config.sets;
+        let ways = set * self.config.ways..(set + 1) * self.config.ways;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+
+        if write { self.stats.writes += 1 } else { self.stats.reads += 1 }
+
+        if let Some(hit) = ways.clone().find(|x| self.lines[*x].valid && self.lines[*x].tag == tag) {
+            let line = &mut self.lines[hit];
+            line.used = self.time;
+            line.dirty |= write && write_back;
+            return 0;
+        }
+
+        if write {
+            self.stats.write_misses += 1;
+            if !write_back {
+                return 0;
+            }
+        } else {
+            self.stats.read_misses += 1;
+        }
+
+        let victim = self.victim(ways);
+        let mut stall = self.config.miss_penalty;
+        if self.lines[victim].valid && self.lines[victim].dirty {
+            self.stats.write_backs += 1;
+            stall += self.config.miss_penalty;
+        }
+        self.lines[victim] = Line { valid: true, dirty: write, tag, loaded: self.time, used: self.time };
+        self.stats.stall_cycles += stall;
+        stall
+    }
+
+    /// Invalid line of the set or the one selected by the replacement policy
+    fn victim(&self, ways: std::ops::Range<usize>) -> usize {
+        if let Some(free) = ways.clone().find(|x| !self.lines[*x].valid) {
+            return free;
+        }
+        match self.config.replacement {
+            Replacement::Lru => ways.min_by_key(|x| self.lines[*x].used).unwrap(),
+            Replacement::Fifo => ways.min_by_key(|x| self.lines[*x].loaded).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_processor, PROGRAM_START};
+    use crate::parser::parse;
+
+    use super::*;
+
+    fn misses(cache: &mut Cache, addresses: &[usize]) -> Vec<bool> {
+        addresses.iter().map(|x| cache.access(*x, false) != 0).collect()
+    }
+
+    #[test]
+    fn direct_mapped() {
+        let mut cache = Cache::new(CacheConfig::direct_mapped(4, 2));
+        // 0 and 8 are in the same set
+        assert_eq!(vec![true, false, true, true, false, true], misses(&mut cache, &[0, 1, 8, 0, 1, 8]));
+        assert_eq!(6, cache.stats.reads);
+        assert_eq!(4, cache.stats.read_misses);
+        assert_eq!(40, cache.stats.stall_cycles);
+    }
+
+    #[test]
+    fn lru_and_fifo() {
+        // Two ways of a single set: 0 is used again before 2 comes, so LRU keeps it and FIFO doesn't
+        let addresses = [0, 1, 0, 2, 0];
+        let mut lru = Cache::new(CacheConfig::set_associative(1, 2, 1));
+        assert_eq!(vec![true, true, false, true, false], misses(&mut lru, &addresses));
+        let mut fifo = Cache::new(CacheConfig::set_associative(1, 2, 1).fifo());
+        assert_eq!(vec![true, true, false, true, true], misses(&mut fifo, &addresses));
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut through = Cache::new(CacheConfig::direct_mapped(1, 1).miss_penalty(3));
+        assert_eq!(0, through.access(5, true));
+        assert_eq!(3, through.access(5, false));
+        assert_eq!(0, through.access(6, true));
+        assert_eq!(0, through.stats.write_backs);
+
+        let mut back = Cache::new(CacheConfig::direct_mapped(1, 1).miss_penalty(3).write_back());
+        assert_eq!(3, back.access(5, true));
+        assert_eq!(0, back.access(5, false));
+        // The dirty line is written back before 6 is loaded
+        assert_eq!(6, back.access(6, false));
+        assert_eq!(1, back.stats.write_backs);
+        assert_eq!(2, back.stats.misses());
+    }
+
+    #[test]
+    fn program_is_slower_but_the_same() {
+        let commands = parse("BIPUSH 0x05\nDUP\nIADD\nISTORE 0x01\nILOAD 0x01\nILOAD 0x01\nIADD");
+        let mut plain = create_processor(&commands, vec![1, 2], [0; 10]);
+        plain.run(commands.len() + 1, PROGRAM_START);
+
+        let mut cached = create_processor(&commands, vec![1, 2], [0; 10]);
+        cached.main_memory.data_cache = Some(Cache::new(CacheConfig::set_associative(2, 2, 4).write_back().miss_penalty(5)));
+        cached.main_memory.fetch_cache = Some(Cache::new(CacheConfig::direct_mapped(4, 4).miss_penalty(5)));
+        cached.run(commands.len() + 1, PROGRAM_START);
+
+        assert_eq!(plain.tos.get(), cached.tos.get());
+        assert_eq!(plain.main_memory.cells(), cached.main_memory.cells());
+
+        let data = cached.main_memory.data_cache.as_ref().unwrap().stats;
+        let fetch = cached.main_memory.fetch_cache.as_ref().unwrap().stats;
+        assert!(data.hits() > 0 && fetch.hits() > 0);
+        assert_eq!(plain.cycles + data.stall_cycles + fetch.stall_cycles, cached.cycles);
+    }
+}
@@ -1,15 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
 use std::env::var;
+use std::fmt;
+use std::fs;
+use std::path::Path;
 use std::process::Command;
-use std::str::FromStr;
 
-use linked_hash_map::LinkedHashMap;
 use tree_sitter::{Language, Node, Parser};
 use tree_sitter::LogType::Parse;
 
-use crate::asm::IjvmCommand;
-use crate::asm::IjvmCommand::{BIPUSH, GOTO, IFEQ, IFLT, IINC, ILOAD, INVOKEVIRTUAL, ISTORE, LDC_W};
-use crate::compiler::IdentifierRole::{CONSTANT, LABEL, METHOD, VARIABLE};
+use crate::asm::Isa;
+use crate::asm::OperandKind::{Byte, Const, ConstantIndex, LabelOffset, MethodIndex, SignedByte, Varnum};
+use crate::expression::evaluate;
+use crate::linker::{link, Object, Relocation, Symbol};
+use crate::parser::number;
 use crate::main;
 
 extern "C" { fn tree_sitter_jas() -> Language; }
@@ -17,11 +20,150 @@ extern "C" { fn tree_sitter_jas() -> Language; }
 pub struct ProcessorInfo {
     pub constants: Vec<i32>,
     pub main_program: Vec<i32>,
+    /// Source line of every byte of the program
+    pub lines: Vec<SourceLine>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLine {
+    /// Canonical path of the file, empty for the source that is compiled from a string
+    pub file: String,
+    /// Line in the file, starting from 1
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "Line {}", self.line)
+        } else {
+            write!(f, "{}, line {}", self.file, self.line)
+        }
+    }
 }
 
 const PLACEHOLDER: i32 = 0x00;
 
 pub fn compile(source: &str, program_start_offset: u32, stop_command: Option<i32>) -> ProcessorInfo {
+    compile_with_isa(source, &Isa::standard(), program_start_offset, stop_command)
+}
+
+/// Mnemonics and operands are taken from the ISA, e.g. loaded from ijvm.conf; included files are read from the current directory
+pub fn compile_with_isa(source: &str, isa: &Isa, program_start_offset: u32, stop_command: Option<i32>) -> ProcessorInfo {
+    compile_with_includes(source, "", isa, program_start_offset, stop_command, read_relative)
+}
+
+/// Included files are read from the directory of the file
+pub fn compile_file(path: &Path, isa: &Isa, program_start_offset: u32, stop_command: Option<i32>) -> ProcessorInfo {
+    let path = fs::canonicalize(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    let source = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    compile_with_includes(&source, &path.display().to_string(), isa, program_start_offset, stop_command, read_relative)
+}
+
+/// Canonical path and source of the file included by `including`, the name is relative to the directory of `including`
+fn read_relative(name: &str, including: &str) -> (String, String) {
+    let path = Path::new(including).parent().unwrap_or(Path::new("")).join(name);
+    let path = fs::canonicalize(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    let source = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    (path.display().to_string(), source)
+}
+
+/**
+ * Compiles the source and the files of its `.include "file.jas"` lines into objects and links them.
+ *
+ * Included files have constants and methods, and may include other files. `read_include(name, including)`
+ *   returns the canonical name and the source of the file included by the file `including`, `file` is the name
+ *   of the source. Every file is compiled once even if it's included several times under different names.
+ */
+pub fn compile_with_includes(
+    source: &str,
+    file: &str,
+    isa: &Isa,
+    program_start_offset: u32,
+    stop_command: Option<i32>,
+    read_include: impl Fn(&str, &str) -> (String, String),
+) -> ProcessorInfo {
+    let mut objects = Vec::new();
+    let mut included = HashSet::new();
+    included.insert(file.to_string());
+    let mut sources = VecDeque::new();
+    sources.push_back((file.to_string(), source.to_string()));
+    while let Some((file, source)) = sources.pop_front() {
+        let (source, includes) = split_includes(&source);
+        let mut object = compile_object(&source, isa, stop_command);
+        object.file = file.clone();
+        objects.push(object);
+        for name in includes {
+            let (canonical, source) = read_include(&name, &file);
+            if included.insert(canonical.clone()) {
+                sources.push_back((canonical, source));
+            }
+        }
+    }
+    link(&objects, program_start_offset)
+}
+
+/// Source with the `.include` lines left empty to keep the line numbers, and names of the included files
+fn split_includes(source: &str) -> (String, Vec<String>) {
+    let mut includes = Vec::new();
+    let lines: Vec<&str> = source.lines().map(|line| {
+        match line.trim().strip_prefix(".include") {
+            Some(name) => {
+                let name = name.trim();
+                if name.len() < 2 || !name.starts_with('"') || !name.ends_with('"') {
+                    panic!("Expected a quoted file name: {}", line.trim());
+                }
+                includes.push(name[1..name.len() - 1].to_string());
+                ""
+            }
+            None => line,
+        }
+    }).collect();
+    (lines.join("\n") + "\n", includes)
+}
+
+/**
+ * Source with the `.constant` section left empty to keep the line numbers, and names with expressions of the constants.
+ *
+ * The values may have character literals and operators that the grammar doesn't know, so it never gets them.
+ */
+fn split_constants(source: &str) -> (String, Vec<(String, String)>) {
+    let mut constants = Vec::new();
+    let mut inside = false;
+    let lines: Vec<&str> = source.lines().map(|line| {
+        let text = line.split("//").next().unwrap().trim();
+        match text {
+            ".constant" => inside = true,
+            ".end-constant" => inside = false,
+            _ if !inside => return line,
+            "" => {}
+            _ => {
+                let name_end = text.find(char::is_whitespace).unwrap_or_else(|| panic!("Constant {} has no value", text));
+                constants.push((text[..name_end].to_string(), text[name_end..].trim().to_string()));
+            }
+        }
+        ""
+    }).collect();
+    if inside {
+        panic!("Unclosed .constant section");
+    }
+    (lines.join("\n") + "\n", constants)
+}
+
+/// Relocatable object of a source without `.include` lines, the stop command follows the main program
+pub fn compile_object(source: &str, isa: &Isa, stop_command: Option<i32>) -> Object {
+    let (source, constants) = split_constants(source);
+    let source = source.as_str();
+    let mut object = Object::default();
+    for (name, expression) in constants {
+        // The value is an expression over the literals and the constants above
+        let value = evaluate(&expression, |x| object.constant(x).map(|x| x as i64));
+        if value < i32::MIN as i64 || value > u32::MAX as i64 {
+            panic!("Constant {} = {} doesn't fit into a word", name, value);
+        }
+        object.symbols.push((name, Symbol::Constant(value as i32)));
+    }
+
     let language = unsafe { tree_sitter_jas() };
     let mut parser = Parser::new();
     parser.set_language(language).unwrap();
@@ -31,81 +173,51 @@ pub fn compile(source: &str, program_start_offset: u32, stop_command: Option<i32
 
     println!("{}", tree.root_node().to_sexp());
 
-    let mut constants = LinkedHashMap::new();
-    let mut methods = LinkedHashMap::new();
-    let mut method_placeholders = LinkedHashMap::new();
-    let mut main_program = Vec::new();
     for i in 0..pointer.child_count() {
         let current_node = pointer.child(i).unwrap();
 
-        if current_node.kind() == "constants" {
-            assert_eq!(0, i);
-            for i in 1..(current_node.child_count() - 1) {
-                let expression = current_node.child(i).unwrap();
-                let const_name = expression.child(0).unwrap().utf8_text(source.as_ref()).unwrap();
-                let const_value = expression.child(1).unwrap().utf8_text(source.as_ref()).unwrap();
-                constants.insert(const_name, i32::from_str(const_value).unwrap());
-            }
-        }
-
         if current_node.kind() == "main_program" {
-            assert!(i == 0 || i == 1);
+            assert_eq!(0, i);
+            object.has_main = true;
             let mut process_from = 1;
             let mut vars = Vec::new();
             if current_node.child(1).unwrap().kind() == "variables" {
                 vars = process_variables(&current_node.child(1).unwrap(), source);
                 process_from = 2;
             }
-            parse_method_body(source, &mut constants, &mut method_placeholders, &Vec::new(), &vars, &mut main_program, current_node, program_start_offset, process_from);
+            parse_method_body(source, isa, &mut object, None, &Vec::new(), &vars, current_node, process_from);
 
             match stop_command {
-                Some(t) => main_program.push(t),
+                Some(t) => {
+                    object.code.push(t);
+                    object.lines.push(current_node.end_position().row + 1);
+                }
                 None => {}
             }
         }
 
         if current_node.kind() == "method" {
-            method_parsing::process_method(source, program_start_offset, &mut constants, &mut methods, &mut method_placeholders, &mut main_program, current_node)
+            method_parsing::process_method(source, isa, &mut object, current_node)
         }
     }
-
-    // Add methods to constants
-    let mut method_constants = HashMap::new();
-    for (key, value) in methods {
-        method_constants.insert(key, constants.len());
-        constants.insert(key, value + program_start_offset as i32);
-    }
-
-    // Replace method placeholders
-    for (key, value) in method_placeholders {
-        let method_value = method_constants.get(value).unwrap();
-        main_program[key] = ((*method_value as i32) / 0x100) % 0x100;
-        main_program[key + 1] = (*method_value as i32) % 0x100;
-    }
-
-    return ProcessorInfo {
-        constants: constants.values().cloned().collect(),
-        main_program,
-    };
+    object
 }
 
 mod method_parsing {
-    use linked_hash_map::LinkedHashMap;
     use tree_sitter::Node;
 
+    use crate::asm::Isa;
     use crate::compiler::{parse_method_body, process_variables};
+    use crate::linker::{Object, Symbol};
 
-    pub fn process_method<'a>(
-        source: &'a str,
-        program_start_offset: u32,
-        mut constants: &mut LinkedHashMap<&str, i32>,
-        mut methods: &mut LinkedHashMap<&'a str, i32>,
-        mut method_placeholders: &mut LinkedHashMap<usize, &'a str>,
-        mut main_program: &mut Vec<i32>,
+    pub fn process_method(
+        source: &str,
+        isa: &Isa,
+        object: &mut Object,
         current_node: Node,
     ) {
         let name = current_node.child(1).unwrap().utf8_text(source.as_ref()).unwrap();
-        methods.insert(name, main_program.len() as i32);
+        object.symbols.push((name.to_string(), Symbol::Method(object.code.len())));
 
         let parameters = process_parameters(source, &current_node.child(2).unwrap());
 
@@ -119,12 +231,13 @@ mod method_parsing {
         // One more parameter for OBJREF
         let amount_of_parameters = parameters.len() + 1;
 
-        main_program.push(((amount_of_parameters / 0x100) % 0x100) as i32);
-        main_program.push((amount_of_parameters % 0x100) as i32);
-        main_program.push(((vars.len() / 0x100) % 0x100) as i32);
-        main_program.push((vars.len() % 0x100) as i32);
+        object.code.push(((amount_of_parameters / 0x100) % 0x100) as i32);
+        object.code.push((amount_of_parameters % 0x100) as i32);
+        object.code.push(((vars.len() / 0x100) % 0x100) as i32);
+        object.code.push((vars.len() % 0x100) as i32);
+        object.lines.resize(object.code.len(), current_node.start_position().row + 1);
 
-        parse_method_body(source, &mut constants, &mut method_placeholders, &parameters, &vars, &mut main_program, current_node, program_start_offset, process_from)
+        parse_method_body(source, isa, object, Some(name), &parameters, &vars, current_node, process_from)
     }
 
     fn process_parameters<'a>(
@@ -137,66 +250,99 @@ mod method_parsing {
     }
 }
 
-fn parse_method_body<'a>(
-    source: &'a str,
-    constants: &mut LinkedHashMap<&str, i32>,
-    mut method_placeholders: &mut LinkedHashMap<usize, &'a str>,
+/// Labels, constant indexes and method indexes are left as placeholders with relocations
+fn parse_method_body(
+    source: &str,
+    isa: &Isa,
+    object: &mut Object,
+    scope: Option<&str>,
     parameters: &Vec<&str>,
     variables: &Vec<&str>,
-    mut main_program: &mut Vec<i32>,
     current_node: Node,
-    program_start_offset: u32,
     inspect_from: usize,
 ) {
-    let mut label_positions = HashMap::new();
-    let mut labels = HashMap::new();
+    // Instruction that takes the next operands, its position and the kinds of these operands
+    let mut instruction = "";
+    let mut instruction_start = 0;
+    let mut operands = VecDeque::new();
+    // Values of the operands that aren't written yet and whether the instruction has a form with WIDE
+    let mut values = Vec::new();
+    let mut wide_form = false;
     for x in inspect_from..current_node.child_count() - 1 {
         let command = current_node.child(x).unwrap();
+        let text = command.utf8_text(source.as_ref()).unwrap();
         match command.kind() {
-            "command" => main_program.push(IjvmCommand::parse(command.utf8_text(source.as_ref()).unwrap()).unwrap() as i32),
-            "dec_number" => main_program.push(i32::from_str(command.utf8_text(source.as_ref()).unwrap()).unwrap()),
-            "oct_number" => main_program.push(i32::from_str_radix(command.utf8_text(source.as_ref()).map(|x| x.trim_start_matches("0")).unwrap(), 8).unwrap()),
-            "hex_number" => main_program.push(i32::from_str_radix(command.utf8_text(source.as_ref()).map(|x| x.trim_start_matches("0x")).unwrap(), 16).unwrap()),
-            "bin_number" => main_program.push(i32::from_str_radix(command.utf8_text(source.as_ref()).map(|x| x.trim_start_matches("0b")).unwrap(), 2).unwrap()),
-            "identifier" => {
-                let role = identifier_role(main_program.last().unwrap());
-                match role {
-                    CONSTANT => {
-                        let const_name = command.utf8_text(source.as_ref()).unwrap();
-                        let position = constants.keys().position(|&x| x == const_name).unwrap();
-                        main_program.push(((position / 0x100) % 0x100) as i32);
-                        main_program.push((position % 0x100) as i32);
-                    },
-                    LABEL => {
-                        label_positions.insert(main_program.len(), command.utf8_text(source.as_ref()).unwrap());
-                        main_program.push(PLACEHOLDER)
+            "command" => {
+                if !operands.is_empty() {
+                    panic!("{} expects more operands", instruction);
+                }
+                let command = isa.by_name(text).unwrap_or_else(|| panic!("Unknown instruction: {}", text));
+                instruction = &command.name;
+                instruction_start = object.code.len();
+                operands = command.operands.iter().cloned().collect();
+                wide_form = command.operands.contains(&Varnum);
+                object.code.push(command.opcode)
+            }
+            "dec_number" | "oct_number" | "hex_number" | "bin_number" | "identifier" => {
+                let kind = operands.pop_front().unwrap_or_else(|| panic!("Unexpected operand {} of {}", text, instruction));
+                let value = if command.kind() != "identifier" { Some(number(text)) } else {
+                    let offset = object.code.len();
+                    let name = text.to_string();
+                    match kind {
+                        ConstantIndex => {
+                            object.relocations.push(Relocation::ConstantIndex { offset, name });
+                            object.code.extend(&[PLACEHOLDER, PLACEHOLDER]);
+                            None
+                        },
+                        LabelOffset => {
+                            let scope = scope.map(String::from);
+                            object.relocations.push(Relocation::LabelOffset { offset, opcode: instruction_start, scope, name });
+                            object.code.extend(&[PLACEHOLDER, PLACEHOLDER]);
+                            None
+                        }
+                        Varnum => {
+                            let parameter_position = parameters.iter().position(|&x| x == text);
+                            Some(match parameter_position {
+                                None => (variables.iter().position(|&x| x == text).unwrap_or_else(|| panic!("Unknown variable: {}", text)) + parameters.len()) as i64,
+                                Some(t) => t as i64
+                            } + 1)
+                        }
+                        MethodIndex => {
+                            object.relocations.push(Relocation::MethodIndex { offset, name });
+                            object.code.extend(&[PLACEHOLDER, PLACEHOLDER]);
+                            None
+                        }
+                        // Named constants are immediates as well, the range is checked on encoding
+                        Byte | SignedByte | Const => Some(object.constant(text).unwrap_or_else(|| panic!("Unknown constant: {}", text)) as i64),
                     }
-                    VARIABLE => {
-                        let var_name = command.utf8_text(source.as_ref()).unwrap();
-                        let parameter_position = parameters.iter().position(|&x| x == var_name);
-                        main_program.push(match parameter_position {
-                            None => (variables.iter().position(|&x| x == var_name).unwrap() + parameters.len()) as i32,
-                            Some(t) => t as i32
-                        } + 1)
+                };
+                if let Some(value) = value {
+                    values.push((kind, value, text));
+                }
+                if !wide_form && !values.is_empty() || wide_form && operands.is_empty() {
+                    // Varnums and increments are written when all of them are known, they need WIDE if they don't fit into a byte
+                    let wide = wide_form && values.iter().any(|(kind, value, _)| !kind.fits(*value, false));
+                    if wide {
+                        let opcode = isa.by_name("WIDE").unwrap_or_else(|| panic!("{} needs WIDE", instruction)).opcode;
+                        object.code.insert(instruction_start, opcode);
+                        object.lines.insert(instruction_start, object.lines[instruction_start]);
                     }
-                    METHOD => {
-                        method_placeholders.insert(main_program.len(), command.utf8_text(source.as_ref()).unwrap());
-                        main_program.push(PLACEHOLDER);
-                        main_program.push(PLACEHOLDER);
+                    for (kind, value, text) in values.drain(..) {
+                        let bytes = kind.encode(value, wide).unwrap_or_else(|| panic!("Operand {} of {} is out of range", text, instruction));
+                        object.code.extend(bytes);
                     }
                 }
             }
             "label" => {
-                labels.insert(command.child(0).unwrap().utf8_text(source.as_ref()).unwrap(), main_program.len());
+                let name = command.child(0).unwrap().utf8_text(source.as_ref()).unwrap().to_string();
+                object.symbols.push((name, Symbol::Label { scope: scope.map(String::from), offset: object.code.len() }));
             }
             _ => panic!("Unexpected type: {}", command.kind())
         }
+        object.lines.resize(object.code.len(), command.start_position().row + 1);
     }
-
-    // Replace labels placeholders
-    for (key, value) in label_positions {
-        let label_value = labels.get(value).unwrap();
-        main_program[key] = *label_value as i32 + program_start_offset as i32;
+    if !operands.is_empty() {
+        panic!("{} expects more operands", instruction);
     }
 }
 
@@ -208,32 +354,10 @@ fn process_variables<'a>(node: &Node, source: &'a str) -> Vec<&'a str> {
     return vars;
 }
 
-fn identifier_role(previous_command: &i32) -> IdentifierRole {
-    let label_expected = [GOTO as i32, IFEQ as i32, IFLT as i32];
-    let var_expected = [IINC as i32, ILOAD as i32, ISTORE as i32];
-    let const_expected = [LDC_W as i32];
-    let method_expected = [INVOKEVIRTUAL as i32];
-
-    if label_expected.contains(&previous_command) {
-        return LABEL;
-    } else if var_expected.contains(&previous_command) {
-        return VARIABLE;
-    } else if const_expected.contains(&previous_command) {
-        return CONSTANT;
-    } else if method_expected.contains(&previous_command) {
-        return METHOD;
-    } else { panic!("Unexpected") }
-}
-
-enum IdentifierRole {
-    CONSTANT,
-    LABEL,
-    VARIABLE,
-    METHOD,
-}
-
 #[cfg(test)]
 mod tests {
+    use std::env;
+
     use crate::asm::IjvmCommand::*;
 
     use super::*;
@@ -349,7 +473,7 @@ mod tests {
         let info = compile(program, 10, None);
 
         assert_constants(vec![], &info);
-        assert_main(vec![DUP as i32, GOTO as i32, 10], &info);
+        assert_main(vec![DUP as i32, GOTO as i32, 0xFF, 0xFF], &info);
     }
 
     #[test]
@@ -363,7 +487,7 @@ mod tests {
         let info = compile(program, 10, None);
 
         assert_constants(vec![], &info);
-        assert_main(vec![GOTO as i32, 12, DUP as i32], &info);
+        assert_main(vec![GOTO as i32, 0x00, 0x03, DUP as i32], &info);
     }
 
     #[test]
@@ -482,8 +606,8 @@ mod tests {
 "#;
         let info = compile(program, 10, None);
 
-        assert_constants(vec![13], &info);
-        assert_main(vec![DUP as i32, GOTO as i32, 10, 0x00, 0x01, 0x00, 0x00, DUP as i32, GOTO as i32, 17], &info);
+        assert_constants(vec![14], &info);
+        assert_main(vec![DUP as i32, GOTO as i32, 0xFF, 0xFF, 0x00, 0x01, 0x00, 0x00, DUP as i32, GOTO as i32, 0xFF, 0xFF], &info);
     }
 
     #[test]
@@ -500,6 +624,226 @@ mod tests {
         assert_main(vec![DUP as i32, POP as i32, 0xFF], &info);
     }
 
+    #[test]
+    fn if_icmpeq_with_label() {
+        let program = r#"
+                       .main
+                       label: DUP
+                       IF_ICMPEQ label
+                       .end-main
+"#;
+        let info = compile(program, 10, None);
+
+        assert_main(vec![DUP as i32, IF_ICMPEQ as i32, 0xFF, 0xFF], &info);
+    }
+
+    #[test]
+    fn iinc_with_increment() {
+        let program = r#"
+                       .main
+                       .var
+                       i
+                       .end-var
+                       IINC i -1
+                       .end-main
+"#;
+        let info = compile(program, 10, None);
+
+        assert_main(vec![IINC as i32, 0x01, 0xFF], &info);
+    }
+
+    #[test]
+    #[should_panic(expected = "Operand 300 of BIPUSH is out of range")]
+    fn bipush_out_of_range() {
+        let program = r#"
+                       .main
+                       BIPUSH 300
+                       .end-main
+"#;
+        compile(program, 10, None);
+    }
+
+    #[test]
+    fn branch_offsets_do_not_depend_on_program_start() {
+        let program = r#"
+                       .main
+                       IFEQ end
+                       loop: DUP
+                       IFLT loop
+                       end: POP
+                       .end-main
+"#;
+        let expected = vec![IFEQ as i32, 0x00, 0x07, DUP as i32, IFLT as i32, 0xFF, 0xFF, POP as i32];
+        assert_main(expected.clone(), &compile(program, 0, None));
+        assert_main(expected, &compile(program, 300, None));
+    }
+
+    #[test]
+    fn far_branch() {
+        let program = format!(".main\nGOTO end\n{}end: BIPUSH 7\n.end-main\n", "IINC i 1\n".repeat(99)).replace(".main\n", ".main\n.var\ni\n.end-var\n");
+        let info = compile(&program, 100, Some(0xFF));
+        assert_eq!(vec![GOTO as i32, 0x01, 0x2C], info.main_program[..3].to_vec());
+    }
+
+    #[test]
+    fn automatic_wide() {
+        let variables: Vec<String> = (0..300).map(|x| format!("v{}", x)).collect();
+        let program = format!(".main\n.var\n{}\n.end-var\nILOAD v299\nIINC v0 1000\nIINC v0 1\nIINC v0 200\nIINC v0 -128\nISTORE v1\n.end-main\n", variables.join("\n"));
+        let info = compile(&program, 10, None);
+
+        assert_main(vec![
+            WIDE as i32, ILOAD as i32, 0x01, 0x2C,
+            WIDE as i32, IINC as i32, 0x00, 0x01, 0x03, 0xE8,
+            IINC as i32, 0x01, 0x01,
+            WIDE as i32, IINC as i32, 0x00, 0x01, 0x00, 0xC8,
+            IINC as i32, 0x01, 0x80,
+            ISTORE as i32, 0x02,
+        ], &info);
+        assert_eq!(info.main_program.len(), info.lines.len());
+    }
+
+    #[test]
+    fn constant_expressions() {
+        let program = r#"
+                       .constant
+                       minus -5
+                       mask 0xFF
+                       letter 'A'
+                       buf_start 0x40
+                       buf_end buf_start + 64
+                       lower letter + ('a' - 'A')
+                       .end-constant
+                       .main
+                       .end-main
+"#;
+        let info = compile(program, 0, None);
+
+        assert_constants(vec![-5, 0xFF, 65, 0x40, 0x80, 97], &info);
+    }
+
+    #[test]
+    fn named_immediates() {
+        let program = r#"
+                       .constant
+                       newline '\n'
+                       step -2
+                       .end-constant
+                       .main
+                       .var
+                       i
+                       .end-var
+                       BIPUSH newline
+                       IINC i step
+                       .end-main
+"#;
+        let info = compile(program, 0, None);
+
+        assert_main(vec![BIPUSH as i32, 0x0A, IINC as i32, 0x01, 0xFE], &info);
+    }
+
+    #[test]
+    #[should_panic(expected = "Operand big of BIPUSH is out of range")]
+    fn named_immediate_out_of_range() {
+        let program = r#"
+                       .constant
+                       big 0x100
+                       .end-constant
+                       .main
+                       BIPUSH big
+                       .end-main
+"#;
+        compile(program, 0, None);
+    }
+
+    #[test]
+    fn constant_section() {
+        let source = ".constant\n  newline '\\n' // LF\n\n  end start + 'A' * 2\n.end-constant\n.main\n.end-main";
+        let (source, constants) = split_constants(source);
+
+        assert_eq!("\n\n\n\n\n.main\n.end-main\n", source);
+        assert_eq!(vec![
+            ("newline".to_string(), "'\\n'".to_string()),
+            ("end".to_string(), "start + 'A' * 2".to_string()),
+        ], constants);
+    }
+
+    #[test]
+    fn include_lines() {
+        let (source, includes) = split_includes(".include \"math.jas\"\n.main\n  .include \"io.jas\"\n.end-main");
+
+        assert_eq!("\n.main\n\n.end-main\n", source);
+        assert_eq!(vec!["math.jas", "io.jas"], includes);
+    }
+
+    #[test]
+    fn included_library() {
+        let program = r#".include "math.jas"
+                       .constant
+                       one 1
+                       .end-constant
+                       .main
+                       LDC_W ten
+                       INVOKEVIRTUAL double
+                       .end-main
+"#;
+        let library = r#".include "math.jas"
+                       .constant
+                       one 1
+                       ten 10
+                       .end-constant
+                       .method double(x)
+                       ILOAD x
+                       DUP
+                       IADD
+                       IRETURN
+                       .end-method
+"#;
+        let info = compile_with_includes(program, "main.jas", &Isa::standard(), 100, Some(0xFF), |name, including| {
+            assert_eq!("math.jas", name);
+            // The library includes itself, so it's read again but compiled once
+            assert!(["main.jas", "lib/math.jas"].contains(&including));
+            ("lib/math.jas".to_string(), library.to_string())
+        });
+
+        assert_constants(vec![1, 10, 107], &info);
+        assert_main(vec![
+            LDC_W as i32, 0x00, 0x01, INVOKEVIRTUAL as i32, 0x00, 0x02, 0xFF,
+            0x00, 0x02, 0x00, 0x00, ILOAD as i32, 0x01, DUP as i32, IADD as i32, IRETURN as i32,
+        ], &info);
+        assert_eq!(SourceLine { file: "main.jas".to_string(), line: 6 }, info.lines[0]);
+        assert_eq!(SourceLine { file: "lib/math.jas".to_string(), line: 6 }, info.lines[7]);
+        assert_eq!("lib/math.jas, line 6", info.lines[7].to_string());
+    }
+
+    #[test]
+    fn include_paths() {
+        let directory = env::temp_dir().join(format!("compiler_{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(directory.join("lib").join("math.jas"), ".include \"util.jas\"\n").unwrap();
+        fs::write(directory.join("lib").join("util.jas"), "").unwrap();
+        let math = read_relative("lib/math.jas", &directory.join("main.jas").display().to_string());
+        let util = read_relative("util.jas", &math.0);
+        let again = read_relative("../lib/./math.jas", &util.0);
+        fs::remove_dir_all(&directory).unwrap();
+
+        let lib = fs::canonicalize(env::temp_dir()).unwrap().join(format!("compiler_{}", std::process::id())).join("lib");
+        assert_eq!(lib.join("math.jas").display().to_string(), math.0);
+        assert_eq!(".include \"util.jas\"\n", math.1);
+        assert_eq!(lib.join("util.jas").display().to_string(), util.0);
+        assert_eq!(math, again);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown method: double")]
+    fn method_without_include() {
+        let program = r#"
+                       .main
+                       INVOKEVIRTUAL double
+                       .end-main
+"#;
+        compile_with_includes(program, "", &Isa::standard(), 100, None, |_, _| unreachable!());
+    }
+
     fn assert_constants(expected: Vec<i32>, info: &ProcessorInfo) {
         assert_eq!(expected, info.constants);
     }
@@ -1,47 +1,48 @@
-pub fn decoder_2x4(f0: bool, f1: bool) -> [bool; 4] {
-    [!f0 && !f1, f0 && !f1, !f0 && f1, f0 && f1]
+use crate::logic::Logic;
+
+pub fn decoder_2x4<T: Logic>(f0: T, f1: T) -> [T; 4] {
+    [!f0 & !f1, f0 & !f1, !f0 & f1, f0 & f1]
 }
 
-fn decoder_4x16(f0: bool, f1: bool, f2: bool, f3: bool) -> [bool; 16] {
-    let mut res = [false; 16];
+fn decoder_4x16<T: Logic>(f0: T, f1: T, f2: T, f3: T) -> [T; 16] {
+    let mut res = [T::constant(false); 16];
     let en = decoder_2x4(f2, f3);
     let s = decoder_2x4(f0, f1);
     for i in 0..4 {
         for k in 0..4 {
-            res[i * 4 + k] = en[i] && s[k];
+            res[i * 4 + k] = en[i] & s[k];
         }
     }
 
     res
 }
 
-pub fn decoder_4x9(input: [bool; 4]) -> [bool; 9] {
-    let mut dest = [false; 9];
+pub fn decoder_4x9<T: Logic>(input: [T; 4]) -> [T; 9] {
+    let mut dest = [T::constant(false); 9];
     let res = decoder_4x16(input[0], input[1], input[2], input[3]);
     dest.copy_from_slice(&res[..9]);
     dest
 }
 
-pub fn decoder_9x512(input: [bool; 9]) -> [bool; 512] {
-    let mut res = [false; 512];
-    let first_part = decoder_4x16(input[0], input[1], input[2], input[3]);
-    let second_part = decoder_4x16(input[4], input[5], input[6], input[7]);
-
-    for i in 0..16 {
-        for k in 0..16 {
-            res[i * 16 + k] = first_part[k] && second_part[i];
-            res[i * 16 + k + 256] = first_part[k] && second_part[i];
+/// Decoder of any width, the input is split into 4x16 decoders and their outputs are combined
+pub fn decoder<T: Logic>(input: &[T]) -> Vec<T> {
+    let mut res = vec![T::constant(true)];
+    let mut rest = input;
+    while !rest.is_empty() {
+        let (part, used) = match rest.len() {
+            1 => (vec![!rest[0], rest[0]], 1),
+            2 | 3 => (decoder_2x4(rest[0], rest[1]).to_vec(), 2),
+            _ => (decoder_4x16(rest[0], rest[1], rest[2], rest[3]).to_vec(), 4),
+        };
+        let mut combined = Vec::with_capacity(res.len() * part.len());
+        for high in part.iter() {
+            for low in res.iter() {
+                combined.push(*low & *high);
+            }
         }
+        res = combined;
+        rest = &rest[used..];
     }
-
-    for i in 0..256 {
-        res[i] = res[i] && !input[8];
-    }
-
-    for i in 256..512 {
-        res[i] = res[i] && input[8];
-    }
-
     res
 }
 
@@ -61,7 +62,7 @@ mod tests {
 
     #[test]
     fn dec_all_zero() {
-        let input = decoder_9x512([false; 9]);
+        let input = decoder(&[false; 9]);
         assert_eq!(true, input[0]);
         for x in 1..512 {
             assert_eq!(false, input[x]);
@@ -70,7 +71,7 @@ mod tests {
 
     #[test]
     fn dec_last_true() {
-        let input = decoder_9x512([false, false, false, false, false, false, false, false, true]);
+        let input = decoder(&[false, false, false, false, false, false, false, false, true]);
         for x in 0..512 {
             if x == 256 {
                 assert_eq!(true, input[x], "Index: {}", x);
@@ -110,9 +111,23 @@ mod tests {
             number += if res[x] { 2i32.pow(x as u32) } else { 0 };
         }
 
-        let decoded = decoder_9x512(res);
+        let decoded = decoder(&res);
         for x in 0..512 {
             assert_eq!(number == x, decoded[x as usize], "Index: {}", x)
         }
     }
+
+    #[quickcheck]
+    fn dec_any_width(value: u16) {
+        for width in 1..12 {
+            let number = value as usize % (1 << width);
+            let input: Vec<bool> = (0..width).map(|x| number & (1 << x) != 0).collect();
+
+            let decoded = decoder(&input);
+            assert_eq!(1 << width, decoded.len());
+            for x in 0..decoded.len() {
+                assert_eq!(number == x, decoded[x], "Width: {}, index: {}", width, x)
+            }
+        }
+    }
 }
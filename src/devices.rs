@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+
+use crate::main_memory::Device;
+
+/**
+ * Character console.
+ *
+ * Offset 0: writing prints the character, reading takes the next input character or -1 if there is none.
+ * Offset 1: amount of input characters left, writes are ignored.
+ */
+pub struct Console {
+    pub input: VecDeque<i64>,
+    pub output: String,
+}
+
+impl Console {
+    pub fn new(input: &str) -> Console {
+        Console { input: input.chars().map(|x| x as i64).collect(), output: String::new() }
+    }
+}
+
+impl Device for Console {
+    fn read(&mut self, offset: usize) -> i64 {
+        match offset {
+            0 => self.input.pop_front().unwrap_or(-1),
+            _ => self.input.len() as i64,
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: i64) {
+        if offset == 0 {
+            self.output.push(std::char::from_u32(value as u32).unwrap_or(std::char::REPLACEMENT_CHARACTER));
+        }
+    }
+}
+
+/// Counts cycles, writing sets the counter
+pub struct Timer {
+    pub counter: i64,
+}
+
+impl Timer {
+    pub fn new() -> Timer { Timer { counter: 0 } }
+}
+
+impl Device for Timer {
+    fn read(&mut self, _offset: usize) -> i64 { self.counter }
+
+    fn write(&mut self, _offset: usize, value: i64) { self.counter = value }
+
+    fn tick(&mut self) { self.counter += 1 }
+}
+
+/// Monochrome screen, the pixel at (x, y) is at offset `y * width + x`, non-zero pixels are lit
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<i64>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer { width, height, pixels: vec![0; width * height] }
+    }
+
+    /// Size of the region to attach
+    pub fn size(&self) -> usize { self.width * self.height }
+
+    /// Lines of the screen, `#` for lit pixels and `.` for others
+    pub fn render(&self) -> String {
+        let lines: Vec<String> = self.pixels.chunks(self.width)
+            .map(|line| line.iter().map(|x| if *x != 0 { '#' } else { '.' }).collect())
+            .collect();
+        lines.join("\n")
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: usize) -> i64 { self.pixels[offset] }
+
+    fn write(&mut self, offset: usize, value: i64) { self.pixels[offset] = value }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::{create_processor, PROGRAM_START};
+    use crate::main_memory::{decode, encode, MainMemory};
+    use crate::parser::parse;
+
+    use super::*;
+
+    /// LV is at the start of the stack, so the local 0x78 is at 130, right after the programs
+    const DEVICE: usize = 130;
+
+    #[test]
+    fn console() {
+        let console = Rc::new(RefCell::new(Console::new("x")));
+        let commands = parse("BIPUSH 0x48\nISTORE 0x78\nBIPUSH 0x69\nISTORE 0x78\nILOAD 0x78\nILOAD 0x78");
+        let mut mic1 = create_processor(&commands, vec![0], [0; 10]);
+        mic1.main_memory.attach(DEVICE, 2, Box::new(console.clone()));
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        assert_eq!("Hi", console.borrow().output);
+        let sp = encode(&mic1.sp.get()) as usize;
+        assert_eq!('x' as i32, mic1.main_memory.read_number(sp - 1));
+        assert_eq!(-1, encode(&mic1.tos.get()));
+        // The cell under the device is not touched
+        assert_eq!(0, mic1.main_memory.read_number(DEVICE));
+    }
+
+    #[test]
+    fn timer() {
+        let timer = Rc::new(RefCell::new(Timer::new()));
+        let commands = parse("ILOAD 0x78\nILOAD 0x78\nISUB");
+        let mut mic1 = create_processor(&commands, vec![0], [0; 10]);
+        mic1.main_memory.attach(DEVICE, 1, Box::new(timer.clone()));
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        // ILOAD with Main1 takes 6 cycles
+        assert_eq!(-6, encode(&mic1.tos.get()));
+        assert_eq!(mic1.cycles as i64, timer.borrow().counter);
+    }
+
+    #[test]
+    fn framebuffer() {
+        let screen = Rc::new(RefCell::new(Framebuffer::new(3, 2)));
+        let commands = parse("BIPUSH 0x01\nDUP\nISTORE 0x78\nISTORE 0x7C");
+        let mut mic1 = create_processor(&commands, vec![0], [0; 10]);
+        mic1.main_memory.attach(DEVICE, screen.borrow().size(), Box::new(screen.clone()));
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        assert_eq!("#..\n.#.", screen.borrow().render());
+    }
+
+    /// Counts the requests and answers after three cycles
+    struct Slow {
+        requests: usize,
+    }
+
+    impl Device for Slow {
+        fn read(&mut self, offset: usize) -> i64 { (offset + self.requests * 10) as i64 }
+        fn write(&mut self, _offset: usize, _value: i64) {}
+        fn read_requested(&mut self, _offset: usize) { self.requests += 1 }
+        fn read_latency(&self) -> usize { 3 }
+    }
+
+    #[test]
+    fn read_latency() {
+        let mut memory: MainMemory = MainMemory::initialize();
+        memory.write_data(7, 5);
+        memory.attach(300, 4, Box::new(Slow { requests: 0 }));
+
+        memory.request_first_read(decode(302), true);
+        memory.request_second_read(decode(5), true);
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        for _ in 0..5 {
+            let (value, enabled) = memory.check_first_read();
+            first.push((enabled, encode(&value)));
+            let (value, enabled) = memory.check_second_read();
+            second.push((enabled, encode(&value)));
+        }
+
+        assert_eq!(vec![(false, 0), (false, 0), (false, 0), (true, 12), (false, 0)], first);
+        assert_eq!(vec![(false, 0), (true, 7), (false, 0), (false, 0), (false, 0)], second);
+    }
+
+    #[test]
+    fn ready_and_write_latency() {
+        let mut memory: MainMemory = MainMemory::initialize();
+        memory.write_latency = 2;
+        memory.write(decode(7), decode(20), true);
+        memory.request_first_read(decode(5), true);
+        assert!(!memory.ready());
+
+        memory.tick();
+        memory.check_first_read();
+        // Both the read and the write are finished at the beginning of the next cycle
+        assert!(memory.ready());
+        assert_eq!(0, memory.read_number(20));
+
+        memory.tick();
+        assert_eq!(7, memory.read_number(20));
+        assert!(!memory.late_read(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Region 10..20 overlaps with 15..16")]
+    fn overlapping_regions() {
+        let mut memory: MainMemory = MainMemory::initialize();
+        memory.attach(15, 1, Box::new(Timer::new()));
+        memory.attach(10, 10, Box::new(Timer::new()));
+    }
+}
@@ -0,0 +1,289 @@
+use crate::interpreter::Ijvm;
+use crate::main_memory::{fast_encode, MEMORY_SIZE};
+use crate::processor::Mic1;
+use crate::STACK_START;
+use crate::traps::FaultKind;
+
+#[derive(Debug, PartialEq)]
+pub enum Location {
+    Register(&'static str),
+    Stack(i32),
+    Local(i32),
+    Memory(i32),
+    /// Kinds of the faults, the values are their addresses
+    Fault { reference: Option<FaultKind>, microcode: Option<FaultKind> },
+}
+
+/// The first place where the microcode disagrees with the reference interpreter
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// Number of executed instructions, 0 means the initial state
+    pub instruction: usize,
+    /// Address of the instruction that caused the divergence
+    pub pc: i32,
+    pub location: Location,
+    pub reference: i32,
+    pub microcode: i32,
+}
+
+/**
+ * Runs `Mic1` and the reference interpreter instruction by instruction and compares
+ *   registers and the whole memory after each instruction.
+ *
+ * `mic1` should be just created: it starts one cell before the program and executes
+ *   an implicit NOP first, `reference` should point to the first instruction of the same program.
+ * The run stops at the same fault of both engines, the state after it isn't compared.
+ */
+pub fn run_differential(mic1: &mut Mic1, reference: &mut Ijvm, max_instructions: usize, stop_instruction: Option<i32>) -> Option<Divergence> {
+    mic1.step_instruction();
+    if let Some(divergence) = compare(mic1, reference, 0, reference.pc) {
+        return Some(divergence);
+    }
+
+    for instruction in 1..=max_instructions {
+        if stop_instruction == Some(reference.opcode()) {
+            break;
+        }
+
+        let pc = reference.pc;
+        mic1.step_instruction();
+        reference.step();
+
+        if let Some(divergence) = compare(mic1, reference, instruction, pc) {
+            return Some(divergence);
+        }
+        if reference.halted() {
+            break;
+        }
+    }
+    None
+}
+
+fn compare(mic1: &Mic1, reference: &Ijvm, instruction: usize, pc: i32) -> Option<Divergence> {
+    let reference_fault = reference.fault.map(|x| (x.kind, x.address));
+    let microcode_fault = mic1.fault.map(|x| (x.kind, x.address));
+    if reference_fault != microcode_fault {
+        let location = Location::Fault { reference: reference_fault.map(|x| x.0), microcode: microcode_fault.map(|x| x.0) };
+        let address = |fault: Option<(FaultKind, i64)>| fault.map_or(0, |x| x.1 as i32);
+        return Some(Divergence { instruction, pc, location, reference: address(reference_fault), microcode: address(microcode_fault) });
+    }
+    if reference_fault.is_some() {
+        return None;
+    }
+
+    let registers = [
+        ("PC", reference.pc, fast_encode(&mic1.pc.get())),
+        ("SP", reference.sp, fast_encode(&mic1.sp.get())),
+        ("LV", reference.lv, fast_encode(&mic1.lv.get())),
+        ("CPP", reference.cpp, fast_encode(&mic1.cpp.get())),
+        ("TOS", reference.tos, fast_encode(&mic1.tos.get())),
+    ];
+    for (name, reference_value, microcode_value) in registers.iter() {
+        if reference_value != microcode_value {
+            return Some(Divergence { instruction, pc, location: Location::Register(name), reference: *reference_value, microcode: *microcode_value });
+        }
+    }
+
+    for address in 0..MEMORY_SIZE {
+        let reference_value = reference.main_memory.read_number(address);
+        let microcode_value = mic1.main_memory.read_number(address);
+        if reference_value != microcode_value {
+            let location = location(reference, address as i32);
+            return Some(Divergence { instruction, pc, location, reference: reference_value, microcode: microcode_value });
+        }
+    }
+    None
+}
+
+/// Locals are located between LV and the link pointer, stack is everything up to SP
+fn location(reference: &Ijvm, address: i32) -> Location {
+    let link = reference.main_memory.read_number(reference.lv as usize);
+    if address >= reference.lv && address < link && link <= reference.sp {
+        Location::Local(address)
+    } else if address >= STACK_START && address <= reference.sp {
+        Location::Stack(address)
+    } else {
+        Location::Memory(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_interpreter, create_processor, create_processor_with_control_memory, make_control_memory, make_control_memory_with_layout, PROGRAM_START};
+    use crate::alu::Adder;
+    use crate::asm::IjvmCommand::*;
+    use crate::microasm::MicroAsm::{iadd3, isub3};
+    use crate::mir_layout::MirLayout;
+    use crate::parser::parse;
+
+    use super::*;
+
+    fn assert_same(program: &str, initial_stack: Vec<i32>, constants: [i32; 10]) {
+        let commands = parse(program);
+        let mut mic1 = create_processor(&commands, initial_stack.clone(), constants);
+        let mut reference = create_interpreter(&commands, initial_stack, constants);
+
+        assert_eq!(None, run_differential(&mut mic1, &mut reference, 100, Some(0xFF)));
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_same("BIPUSH 0x05\nIADD\nDUP\nISUB\nSWAP\nIAND\nIOR\nPOP", vec![1, 2, 3, 4], [0; 10]);
+    }
+
+    #[test]
+    fn shifts() {
+        assert_same("BIPUSH 0x03\nISHL\nBIPUSH 0x21\nISHR\nBIPUSH 0x04\nIUSHR", vec![-7, 5], [0; 10]);
+    }
+
+    #[test]
+    fn locals() {
+        assert_same("ILOAD 0x01\nISTORE 0x02\nIINC 0x00 0x07\nWIDE\nILOAD 0x00 0x03\nWIDE\nISTORE 0x00 0x00", vec![1, 2, 3, 4], [0; 10]);
+    }
+
+    #[test]
+    fn negative_increment() {
+        assert_same("IINC 0x01 -1\nIINC 0x00 0x80\nILOAD 0x01\nILOAD 0x00", vec![1, 10], [0; 10]);
+    }
+
+    #[test]
+    fn same_fault() {
+        assert_same("BIPUSH 0x05\nILOAD 0x80\nBIPUSH 0x05", vec![1, 2], [0; 10]);
+        assert_same("ISTORE 0x80", vec![1, 2], [0; 10]);
+    }
+
+    #[test]
+    fn constants() {
+        assert_same("LDC_W 0x00 0x02\nLDC_W 0x00 0x00\nIADD", vec![1], [7, 8, 9, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn loop_with_branches() {
+        assert_same("BIPUSH 0x05\nDUP\nIFEQ 0x00 0x09\nBIPUSH 0x01\nISUB\nGOTO 0xFF 0xF9\n0xFF", vec![], [0; 10]);
+    }
+
+    #[test]
+    fn compare_branches() {
+        assert_same("IF_ICMPEQ 0x00 0x06\nIINC 0x00 0x01\nIFLT 0x00 0x04\nNOP\n0xFF", vec![1, -2, 3, 3], [0; 10]);
+    }
+
+    #[test]
+    fn method_call() {
+        let program = r#"
+                BIPUSH 0x02
+                BIPUSH 0x05
+                INVOKEVIRTUAL 0x00 0x02
+                0xFF
+                0x00  0x02
+                0x00  0x01
+                ILOAD 0x01
+                DUP
+                IADD
+                IRETURN
+"#;
+        let constants = [0xCA, 0x11, PROGRAM_START as i32 + 0x08, 0, 0, 0, 0, 0, 0, 0];
+        assert_same(program, vec![1, 2], constants);
+    }
+
+    #[test]
+    fn alternative_adders() {
+        let commands = parse("BIPUSH 0x05\nDUP\nIFEQ 0x00 0x09\nBIPUSH 0x01\nISUB\nGOTO 0xFF 0xF9\n0xFF");
+        for adder in [Adder::CarryLookahead, Adder::CarrySelect].iter() {
+            let mut mic1 = create_processor(&commands, vec![-3], [0; 10]).with_adder(*adder);
+            let mut reference = create_interpreter(&commands, vec![-3], [0; 10]);
+
+            assert_eq!(None, run_differential(&mut mic1, &mut reference, 100, Some(0xFF)), "{:?}", adder);
+        }
+    }
+
+    #[test]
+    fn wider_microinstruction() {
+        let layout = MirLayout::standard().resize("addr", 10).field("spare", 4);
+        let commands = parse("BIPUSH 0x05\nDUP\nIFEQ 0x00 0x09\nBIPUSH 0x01\nISUB\nGOTO 0xFF 0xF9\n0xFF");
+        let mut mic1 = create_processor_with_control_memory(&commands, vec![2], [0; 10], make_control_memory_with_layout(layout));
+        let mut reference = create_interpreter(&commands, vec![2], [0; 10]);
+
+        assert_eq!(None, run_differential(&mut mic1, &mut reference, 100, Some(0xFF)));
+    }
+
+    #[test]
+    fn broken_microcode() {
+        let mut control_memory = make_control_memory();
+        control_memory.write_data(&isub3.command(&MirLayout::standard()), iadd3 as usize);
+
+        let commands = parse("DUP\nIADD");
+        let mut mic1 = create_processor_with_control_memory(&commands, vec![1, 2], [0; 10], control_memory);
+        let mut reference = create_interpreter(&commands, vec![1, 2], [0; 10]);
+
+        let divergence = run_differential(&mut mic1, &mut reference, 10, None);
+
+        let expected = Divergence { instruction: 2, pc: PROGRAM_START as i32 + 1, location: Location::Register("TOS"), reference: 4, microcode: 0 };
+        assert_eq!(Some(expected), divergence);
+    }
+
+    #[quickcheck]
+    fn random_programs(commands: Vec<(u8, u8)>) {
+        let mut program = Vec::new();
+        let mut depth = 4;
+        for (command, argument) in commands.into_iter().take(16) {
+            let argument = argument as i32;
+            match command % 14 {
+                0 => {
+                    program.extend(vec![BIPUSH as i32, argument]);
+                    depth += 1;
+                }
+                1 if depth > 1 => {
+                    program.push(IADD as i32);
+                    depth -= 1;
+                }
+                2 if depth > 1 => {
+                    program.push(ISUB as i32);
+                    depth -= 1;
+                }
+                3 if depth > 1 => {
+                    program.push(IAND as i32);
+                    depth -= 1;
+                }
+                4 if depth > 1 => {
+                    program.push(IOR as i32);
+                    depth -= 1;
+                }
+                5 => {
+                    program.push(DUP as i32);
+                    depth += 1;
+                }
+                6 if depth > 1 => {
+                    program.push(POP as i32);
+                    depth -= 1;
+                }
+                7 if depth > 1 => program.push(SWAP as i32),
+                8 => {
+                    program.extend(vec![ILOAD as i32, argument % 4]);
+                    depth += 1;
+                }
+                9 if depth > 1 => {
+                    program.extend(vec![ISTORE as i32, argument % 4]);
+                    depth -= 1;
+                }
+                10 if depth > 1 => {
+                    program.push(ISHL as i32);
+                    depth -= 1;
+                }
+                11 if depth > 1 => {
+                    program.push(ISHR as i32);
+                    depth -= 1;
+                }
+                12 if depth > 1 => {
+                    program.push(IUSHR as i32);
+                    depth -= 1;
+                }
+                _ => program.extend(vec![IINC as i32, argument % 4, argument]),
+            }
+        }
+
+        let mut mic1 = create_processor(&program, vec![1, 2, 3, 4], [0; 10]);
+        let mut reference = create_interpreter(&program, vec![1, 2, 3, 4], [0; 10]);
+
+        assert_eq!(None, run_differential(&mut mic1, &mut reference, program.len(), None));
+    }
+}
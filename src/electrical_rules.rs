@@ -0,0 +1,181 @@
+use std::fmt;
+
+use crate::processor_elements::{BBusControls, B_BUS_SOURCES};
+
+/// What the simulator does when a microinstruction breaks the electrical rules
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RuleChecking {
+    Off,
+    /// Violations are collected and the simulation goes on
+    Warn,
+    /// The first violation halts the processor before the microinstruction is executed, see `Mic1::rule_error`
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Violation {
+    /// Several sources drive the B bus at once, the bus gets OR of them
+    BusContention(Vec<&'static str>),
+    /// ALU reads the B bus, but nothing drives it
+    FloatingBus,
+    /// Memory strobes that can't be served in the same cycle
+    MemoryStrobes(Vec<&'static str>),
+    /// MDR or MBR is used while the read into it is late, see `MainMemory::late_read`
+    DataNotReady(&'static str),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::BusContention(drivers) => write!(f, "B bus is driven by {}", drivers.join(", ")),
+            Violation::FloatingBus => write!(f, "B bus is read by ALU, but nothing drives it"),
+            Violation::MemoryStrobes(strobes) => write!(f, "Conflicting memory strobes: {}", strobes.join(", ")),
+            Violation::DataNotReady(register) => write!(f, "{} is used before the data arrived", register),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleViolation {
+    pub cycle: usize,
+    /// Address of the microinstruction that breaks the rule
+    pub mpc: usize,
+    pub violation: Violation,
+}
+
+impl fmt::Display for RuleViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cycle {}, MPC 0x{:03X}: {}", self.cycle, self.mpc, self.violation)
+    }
+}
+
+/**
+ * Electrical rules of a single cycle.
+ *
+ * The decoder enables exactly one of the B bus sources for codes 0-8, so contention is only possible
+ *   with broken enables, e.g. with stuck-at faults. Codes 9-15 leave the bus floating.
+ * MAR is the address of both the read and the write, so they can't be requested together.
+ */
+pub fn check_cycle(b_controls: &BBusControls, en_b: bool, read: bool, write: bool) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    let drivers: Vec<&'static str> = (0..B_BUS_SOURCES.len()).filter(|x| b_controls.enabled(*x)).map(|x| B_BUS_SOURCES[x]).collect();
+    if drivers.len() > 1 {
+        violations.push(Violation::BusContention(drivers));
+    } else if drivers.is_empty() && en_b {
+        violations.push(Violation::FloatingBus);
+    }
+
+    if read && write {
+        violations.push(Violation::MemoryStrobes(vec!["RD", "WR"]));
+    }
+
+    violations
+}
+
+/// Timing rule: MDR is used if ALU reads it from the B bus, MBR is also used by JMPC
+pub fn check_data_ready(b_controls: &BBusControls, en_b: bool, jmpc: bool, late_mdr: bool, late_mbr: bool) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if late_mdr && en_b && b_controls.mdr() {
+        violations.push(Violation::DataNotReady("MDR"));
+    }
+    if late_mbr && (en_b && (b_controls.mbr() || b_controls.mbru()) || jmpc) {
+        violations.push(Violation::DataNotReady("MBR"));
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_processor, PROGRAM_START};
+    use crate::decoders::decoder_4x9;
+    use crate::fault_injection::parse_faults;
+    use crate::microasm::MicroAsm::iadd1;
+    use crate::parser::parse;
+    use crate::processor::Mic1;
+
+    use super::*;
+
+    const PROGRAM: &str = "BIPUSH 0x05\nDUP\nIADD\nBIPUSH 0x03\nISUB\nDUP\nIAND\nSWAP\nPOP\nDUP\nIFEQ 0x00 0x05\nBIPUSH 0x01\nIOR";
+
+    fn processor(faults: &str) -> Mic1 {
+        let mut mic1 = create_processor(&parse(PROGRAM), vec![1, 2], [0; 10]);
+        for fault in parse_faults(faults).unwrap() {
+            mic1.inject(fault);
+        }
+        mic1
+    }
+
+    fn controls(code: usize) -> BBusControls {
+        BBusControls::new(decoder_4x9([code & 1 == 1, code & 2 == 2, code & 4 == 4, code & 8 == 8]))
+    }
+
+    #[test]
+    fn decoded_sources() {
+        for code in 0..9 {
+            assert_eq!(Vec::<Violation>::new(), check_cycle(&controls(code), true, false, false));
+        }
+        for code in 9..16 {
+            assert_eq!(vec![Violation::FloatingBus], check_cycle(&controls(code), true, false, false));
+            assert_eq!(Vec::<Violation>::new(), check_cycle(&controls(code), false, false, false));
+        }
+    }
+
+    #[test]
+    fn contention() {
+        let mut enables = [false; 9];
+        enables[0] = true;
+        enables[7] = true;
+        let violations = check_cycle(&BBusControls::new(enables), false, true, true);
+        assert_eq!(vec![Violation::BusContention(vec!["MDR", "TOS"]), Violation::MemoryStrobes(vec!["RD", "WR"])], violations);
+
+        let violation = RuleViolation { cycle: 3, mpc: 0x12, violation: violations[0].clone() };
+        assert_eq!("Cycle 3, MPC 0x012: B bus is driven by MDR, TOS", violation.to_string());
+    }
+
+    #[test]
+    fn microprogram_follows_the_rules() {
+        let mut mic1 = processor("");
+        mic1.rule_checking = RuleChecking::Error;
+        mic1.run(parse(PROGRAM).len() + 1, PROGRAM_START);
+        assert_eq!(Vec::<RuleViolation>::new(), mic1.violations);
+    }
+
+    #[test]
+    fn stuck_enable() {
+        let mut mic1 = processor("stuck-at-1 b-enable 7");
+        mic1.run_n_times(3);
+
+        // Main1 puts PC on the B bus
+        let violation = &mic1.violations[0];
+        assert_eq!(1, violation.cycle);
+        assert_eq!(1, violation.mpc);
+        assert_eq!(Violation::BusContention(vec!["PC", "TOS"]), violation.violation);
+    }
+
+    #[test]
+    fn read_and_write() {
+        let mut mic1 = processor(&format!("stuck-at-1 control-store {} 29", iadd1 as usize));
+        mic1.rule_checking = RuleChecking::Error;
+        mic1.run_n_times(20);
+
+        assert!(mic1.halted());
+        let violation = mic1.rule_error.clone().unwrap();
+        assert_eq!(iadd1 as usize, violation.mpc);
+        assert_eq!("Conflicting memory strobes: RD, WR", violation.violation.to_string());
+        assert!(mic1.violations.is_empty());
+
+        // Nothing is executed after the violation
+        let cycles = mic1.cycles;
+        mic1.run_n_times(5);
+        assert_eq!(cycles, mic1.cycles);
+    }
+
+    #[test]
+    fn checking_is_off() {
+        let mut mic1 = processor("stuck-at-1 b-enable 7");
+        mic1.rule_checking = RuleChecking::Off;
+        mic1.run_n_times(20);
+        assert!(mic1.violations.is_empty());
+    }
+}
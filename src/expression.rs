@@ -0,0 +1,193 @@
+use std::convert::TryFrom;
+
+use crate::parser::number;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    /// Number, character literal or name
+    Word(String),
+    Operator(&'static str),
+}
+
+impl Token {
+    fn text(&self) -> &str {
+        match self {
+            Token::Word(word) => word,
+            Token::Operator(operator) => operator,
+        }
+    }
+}
+
+const OPERATORS: [&str; 13] = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")"];
+
+/// Binary operators from the lowest precedence to the highest
+const LEVELS: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+/**
+ * Value of a constant expression like `BUF_START + 64` or `'A' - 1`.
+ *
+ * Operators and precedence are the same as in Rust. Names are resolved with `lookup`,
+ *   literals are numbers of `parser::number`.
+ */
+pub fn evaluate(text: &str, lookup: impl Fn(&str) -> Option<i64>) -> i64 {
+    let tokens = tokenize(text);
+    let mut evaluator = Evaluator { text, tokens, position: 0, lookup };
+    let value = evaluator.binary(0);
+    if evaluator.position != evaluator.tokens.len() {
+        panic!("Unexpected {} in the expression {}", evaluator.tokens[evaluator.position].text(), text);
+    }
+    value
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let length = if let Some(literal) = rest.strip_prefix('\'') {
+            // The closing quote, an escaped quote is skipped
+            let end = match literal.strip_prefix('\\') {
+                Some(escaped) => escaped.get(1..).and_then(|x| x.find('\'')).map(|x| x + 3),
+                None => literal.find('\'').map(|x| x + 1),
+            };
+            let end = end.unwrap_or_else(|| panic!("Unclosed character literal in the expression {}", text));
+            tokens.push(Token::Word(rest[..=end].to_string()));
+            end + 1
+        } else if let Some(operator) = OPERATORS.iter().find(|x| rest.starts_with(*x)) {
+            tokens.push(Token::Operator(operator));
+            operator.len()
+        } else {
+            let length = rest.find(|x: char| !(x.is_alphanumeric() || x == '_')).unwrap_or(rest.len());
+            if length == 0 {
+                panic!("Unexpected character in the expression {}", text);
+            }
+            tokens.push(Token::Word(rest[..length].to_string()));
+            length
+        };
+        rest = rest[length..].trim_start();
+    }
+    tokens
+}
+
+struct Evaluator<'t, F: Fn(&str) -> Option<i64>> {
+    text: &'t str,
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: F,
+}
+
+impl<F: Fn(&str) -> Option<i64>> Evaluator<'_, F> {
+    fn binary(&mut self, level: usize) -> i64 {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1);
+        while let Some(Token::Operator(operator)) = self.tokens.get(self.position).cloned() {
+            if !LEVELS[level].contains(&operator) {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(level + 1);
+            value = self.apply(operator, value, right);
+        }
+        value
+    }
+
+    fn unary(&mut self) -> i64 {
+        match self.next() {
+            Token::Operator("-") => self.unary().checked_neg().unwrap_or_else(|| self.overflow()),
+            Token::Operator("+") => self.unary(),
+            Token::Operator("~") => !self.unary(),
+            Token::Operator("(") => {
+                let value = self.binary(0);
+                if self.next() != Token::Operator(")") {
+                    panic!("Unclosed parenthesis in the expression {}", self.text);
+                }
+                value
+            }
+            Token::Word(word) => {
+                if word.starts_with(|x: char| x.is_ascii_digit() || x == '\'') {
+                    number(&word)
+                } else {
+                    (self.lookup)(&word).unwrap_or_else(|| panic!("Unknown constant {} in the expression {}", word, self.text))
+                }
+            }
+            Token::Operator(operator) => panic!("Unexpected {} in the expression {}", operator, self.text),
+        }
+    }
+
+    fn apply(&self, operator: &str, left: i64, right: i64) -> i64 {
+        let value = match operator {
+            "+" => left.checked_add(right),
+            "-" => left.checked_sub(right),
+            "*" => left.checked_mul(right),
+            "/" | "%" if right == 0 => panic!("Division by zero in the expression {}", self.text),
+            "/" => left.checked_div(right),
+            "%" => left.checked_rem(right),
+            "<<" => u32::try_from(right).ok().and_then(|x| left.checked_shl(x)),
+            ">>" => u32::try_from(right).ok().and_then(|x| left.checked_shr(x)),
+            "&" => Some(left & right),
+            "|" => Some(left | right),
+            "^" => Some(left ^ right),
+            _ => unreachable!(),
+        };
+        value.unwrap_or_else(|| self.overflow())
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens.get(self.position).cloned().unwrap_or_else(|| panic!("Unexpected end of the expression {}", self.text));
+        self.position += 1;
+        token
+    }
+
+    fn overflow(&self) -> ! { panic!("Overflow in the expression {}", self.text) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(name: &str) -> Option<i64> {
+        match name {
+            "BUF_START" => Some(0x40),
+            "SIZE" => Some(8),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(-5, evaluate("-5", constant));
+        assert_eq!(0xFF, evaluate("0xFF", constant));
+        assert_eq!(65, evaluate("'A'", constant));
+        assert_eq!(39, evaluate("'\\''", constant));
+        assert_eq!(32, evaluate("' '", constant));
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(0x40 + 64, evaluate("BUF_START + 64", constant));
+        assert_eq!(0x40 + 2 * 8, evaluate("BUF_START+2*SIZE", constant));
+        assert_eq!(-0x30, evaluate("-(BUF_START - 2 * SIZE)", constant));
+        assert_eq!(1 | 4, evaluate("1 | 1 << 2 & 0xFF", constant));
+        assert_eq!('a' as i64, evaluate("'A' + ('a' - 'A')", constant));
+        assert_eq!(!7 % 3, evaluate("~7 % 3", constant));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown constant END in the expression BUF_START + END")]
+    fn unknown_constant() {
+        evaluate("BUF_START + END", constant);
+    }
+
+    #[test]
+    #[should_panic(expected = "Division by zero in the expression SIZE / (SIZE - 8)")]
+    fn division_by_zero() {
+        evaluate("SIZE / (SIZE - 8)", constant);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected ) in the expression (1 + 2))")]
+    fn parentheses() {
+        evaluate("(1 + 2))", constant);
+    }
+}
@@ -0,0 +1,376 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::arch_state::ArchState;
+use crate::main_memory::{encode, MEMORY_SIZE};
+use crate::memory::ControlMemory;
+use crate::microasm::MicroAsm::Main1;
+use crate::processor::Mic1;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegisterName {
+    Mar,
+    Mdr,
+    Pc,
+    Mbr,
+    Sp,
+    Lv,
+    Cpp,
+    Tos,
+    Opc,
+    H,
+}
+
+pub const REGISTER_NAMES: [RegisterName; 10] = [RegisterName::Mar, RegisterName::Mdr, RegisterName::Pc, RegisterName::Mbr,
+    RegisterName::Sp, RegisterName::Lv, RegisterName::Cpp, RegisterName::Tos, RegisterName::Opc, RegisterName::H];
+
+impl RegisterName {
+    fn from(name: &str) -> Result<RegisterName, String> {
+        match name.to_uppercase().as_str() {
+            "MAR" => Ok(RegisterName::Mar),
+            "MDR" => Ok(RegisterName::Mdr),
+            "PC" => Ok(RegisterName::Pc),
+            "MBR" => Ok(RegisterName::Mbr),
+            "SP" => Ok(RegisterName::Sp),
+            "LV" => Ok(RegisterName::Lv),
+            "CPP" => Ok(RegisterName::Cpp),
+            "TOS" => Ok(RegisterName::Tos),
+            "OPC" => Ok(RegisterName::Opc),
+            "H" => Ok(RegisterName::H),
+            _ => Err(format!("Unknown register: {}", name)),
+        }
+    }
+}
+
+/// Group of wires of the datapath, 32 bits wide unless said otherwise
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Line {
+    /// Latches of the register
+    Register(RegisterName),
+    BBus,
+    /// Outputs of the B bus decoder, one per source, 9 bits
+    BBusEnable,
+    CBus,
+    /// Outputs of ALU slices, before the shifter
+    Alu,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Signal {
+    Line(Line, usize),
+    ControlStore { address: usize, bit: usize },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HardwareFault {
+    StuckAt { signal: Signal, value: bool },
+    /// Single event upset, the bit of the memory word is flipped before the cycle
+    MemoryUpset { address: usize, bit: usize, cycle: usize },
+}
+
+fn number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(digits) => usize::from_str_radix(digits, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Number is expected: {}", text))
+}
+
+fn bit(text: &str, width: usize) -> Result<usize, String> {
+    let bit = number(text)?;
+    if bit >= width {
+        return Err(format!("Bit {} is out of range, the width is {}", bit, width));
+    }
+    Ok(bit)
+}
+
+fn memory_address(text: &str) -> Result<usize, String> {
+    let address = number(text)?;
+    if address >= MEMORY_SIZE {
+        return Err(format!("Address {} is out of the memory", address));
+    }
+    Ok(address)
+}
+
+/**
+ * Parses faults, one per line. `#` starts a comment.
+ *
+ * stuck-at-0 register PC 3
+ * stuck-at-1 b-bus 7
+ * stuck-at-1 b-enable 7
+ * stuck-at-1 c-bus 7
+ * stuck-at-0 alu 31
+ * stuck-at-1 control-store 0x0F 20
+ * flip memory 120 5 at 40
+ *
+ * Control store bits depend on the MIR layout, so they are checked when the fault is injected.
+ */
+pub fn parse_faults(text: &str) -> Result<Vec<HardwareFault>, String> {
+    let mut faults = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fault = parse_fault(line).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        faults.push(fault);
+    }
+    Ok(faults)
+}
+
+fn parse_fault(line: &str) -> Result<HardwareFault, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [kind, "register", name, index] => stuck_at(kind, Signal::Line(Line::Register(RegisterName::from(name)?), bit(index, 32)?)),
+        [kind, "b-bus", index] => stuck_at(kind, Signal::Line(Line::BBus, bit(index, 32)?)),
+        [kind, "b-enable", index] => stuck_at(kind, Signal::Line(Line::BBusEnable, bit(index, 9)?)),
+        [kind, "c-bus", index] => stuck_at(kind, Signal::Line(Line::CBus, bit(index, 32)?)),
+        [kind, "alu", index] => stuck_at(kind, Signal::Line(Line::Alu, bit(index, 32)?)),
+        [kind, "control-store", address, index] => stuck_at(kind, Signal::ControlStore { address: number(address)?, bit: number(index)? }),
+        ["flip", "memory", address, index, "at", cycle] => Ok(HardwareFault::MemoryUpset { address: memory_address(address)?, bit: bit(index, 32)?, cycle: number(cycle)? }),
+        _ => Err(format!("Unexpected fault: {}", line)),
+    }
+}
+
+fn stuck_at(kind: &str, signal: Signal) -> Result<HardwareFault, String> {
+    match kind {
+        "stuck-at-0" => Ok(HardwareFault::StuckAt { signal, value: false }),
+        "stuck-at-1" => Ok(HardwareFault::StuckAt { signal, value: true }),
+        _ => Err(format!("Unexpected fault kind: {}", kind)),
+    }
+}
+
+pub fn load_faults(path: &Path) -> Result<Vec<HardwareFault>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    parse_faults(&text)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The program got the same result
+    Masked,
+    Changed,
+    /// The program didn't finish in the cycle limit
+    Hung,
+    /// The processor halted on a fault or an electrical rule violation
+    Trapped(String),
+}
+
+/// Runs until the stop instruction, returns false if the cycle limit is exceeded or the processor is halted
+fn run(mic1: &mut Mic1, stop_instruction: i32, max_cycles: usize) -> bool {
+    while mic1.cycles < max_cycles && !mic1.halted() {
+        if mic1.mpc_address() == Main1 as usize && mic1.main_memory.get(encode(&mic1.pc.get())) == Some(stop_instruction as i64) {
+            return true;
+        }
+        mic1.execute_command();
+    }
+    false
+}
+
+/// Result of the program without faults, the program must finish in the cycle limit
+pub fn golden_run(state: &ArchState, control_memory: &ControlMemory, stop_instruction: i32, max_cycles: usize) -> ArchState {
+    let mut mic1 = Mic1::from_arch_state(state, control_memory.clone());
+    if !run(&mut mic1, stop_instruction, max_cycles) {
+        panic!("Program without faults doesn't finish in {} cycles", max_cycles);
+    }
+    mic1.arch_state()
+}
+
+pub fn run_with_faults(state: &ArchState, control_memory: &ControlMemory, faults: &[HardwareFault], golden: &ArchState, stop_instruction: i32, max_cycles: usize) -> Outcome {
+    let mut mic1 = Mic1::from_arch_state(state, control_memory.clone());
+    for fault in faults {
+        mic1.inject(*fault);
+    }
+    let finished = run(&mut mic1, stop_instruction, max_cycles);
+    if let Some(fault) = mic1.fault {
+        return Outcome::Trapped(fault.to_string());
+    }
+    if let Some(violation) = &mic1.rule_error {
+        return Outcome::Trapped(violation.to_string());
+    }
+    match finished {
+        true if mic1.arch_state() == *golden => Outcome::Masked,
+        true => Outcome::Changed,
+        false => Outcome::Hung,
+    }
+}
+
+/**
+ * All stuck-at-0 and stuck-at-1 faults of every datapath line and of the control store words in use,
+ *   and upsets of every bit of the memory words in use (not zero in the state) before the first cycle.
+ */
+pub fn single_bit_faults(control_memory: &ControlMemory, state: &ArchState) -> Vec<HardwareFault> {
+    let mut lines = vec![Line::BBus, Line::CBus, Line::Alu];
+    lines.extend(REGISTER_NAMES.iter().map(|x| Line::Register(*x)));
+
+    let mut signals = Vec::new();
+    for line in lines {
+        for bit in 0..32 {
+            signals.push(Signal::Line(line, bit));
+        }
+    }
+    for bit in 0..9 {
+        signals.push(Signal::Line(Line::BBusEnable, bit));
+    }
+    let layout = control_memory.layout();
+    for address in 0..layout.control_store_size() {
+        if control_memory.read_address(address).iter().any(|x| *x) {
+            for bit in 0..layout.width() {
+                signals.push(Signal::ControlStore { address, bit });
+            }
+        }
+    }
+
+    let mut faults = Vec::new();
+    for signal in signals {
+        faults.push(HardwareFault::StuckAt { signal, value: false });
+        faults.push(HardwareFault::StuckAt { signal, value: true });
+    }
+    for (address, word) in state.memory.iter().enumerate() {
+        if *word != 0 {
+            faults.extend((0..32).map(|bit| HardwareFault::MemoryUpset { address, bit, cycle: 1 }));
+        }
+    }
+    faults
+}
+
+pub struct CampaignReport {
+    pub runs: Vec<(HardwareFault, Outcome)>,
+}
+
+impl CampaignReport {
+    pub fn count<F: Fn(&Outcome) -> bool>(&self, predicate: F) -> usize {
+        self.runs.iter().filter(|(_, outcome)| predicate(outcome)).count()
+    }
+}
+
+impl fmt::Display for CampaignReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Faults: {}", self.runs.len())?;
+        writeln!(f, "Masked: {}", self.count(|x| *x == Outcome::Masked))?;
+        writeln!(f, "Changed: {}", self.count(|x| *x == Outcome::Changed))?;
+        writeln!(f, "Hung: {}", self.count(|x| *x == Outcome::Hung))?;
+        write!(f, "Trapped: {}", self.count(|x| matches!(x, Outcome::Trapped(_))))
+    }
+}
+
+/// Runs the program once for every fault
+pub fn campaign(state: &ArchState, control_memory: &ControlMemory, faults: &[HardwareFault], stop_instruction: i32, max_cycles: usize) -> CampaignReport {
+    let golden = golden_run(state, control_memory, stop_instruction, max_cycles);
+    let runs = faults.iter()
+        .map(|fault| (*fault, run_with_faults(state, control_memory, &[*fault], &golden, stop_instruction, max_cycles)))
+        .collect();
+    CampaignReport { runs }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_interpreter, make_control_memory};
+    use crate::parser::parse;
+
+    use super::*;
+
+    fn program_state() -> ArchState {
+        create_interpreter(&parse("BIPUSH 0x05\nIADD\nDUP\nIADD\n0xFF"), vec![1, 2], [0; 10]).arch_state()
+    }
+
+    fn outcome(faults: &str) -> Outcome {
+        let state = program_state();
+        let control_memory = make_control_memory();
+        let golden = golden_run(&state, &control_memory, 0xFF, 1000);
+        run_with_faults(&state, &control_memory, &parse_faults(faults).unwrap(), &golden, 0xFF, 1000)
+    }
+
+    #[test]
+    fn parse_config() {
+        let text = "# Faults\nstuck-at-0 register PC 3\nstuck-at-1 b-bus 7 # comment\n\nstuck-at-0 c-bus 0x1F\nstuck-at-1 alu 0\nstuck-at-1 control-store 0x0F 20\nflip memory 120 5 at 40\n";
+        let expected = vec![
+            HardwareFault::StuckAt { signal: Signal::Line(Line::Register(RegisterName::Pc), 3), value: false },
+            HardwareFault::StuckAt { signal: Signal::Line(Line::BBus, 7), value: true },
+            HardwareFault::StuckAt { signal: Signal::Line(Line::CBus, 31), value: false },
+            HardwareFault::StuckAt { signal: Signal::Line(Line::Alu, 0), value: true },
+            HardwareFault::StuckAt { signal: Signal::ControlStore { address: 15, bit: 20 }, value: true },
+            HardwareFault::MemoryUpset { address: 120, bit: 5, cycle: 40 },
+        ];
+        assert_eq!(Ok(expected), parse_faults(text));
+    }
+
+    #[test]
+    fn malformed_config() {
+        assert_eq!(Err("Line 2: Bit 32 is out of range, the width is 32".to_string()), parse_faults("stuck-at-0 alu 31\nstuck-at-0 alu 32"));
+        assert_eq!(Err("Line 1: Bit 9 is out of range, the width is 9".to_string()), parse_faults("stuck-at-1 b-enable 9"));
+        assert_eq!(Err("Line 1: Unknown register: XY".to_string()), parse_faults("stuck-at-1 register XY 0"));
+        assert_eq!(Err("Line 1: Address 512 is out of the memory".to_string()), parse_faults("flip memory 512 0 at 3"));
+        assert_eq!(Err("Line 1: Unexpected fault kind: stuck-at-2".to_string()), parse_faults("stuck-at-2 c-bus 0"));
+        assert_eq!(Err("Line 1: Number is expected: x".to_string()), parse_faults("stuck-at-0 c-bus x"));
+        assert_eq!(Err("Line 1: Unexpected fault: stuck-at-0 d-bus 0".to_string()), parse_faults("stuck-at-0 d-bus 0"));
+    }
+
+    #[test]
+    fn no_faults() {
+        assert_eq!(Outcome::Masked, outcome(""));
+    }
+
+    #[test]
+    fn stuck_tos() {
+        assert_eq!(Outcome::Changed, outcome("stuck-at-1 register TOS 30"));
+    }
+
+    #[test]
+    fn stuck_alu() {
+        // PC = 100 can't get past 101 because PC + 1 never sets bit 1, so the stop instruction is not reached
+        assert_eq!(Outcome::Hung, outcome("stuck-at-0 alu 1"));
+    }
+
+    #[test]
+    fn unused_bit_is_masked() {
+        // OPC is not used by these instructions
+        assert_eq!(Outcome::Masked, outcome("stuck-at-1 register OPC 4"));
+    }
+
+    #[test]
+    fn skipped_instructions() {
+        // Without JMPC Main1 doesn't jump to the instruction, so every opcode is skipped like NOP
+        assert_eq!(Outcome::Changed, outcome("stuck-at-0 control-store 1 9"));
+    }
+
+    #[test]
+    fn stack_pointer_out_of_memory() {
+        assert!(matches!(outcome("stuck-at-1 register SP 20"), Outcome::Trapped(_)));
+    }
+
+    #[test]
+    fn memory_upset() {
+        // Second value on the stack is flipped before IADD reads it
+        assert_eq!(Outcome::Changed, outcome(&format!("flip memory {} 3 at 1", crate::STACK_START + 1)));
+        // Flipped after the program is finished
+        assert_eq!(Outcome::Masked, outcome(&format!("flip memory {} 3 at 500", crate::STACK_START)));
+    }
+
+    #[test]
+    fn campaign_over_register() {
+        let faults: Vec<HardwareFault> = single_bit_faults(&make_control_memory(), &program_state()).into_iter()
+            .filter(|x| matches!(x, HardwareFault::StuckAt { signal: Signal::Line(Line::Register(RegisterName::Tos), _), .. }))
+            .collect();
+        assert_eq!(64, faults.len());
+
+        let report = campaign(&program_state(), &make_control_memory(), &faults, 0xFF, 1000);
+        assert_eq!(64, report.runs.len());
+        // Result (2 * (2 + 5)) has zeros and ones, so some faults of both kinds change it
+        assert_eq!("Faults: 64\nMasked: 29\nChanged: 35\nHung: 0\nTrapped: 0", report.to_string());
+    }
+
+    #[test]
+    fn campaign_over_enables_and_memory() {
+        let state = program_state();
+        let faults: Vec<HardwareFault> = single_bit_faults(&make_control_memory(), &state).into_iter()
+            .filter(|x| matches!(x, HardwareFault::StuckAt { signal: Signal::Line(Line::BBusEnable, _), .. } | HardwareFault::MemoryUpset { .. }))
+            .collect();
+        // Every word in use is flipped: the stack of two values and six bytes of the program
+        assert_eq!(18 + 8 * 32, faults.len());
+
+        let report = campaign(&state, &make_control_memory(), &faults, 0xFF, 1000);
+        assert_eq!("Faults: 274\nMasked: 6\nChanged: 227\nHung: 1\nTrapped: 40", report.to_string());
+    }
+}
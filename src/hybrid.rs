@@ -0,0 +1,185 @@
+use crate::arch_state::ArchState;
+use crate::interpreter::Ijvm;
+use crate::memory::ControlMemory;
+use crate::microasm::MicroAsm::Main1;
+use crate::processor::Mic1;
+use crate::traps::Fault;
+
+/// Both engines keep the whole memory inline, so they are boxed to keep switching cheap
+pub enum Engine {
+    Fast(Box<Ijvm>),
+    Microcode(Box<Mic1>),
+}
+
+/**
+ * Runs the program either with the instruction level interpreter or with the gate level `Mic1`.
+ *
+ * The engines can be switched at any time. The state is transferred via `ArchState`,
+ *   so switching from the microcode first finishes the current instruction.
+ */
+pub struct Hybrid {
+    engine: Engine,
+    control_memory: ControlMemory,
+
+    /// Amount of microinstructions executed (or estimated for the fast engine) since the start
+    pub cycles: usize,
+}
+
+impl Hybrid {
+    pub fn new(state: &ArchState, control_memory: ControlMemory) -> Hybrid {
+        Hybrid { engine: Engine::Fast(Box::new(Ijvm::from_arch_state(state))), control_memory, cycles: 0 }
+    }
+
+    pub fn engine(&self) -> &Engine { &self.engine }
+
+    pub fn arch_state(&mut self) -> ArchState {
+        self.finish_instruction();
+        match &self.engine {
+            Engine::Fast(ijvm) => ijvm.arch_state(),
+            Engine::Microcode(mic1) => mic1.arch_state(),
+        }
+    }
+
+    /// Fault of the current engine, the engines aren't switched after it
+    pub fn fault(&self) -> Option<Fault> {
+        match &self.engine {
+            Engine::Fast(ijvm) => ijvm.fault,
+            Engine::Microcode(mic1) => mic1.fault,
+        }
+    }
+
+    /// Runs the fast engine until PC reaches the breakpoint, the cycle limit is exceeded or there is a fault
+    pub fn run_fast(&mut self, breakpoint: Option<i32>, max_cycles: usize) {
+        self.switch_to_fast();
+        if let Engine::Fast(ijvm) = &mut self.engine {
+            while self.cycles < max_cycles && breakpoint != Some(ijvm.pc) && !ijvm.halted() {
+                let before = ijvm.cycles;
+                ijvm.step();
+                self.cycles += ijvm.cycles - before;
+            }
+        }
+    }
+
+    pub fn step_microinstruction(&mut self) {
+        self.switch_to_microcode();
+        if let Engine::Microcode(mic1) = &mut self.engine {
+            mic1.execute_command();
+            self.cycles += 1;
+        }
+    }
+
+    pub fn switch_to_microcode(&mut self) {
+        if self.fault().is_some() {
+            return;
+        }
+        if let Engine::Fast(ijvm) = &self.engine {
+            let mic1 = Mic1::from_arch_state(&ijvm.arch_state(), self.control_memory.clone());
+            self.engine = Engine::Microcode(Box::new(mic1));
+        }
+    }
+
+    pub fn switch_to_fast(&mut self) {
+        self.finish_instruction();
+        if self.fault().is_some() {
+            return;
+        }
+        if let Engine::Microcode(mic1) = &self.engine {
+            let ijvm = Ijvm::from_arch_state(&mic1.arch_state());
+            self.engine = Engine::Fast(Box::new(ijvm));
+        }
+    }
+
+    /// Runs the microcode up to Main1, so the architectural state is consistent
+    fn finish_instruction(&mut self) {
+        if let Engine::Microcode(mic1) = &mut self.engine {
+            while mic1.mpc_address() != Main1 as usize && !mic1.halted() {
+                mic1.execute_command();
+                self.cycles += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_interpreter, make_control_memory, PROGRAM_START};
+    use crate::main_memory::fast_encode;
+    use crate::parser::parse;
+    use crate::traps::FaultKind;
+
+    use super::*;
+
+    const PROGRAM: &str = r#"
+                BIPUSH 0x02
+                BIPUSH 0x05
+                INVOKEVIRTUAL 0x00 0x02
+                0xFF
+                0x00  0x02
+                0x00  0x01
+                ILOAD 0x01
+                DUP
+                IADD
+                IRETURN
+"#;
+    const CONSTANTS: [i32; 10] = [0xCA, 0x11, PROGRAM_START as i32 + 0x08, 0, 0, 0, 0, 0, 0, 0];
+    const STOP: i32 = PROGRAM_START as i32 + 7;
+
+    fn initial_state() -> ArchState {
+        create_interpreter(&parse(PROGRAM), vec![], CONSTANTS).arch_state()
+    }
+
+    #[test]
+    fn fast_only() {
+        let mut hybrid = Hybrid::new(&initial_state(), make_control_memory());
+        hybrid.run_fast(Some(STOP), usize::MAX);
+
+        let state = hybrid.arch_state();
+        assert_eq!(10, state.tos);
+        assert_eq!(STOP, state.pc);
+    }
+
+    #[test]
+    fn switch_in_method() {
+        let mut fast = Hybrid::new(&initial_state(), make_control_memory());
+        fast.run_fast(Some(STOP), usize::MAX);
+
+        let mut hybrid = Hybrid::new(&initial_state(), make_control_memory());
+        // Stop at ILOAD inside of the method
+        hybrid.run_fast(Some(PROGRAM_START as i32 + 12), usize::MAX);
+        assert_eq!(2, hybrid.arch_state().frames().len());
+
+        for _ in 0..10 {
+            hybrid.step_microinstruction();
+        }
+        match hybrid.engine() {
+            Engine::Microcode(mic1) => assert_eq!(5, fast_encode(&mic1.tos.get())),
+            Engine::Fast(_) => panic!("Microcode engine is expected"),
+        }
+
+        hybrid.run_fast(Some(STOP), usize::MAX);
+
+        assert_eq!(fast.arch_state(), hybrid.arch_state());
+        assert_eq!(fast.cycles, hybrid.cycles);
+    }
+
+    #[test]
+    fn fast_engine_stops_at_fault() {
+        let state = create_interpreter(&parse("BIPUSH 0x02\nISTORE 0x80\nBIPUSH 0x05"), vec![], CONSTANTS).arch_state();
+        let mut hybrid = Hybrid::new(&state, make_control_memory());
+        hybrid.run_fast(None, usize::MAX);
+
+        let fault = hybrid.fault().unwrap();
+        assert_eq!((FaultKind::Write, PROGRAM_START as i64 + 2), (fault.kind, fault.pc));
+        assert_eq!(PROGRAM_START as i32 + 4, hybrid.arch_state().pc);
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let mut hybrid = Hybrid::new(&initial_state(), make_control_memory());
+        // Both BIPUSH commands
+        hybrid.run_fast(None, 8);
+
+        assert_eq!(PROGRAM_START as i32 + 4, hybrid.arch_state().pc);
+        assert_eq!(8, hybrid.cycles);
+    }
+}
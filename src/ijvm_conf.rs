@@ -0,0 +1,170 @@
+use std::fs;
+use std::path::Path;
+
+use strum::IntoEnumIterator;
+
+use crate::asm::{Instruction, Isa, OperandKind};
+use crate::make_control_memory;
+use crate::memory::ControlMemory;
+use crate::microasm::{assemble_microroutine, MicroAsm};
+
+/// Instruction set from an ijvm.conf file with the microroutines of the new instructions
+pub struct IsaConfig {
+    pub isa: Isa,
+    /// Opcode and the text of the microroutine
+    pub microroutines: Vec<(i32, String)>,
+}
+
+impl IsaConfig {
+    /// Built-in microprogram with the microroutines placed at their opcodes
+    pub fn control_memory(&self) -> ControlMemory {
+        let mut control_memory = make_control_memory();
+        let layout = control_memory.layout().clone();
+        let mut used = vec![false; layout.control_store_size()];
+        for command in MicroAsm::iter() {
+            used[command as usize] = true;
+        }
+        for (opcode, text) in &self.microroutines {
+            for (address, word) in assemble_microroutine(text, &layout, *opcode as usize, &mut used) {
+                control_memory.write_data(&word, address);
+            }
+        }
+        control_memory
+    }
+}
+
+/**
+ * Lines `OPCODE MNEMONIC OPERAND...` like in ijvm.conf of Tanenbaum's toolchain, `#` and `//` start comments.
+ *
+ * Operand types are `byte`, `const`, `varnum`, `label`, `offset` (method) and `index` (constant).
+ *   The last word `@file` refers to the microroutine of the instruction, see `assemble_microroutine`.
+ *   Instructions without microroutines should be the built-in ones.
+ */
+pub fn parse_conf(text: &str, read_microroutine: impl Fn(&str) -> String) -> IsaConfig {
+    let standard = Isa::standard();
+    let mut instructions: Vec<Instruction> = Vec::new();
+    let mut microroutines = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap().split("//").next().unwrap();
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        if words.len() < 2 {
+            panic!("Unexpected instruction definition: {}", line.trim());
+        }
+        let microroutine = words.last().and_then(|x| x.strip_prefix('@'));
+        if microroutine.is_some() {
+            words.pop();
+        }
+
+        let opcode = crate::parser::number(words[0]) as i32;
+        let name = words[1].to_string();
+        if !(0..=0xFF).contains(&opcode) {
+            panic!("Opcode {} of {} is out of range", words[0], name);
+        }
+        if let Some(other) = instructions.iter().find(|x| x.opcode == opcode || x.name == name) {
+            panic!("{} at 0x{:02X} conflicts with {}", name, opcode, other.name);
+        }
+        let operands = words[2..].iter().map(|x| operand_kind(x)).collect();
+
+        match microroutine {
+            Some(file) => microroutines.push((opcode, read_microroutine(file))),
+            None => if standard.by_opcode(opcode).map(|x| &x.name) != Some(&name) {
+                panic!("{} at 0x{:02X} has no microroutine", name, opcode);
+            }
+        }
+        instructions.push(Instruction { name, opcode, operands });
+    }
+    IsaConfig { isa: Isa { instructions }, microroutines }
+}
+
+/// Microroutine files are relative to the directory of the configuration
+pub fn load_conf(path: &Path) -> IsaConfig {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    let directory = path.parent().unwrap_or(Path::new(""));
+    parse_conf(&text, |file| {
+        let path = directory.join(file);
+        fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e))
+    })
+}
+
+fn operand_kind(name: &str) -> OperandKind {
+    match name {
+        "byte" => OperandKind::SignedByte,
+        "const" => OperandKind::Const,
+        "varnum" => OperandKind::Varnum,
+        "label" => OperandKind::LabelOffset,
+        "offset" => OperandKind::MethodIndex,
+        "index" => OperandKind::ConstantIndex,
+        _ => panic!("Unknown operand type {}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::create_processor_with_control_memory;
+    use crate::main_memory::encode;
+    use crate::parser::parse_with_isa;
+
+    use super::*;
+
+    /// Pops two values and pushes their product, the counter in OPC is the value on the top
+    const IMUL: &str = "\
+        imul1: r_sp alu_b_dec w_sp w_mar read; goto imul2
+        imul2: r_tos alu_b w_opc; goto imul3
+        imul3: r_mdr alu_b w_h; goto imul4
+        imul4: f1 w_tos; goto imul5   # TOS = 0
+        imul5: r_opc alu_b jamz; goto imul6 or imul8
+        imul6: r_tos alu_sum w_tos; goto imul7
+        imul7: r_opc alu_b_dec w_opc; goto imul5
+        imul8: r_tos alu_b w_mdr write; goto Main1";
+
+    fn with_imul() -> IsaConfig {
+        let conf = format!("{}0x68 IMUL @imul.mal\n", include_str!("../ijvm.conf"));
+        parse_conf(&conf, |file| {
+            assert_eq!("imul.mal", file);
+            IMUL.to_string()
+        })
+    }
+
+    #[test]
+    fn built_in_table() {
+        let config = parse_conf(include_str!("../ijvm.conf"), |_| panic!("No microroutines"));
+        assert_eq!(Isa::standard(), config.isa);
+        assert!(config.microroutines.is_empty());
+    }
+
+    #[test]
+    fn new_instruction() {
+        let config = with_imul();
+        let commands = parse_with_isa("BIPUSH 6\nBIPUSH 7\nIMUL\nBIPUSH 1\nIADD", &config.isa);
+        assert_eq!(0x68, commands[4]);
+
+        let mut mic1 = create_processor_with_control_memory(&commands, vec![0], [0; 10], config.control_memory());
+        mic1.run(commands.len() + 1, crate::PROGRAM_START);
+        assert_eq!(43, encode(&mic1.tos.get()));
+        assert_eq!(11, encode(&mic1.sp.get()));
+    }
+
+    #[test]
+    fn files() {
+        let directory = env::temp_dir().join(format!("ijvm_conf_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("imul.mal"), IMUL).unwrap();
+        fs::write(directory.join("ijvm.conf"), "0x68 IMUL @imul.mal // multiplication\n0x59 DUP").unwrap();
+        let config = load_conf(&directory.join("ijvm.conf"));
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(vec!["IMUL", "DUP"], config.isa.instructions.iter().map(|x| x.name.as_str()).collect::<Vec<_>>());
+        assert_eq!(vec![(0x68, IMUL.to_string())], config.microroutines);
+    }
+
+    #[test]
+    #[should_panic(expected = "IMUL at 0x68 has no microroutine")]
+    fn missing_microroutine() {
+        parse_conf("0x68 IMUL", |_| String::new());
+    }
+}
@@ -0,0 +1,482 @@
+use crate::arch_state::ArchState;
+use crate::asm::IjvmCommand::*;
+use crate::main_memory::MainMemory;
+use crate::traps::{Fault, FaultKind};
+
+/**
+ * Instruction level IJVM interpreter.
+ *
+ * It works with the same memory layout and calling convention as `Mic1`, but executes
+ *   the whole instruction at once instead of running the microprogram. The semantics
+ *   follow the microcode from `microasm.rs`, including its quirks
+ *   (e.g. `INVOKEVIRTUAL` leaves LV in TOS),
+ *   so it can be used as a reference for the microcode.
+ *
+ * Unlike `Mic1`, `pc` always points to the opcode of the next instruction.
+ * An access outside of the memory cells is a fault like in `Mic1`: the rest of the instruction
+ *   doesn't write the memory and nothing is executed after it.
+ */
+pub struct Ijvm {
+    pub pc: i32,
+    pub sp: i32,
+    pub lv: i32,
+    pub cpp: i32,
+    pub tos: i32,
+
+    /// Only kept to be passed on to `Mic1`, the interpreter doesn't check the stack
+    pub stack_base: i32,
+    pub stack_limit: i32,
+
+    pub main_memory: MainMemory,
+
+    /// Amount of microinstructions `Mic1` would spend on the executed instructions
+    pub cycles: usize,
+    /// The first invalid access, `mpc` is 0 and `pc` is the address of the instruction
+    pub fault: Option<Fault>,
+}
+
+impl Ijvm {
+    /// The stack starts at the frame of the main program and may grow up to the program
+    pub fn init(main_memory: MainMemory, tos: i32, pc: i32, sp: i32, lv: i32) -> Ijvm {
+        Ijvm { pc, sp, lv, cpp: 0, tos, stack_base: lv, stack_limit: pc - 1, main_memory, cycles: 0, fault: None }
+    }
+
+    pub fn from_arch_state(state: &ArchState) -> Ijvm {
+        Ijvm {
+            pc: state.pc,
+            sp: state.sp,
+            lv: state.lv,
+            cpp: state.cpp,
+            tos: state.tos,
+            stack_base: state.stack_base,
+            stack_limit: state.stack_limit,
+            main_memory: MainMemory::from_cells(&state.memory),
+            cycles: 0,
+            fault: None,
+        }
+    }
+
+    pub fn arch_state(&self) -> ArchState {
+        ArchState {
+            pc: self.pc,
+            sp: self.sp,
+            lv: self.lv,
+            cpp: self.cpp,
+            tos: self.tos,
+            stack_base: self.stack_base,
+            stack_limit: self.stack_limit,
+            memory: self.main_memory.cells().to_vec(),
+        }
+    }
+
+    pub fn run_until_stop(&mut self, stop_instruction: i32) {
+        while self.opcode() != stop_instruction && !self.halted() {
+            self.step();
+        }
+    }
+
+    pub fn run_n_instructions(&mut self, amount: usize) {
+        for _ in 0..amount {
+            self.step();
+        }
+    }
+
+    pub fn halted(&self) -> bool { self.fault.is_some() }
+
+    pub fn opcode(&self) -> i32 { self.peek(self.pc) }
+
+    /// Amount of microinstructions (including Main1) the microprogram spends on the next instruction, checked against `Mic1` in the tests
+    pub fn instruction_cycles(&self) -> usize {
+        match self.opcode() {
+            x if x == NOP as i32 => 2,
+            x if x == DUP as i32 => 3,
+            x if x == BIPUSH as i32 || x == POP as i32 => 4,
+            x if x == IADD as i32 || x == ISUB as i32 || x == IAND as i32 || x == IOR as i32 => 4,
+            x if x == ISHL as i32 || x == ISHR as i32 || x == IUSHR as i32 => 4,
+            x if x == ILOAD as i32 => 6,
+            x if x == SWAP as i32 || x == IINC as i32 || x == GOTO as i32 => 7,
+            x if x == ISTORE as i32 || x == LDC_W as i32 => 8,
+            x if x == IRETURN as i32 => 9,
+            x if x == IFEQ as i32 => if self.tos == 0 { 12 } else { 9 },
+            x if x == IFLT as i32 => if self.tos < 0 { 12 } else { 9 },
+            x if x == IF_ICMPEQ as i32 => if self.tos == self.peek(self.sp - 1) { 13 } else { 10 },
+            x if x == INVOKEVIRTUAL as i32 => 23,
+            x if x == WIDE as i32 => match self.peek(self.pc + 1) {
+                x if x == ILOAD as i32 => 10,
+                x if x == ISTORE as i32 => 12,
+                x if x == IINC as i32 => 14,
+                _ => 5,
+            },
+            // Empty control store word, it jumps to nop1
+            _ => 3,
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.halted() {
+            return;
+        }
+        let pc = self.pc;
+        self.execute();
+        if let Some(fault) = self.fault.as_mut() {
+            fault.pc = pc as i64;
+        }
+    }
+
+    fn execute(&mut self) {
+        self.cycles += self.instruction_cycles();
+        let opcode = self.byte(0);
+        match opcode {
+            x if x == BIPUSH as i32 => {
+                let value = signed_byte(self.byte(1));
+                self.push(value);
+                self.pc += 2;
+            }
+            x if x == DUP as i32 => {
+                self.push(self.tos);
+                self.pc += 1;
+            }
+            x if x == POP as i32 => {
+                self.pop();
+                self.pc += 1;
+            }
+            x if x == SWAP as i32 => {
+                let second = self.read(self.sp - 1);
+                self.write(self.sp, second);
+                self.write(self.sp - 1, self.tos);
+                self.tos = second;
+                self.pc += 1;
+            }
+            x if x == GOTO as i32 => self.pc += self.branch_offset(),
+            x if x == IADD as i32 => self.binary(|a, b| a.wrapping_add(b)),
+            x if x == ISUB as i32 => self.binary(|a, b| a.wrapping_sub(b)),
+            x if x == IAND as i32 => self.binary(|a, b| a & b),
+            x if x == IOR as i32 => self.binary(|a, b| a | b),
+            // The shift amount is on the top of the stack, only the low five bits of it are used
+            x if x == ISHL as i32 => self.binary(|a, b| a.wrapping_shl(b as u32)),
+            x if x == ISHR as i32 => self.binary(|a, b| a.wrapping_shr(b as u32)),
+            x if x == IUSHR as i32 => self.binary(|a, b| (a as u32).wrapping_shr(b as u32) as i32),
+            x if x == IFEQ as i32 => {
+                let value = self.pop();
+                self.branch(value == 0, 3);
+            }
+            x if x == IFLT as i32 => {
+                let value = self.pop();
+                self.branch(value < 0, 3);
+            }
+            x if x == IF_ICMPEQ as i32 => {
+                let first = self.pop();
+                let second = self.pop();
+                self.branch(first == second, 3);
+            }
+            x if x == IINC as i32 => {
+                let address = self.lv + signed_byte(self.byte(1));
+                let value = self.read(address).wrapping_add(signed_byte(self.byte(2)));
+                self.write(address, value);
+                self.pc += 3;
+            }
+            x if x == ILOAD as i32 => {
+                let index = signed_byte(self.byte(1));
+                self.load(index, 2);
+            }
+            x if x == ISTORE as i32 => {
+                let index = signed_byte(self.byte(1));
+                self.store(index, 2);
+            }
+            x if x == WIDE as i32 => {
+                let index = self.wide_varnum(2);
+                match self.byte(1) {
+                    x if x == ILOAD as i32 => self.load(index, 4),
+                    x if x == ISTORE as i32 => self.store(index, 4),
+                    x if x == IINC as i32 => {
+                        let address = self.lv + index;
+                        let value = self.read(address).wrapping_add(self.signed_word(4));
+                        self.write(address, value);
+                        self.pc += 6;
+                    }
+                    // There is no microcode for other wide commands, so the next byte is skipped
+                    _ => self.pc += 2,
+                }
+            }
+            x if x == LDC_W as i32 => {
+                let index = self.wide_index(1);
+                let value = self.read(self.cpp + index);
+                self.push(value);
+                self.pc += 3;
+            }
+            x if x == INVOKEVIRTUAL as i32 => self.invoke_virtual(),
+            x if x == IRETURN as i32 => self.ireturn(),
+            // NOP and commands without microcode
+            _ => self.pc += 1,
+        }
+    }
+
+    fn invoke_virtual(&mut self) {
+        let return_address = self.pc + 3;
+        let index = self.wide_index(1);
+        let method = self.read(self.cpp + index);
+        let amount_of_parameters = (signed_byte(self.read(method)) << 8) | signed_byte(self.read(method + 1));
+        let amount_of_variables = (signed_byte(self.read(method + 2)) << 8) | signed_byte(self.read(method + 3));
+
+        let new_lv = self.sp - amount_of_parameters + 1;
+        let link = self.sp + amount_of_variables + 1;
+        self.write(new_lv, link);
+        self.sp = link;
+        self.write(self.sp, return_address);
+        self.sp += 1;
+        self.write(self.sp, self.lv);
+
+        self.lv = new_lv;
+        self.tos = new_lv;
+        self.pc = method + 4;
+    }
+
+    fn ireturn(&mut self) {
+        let link = self.read(self.lv);
+        self.sp = self.lv;
+        self.pc = self.read(link);
+        self.lv = self.read(link + 1);
+        self.write(self.sp, self.tos);
+    }
+
+    fn binary(&mut self, operation: fn(i32, i32) -> i32) {
+        let second = self.pop();
+        self.tos = operation(self.tos, second);
+        self.write(self.sp, self.tos);
+        self.pc += 1;
+    }
+
+    fn branch(&mut self, condition: bool, command_size: i32) {
+        if condition {
+            self.pc += self.branch_offset();
+        } else {
+            self.pc += command_size;
+        }
+    }
+
+    fn load(&mut self, index: i32, command_size: i32) {
+        let value = self.read(self.lv + index);
+        self.push(value);
+        self.pc += command_size;
+    }
+
+    fn store(&mut self, index: i32, command_size: i32) {
+        self.write(self.lv + index, self.tos);
+        self.pop();
+        self.pc += command_size;
+    }
+
+    fn push(&mut self, value: i32) {
+        self.sp += 1;
+        self.tos = value;
+        self.write(self.sp, value);
+    }
+
+    /// Removes the top of the stack and returns it. TOS gets the next value.
+    fn pop(&mut self) -> i32 {
+        let value = self.tos;
+        self.sp -= 1;
+        self.tos = self.read(self.sp);
+        value
+    }
+
+    fn branch_offset(&mut self) -> i32 { self.signed_word(1) }
+
+    /// Signed 16-bit number, the microcode builds it from MBRU (first byte) and MBR (second byte).
+    fn signed_word(&mut self, offset: i32) -> i32 { (signed_byte(self.byte(offset)) << 8) | self.byte(offset + 1) }
+
+    /// Index after WIDE, the microcode builds it from MBR for both bytes.
+    fn wide_varnum(&mut self, offset: i32) -> i32 { (self.byte(offset) << 8) | self.byte(offset + 1) }
+
+    /// The microcode builds the index from MBRU for both bytes.
+    fn wide_index(&mut self, offset: i32) -> i32 { (signed_byte(self.byte(offset)) << 8) | signed_byte(self.byte(offset + 1)) }
+
+    fn byte(&mut self, offset: i32) -> i32 { self.access(self.pc + offset, FaultKind::Fetch) }
+
+    fn read(&mut self, address: i32) -> i32 { self.access(address, FaultKind::Read) }
+
+    fn write(&mut self, address: i32, value: i32) {
+        if self.main_memory.get(address as i64).is_none() {
+            self.fail(FaultKind::Write, address);
+        } else if !self.halted() {
+            self.main_memory.write_data(value, address as usize);
+        }
+    }
+
+    /// Value of the cell, an invalid address is a fault and reads as 0
+    fn access(&mut self, address: i32, kind: FaultKind) -> i32 {
+        match self.main_memory.get(address as i64) {
+            Some(value) => value as i32,
+            None => {
+                self.fail(kind, address);
+                0
+            }
+        }
+    }
+
+    /// Records the first fault, `step` sets the address of the instruction
+    fn fail(&mut self, kind: FaultKind, address: i32) {
+        if self.fault.is_none() {
+            self.fault = Some(Fault { kind, address: address as i64, cycle: self.cycles, mpc: 0, pc: self.pc as i64 });
+        }
+    }
+
+    /// Cell without the check for the estimations, invalid addresses read as 0
+    fn peek(&self, address: i32) -> i32 { self.main_memory.get(address as i64).unwrap_or(0) as i32 }
+}
+
+/// Value of the memory cell as it gets to the B bus through MBRU: the lowest byte with sign extension.
+fn signed_byte(value: i32) -> i32 { value as i8 as i32 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_interpreter, make_control_memory, PROGRAM_START, STACK_START};
+    use crate::parser::parse;
+    use crate::processor::Mic1;
+
+    use super::*;
+
+    #[test]
+    fn add() {
+        let commands = parse("IADD");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2], [0; 10]);
+        ijvm.run_n_instructions(1);
+
+        assert_eq!(3, ijvm.tos);
+        assert_stack(vec![3], &ijvm);
+    }
+
+    #[test]
+    fn swap() {
+        let commands = parse("SWAP");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2, 3, 4, 5], [0; 10]);
+        ijvm.run_n_instructions(1);
+
+        assert_stack(vec![1, 2, 3, 5, 4], &ijvm);
+    }
+
+    #[test]
+    fn wide_istore() {
+        let commands = parse("WIDE\nISTORE 0x0 0x1");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2, 3, 4, 5], [0; 10]);
+        ijvm.run_n_instructions(1);
+
+        assert_stack(vec![1, 5, 3, 4], &ijvm);
+    }
+
+    #[test]
+    fn wide_iinc() {
+        let commands = parse("WIDE\nIINC 1 -300");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2, 3], [0; 10]);
+        ijvm.run_n_instructions(1);
+
+        assert_stack(vec![1, -298, 3], &ijvm);
+        assert_eq!(PROGRAM_START as i32 + 6, ijvm.pc);
+    }
+
+    #[test]
+    fn goto() {
+        let commands = parse("GOTO 0x00 0x03\nIINC 0x00 0x01\nIADD");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2, 3, 4], [0; 10]);
+        ijvm.run_n_instructions(3);
+
+        assert_stack(vec![2, 2, 7], &ijvm);
+    }
+
+    #[test]
+    fn if_icmpeq() {
+        let commands = parse("IF_ICMPEQ 0x00 0x05\nIINC 0x00 0x01\nIADD");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2, 3, 4, 4], [0; 10]);
+        ijvm.run_n_instructions(3);
+
+        assert_stack(vec![1, 5], &ijvm);
+    }
+
+    #[test]
+    fn invokevirtual() {
+        let program = r#"
+                BIPUSH 0x02
+                INVOKEVIRTUAL 0x00 0x02
+                IADD
+                0x00  0x01
+                0x00  0x00
+                BIPUSH 0x1c
+                IRETURN
+"#;
+        let commands = parse(program);
+        let constants = [0xCA, 0x11, crate::PROGRAM_START as i32 + 0x06, 4, 4, 5, 6, 0, 0, 0];
+        let mut ijvm = create_interpreter(&commands, vec![1, 2, 3, 4], constants);
+
+        ijvm.run_n_instructions(3);
+        assert_stack(vec![1, 2, 3, 4, 15, 105, 10, 0x1c], &ijvm);
+        assert_eq!(STACK_START + 4, ijvm.lv);
+
+        ijvm.run_n_instructions(1);
+        assert_stack(vec![1, 2, 3, 4, 0x1c], &ijvm);
+        assert_eq!(STACK_START, ijvm.lv);
+        assert_eq!(crate::PROGRAM_START as i32 + 5, ijvm.pc);
+    }
+
+    #[test]
+    fn cycles_match_microcode() {
+        let constants = [0xCA, 0x11, PROGRAM_START as i32 + 0x03, 0, 0, 0, 0, 0, 0, 0];
+        let method = "INVOKEVIRTUAL 0x00 0x02\n0x00 0x01\n0x00 0x00\nIRETURN";
+        // Program and the amount of instructions before the checked one
+        let programs = [
+            ("NOP", 0), ("BIPUSH 0x05", 0), ("LDC_W 0x00 0x01", 0), ("ILOAD 0x01", 0), ("ISTORE 0x01", 0), ("POP", 0),
+            ("DUP", 0), ("SWAP", 0), ("IADD", 0), ("ISUB", 0), ("IAND", 0), ("IOR", 0), ("ISHL", 0), ("ISHR", 0),
+            ("IUSHR", 0), ("IINC 0x01 0x02", 0), ("GOTO 0x00 0x03", 0),
+            // Both ways of the branches
+            ("IFEQ 0x00 0x03", 0), ("BIPUSH 0x00\nIFEQ 0x00 0x03", 1), ("IFLT 0x00 0x03", 0), ("BIPUSH 0x01\nIFLT 0x00 0x03", 1),
+            ("IF_ICMPEQ 0x00 0x03", 0), ("DUP\nIF_ICMPEQ 0x00 0x03", 1),
+            (method, 0), (method, 1),
+            ("WIDE\nILOAD 0x00 0x01", 0), ("WIDE\nISTORE 0x00 0x01", 0), ("WIDE\nIINC 1 2", 0), ("WIDE\nNOP", 0),
+            // Opcode without a microroutine
+            ("0xFE", 0),
+        ];
+        for (program, before) in programs {
+            let mut ijvm = create_interpreter(&parse(program), vec![1, 2, -3], constants);
+            let mut mic1 = Mic1::from_arch_state(&ijvm.arch_state(), make_control_memory());
+            for _ in 0..before {
+                ijvm.step();
+                mic1.step_instruction();
+            }
+            let cycles = mic1.cycles;
+            let expected = ijvm.instruction_cycles();
+            mic1.step_instruction();
+
+            assert_eq!(None, mic1.fault, "{}", program);
+            assert_eq!(expected, mic1.cycles - cycles, "{}", program);
+        }
+    }
+
+    #[test]
+    fn fault() {
+        // The index is sign-extended, so LV - 128 is read
+        let commands = parse("BIPUSH 0x05\nILOAD 0x80\nBIPUSH 0x07");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2], [0; 10]);
+        ijvm.run_n_instructions(3);
+
+        let fault = ijvm.fault.unwrap();
+        assert_eq!((FaultKind::Read, STACK_START as i64 - 128, PROGRAM_START as i64 + 2), (fault.kind, fault.address, fault.pc));
+        assert!(ijvm.halted());
+        // ILOAD still pushes, but the value isn't written
+        assert_stack(vec![1, 2, 5, 0], &ijvm);
+        assert_eq!(PROGRAM_START as i32 + 4, ijvm.pc);
+    }
+
+    #[test]
+    fn fetch_fault() {
+        let commands = parse("GOTO 0x7F 0x00");
+        let mut ijvm = create_interpreter(&commands, vec![1, 2], [0; 10]);
+        ijvm.run_n_instructions(2);
+
+        let fault = ijvm.fault.unwrap();
+        assert_eq!((FaultKind::Fetch, PROGRAM_START as i64 + 0x7F00), (fault.kind, fault.address));
+    }
+
+    fn assert_stack(expected_stack: Vec<i32>, ijvm: &Ijvm) {
+        let real_stack: Vec<i32> = (STACK_START..=ijvm.sp).map(|x| ijvm.main_memory.read_number(x as usize)).collect();
+        assert_eq!(expected_stack, real_stack);
+    }
+}
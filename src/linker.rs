@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+use crate::asm::OperandKind::{ConstantIndex, LabelOffset, MethodIndex};
+use crate::compiler::{ProcessorInfo, SourceLine};
+
+/// Name defined in an object
+#[derive(Clone, Debug, PartialEq)]
+pub enum Symbol {
+    /// Named constant of the constant pool
+    Constant(i32),
+    /// Method with the header at the offset in the code of the object
+    Method(usize),
+    /// Label at the offset, visible in its method or in the main program if the scope is `None`
+    Label { scope: Option<String>, offset: usize },
+}
+
+/// Operand of two bytes at the offset in the code that is known only after linking
+#[derive(Clone, Debug, PartialEq)]
+pub enum Relocation {
+    /// Index of the constant in the merged constant pool
+    ConstantIndex { offset: usize, name: String },
+    /// Index of the address of the method in the merged constant pool
+    MethodIndex { offset: usize, name: String },
+    /// Offset of the label from the opcode of the branch
+    LabelOffset { offset: usize, opcode: usize, scope: Option<String>, name: String },
+}
+
+/// Relocatable code of one source file, offsets are relative to the beginning of its code
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Object {
+    /// Name of the source file, see `SourceLine`
+    pub file: String,
+    pub code: Vec<i32>,
+    /// Source line of every byte of the code, starting from 1
+    pub lines: Vec<usize>,
+    /// Definitions in the source order
+    pub symbols: Vec<(String, Symbol)>,
+    pub relocations: Vec<Relocation>,
+    pub has_main: bool,
+}
+
+impl Object {
+    pub fn constant(&self, name: &str) -> Option<i32> {
+        self.symbols.iter().find_map(|(x, symbol)| match symbol {
+            Symbol::Constant(value) if x == name => Some(*value),
+            _ => None,
+        })
+    }
+
+    fn label(&self, scope: &Option<String>, name: &str) -> Option<usize> {
+        self.symbols.iter().rev().find_map(|(x, symbol)| match symbol {
+            Symbol::Label { scope: label_scope, offset } if x == name && label_scope == scope => Some(*offset),
+            _ => None,
+        })
+    }
+}
+
+/**
+ * Places the code of the objects one after another from `program_start`, the object with the main program goes first.
+ *
+ * The constant pools are merged: named constants of all the objects, then addresses of all the methods.
+ *   A constant that is defined in several objects with the same value takes one entry.
+ */
+pub fn link(objects: &[Object], program_start: u32) -> ProcessorInfo {
+    let mains = objects.iter().filter(|x| x.has_main).count();
+    if mains != 1 {
+        panic!("Expected one main program, found {}", mains);
+    }
+    let order: Vec<&Object> = objects.iter().filter(|x| x.has_main).chain(objects.iter().filter(|x| !x.has_main)).collect();
+    let mut bases = Vec::new();
+    let mut length = 0;
+    for object in &order {
+        bases.push(length);
+        length += object.code.len();
+    }
+
+    let mut constants = Vec::new();
+    let mut constant_indexes = HashMap::new();
+    for object in &order {
+        for (name, symbol) in &object.symbols {
+            if let Symbol::Constant(value) = symbol {
+                match constant_indexes.get(name.as_str()) {
+                    None => {
+                        constant_indexes.insert(name.as_str(), constants.len());
+                        constants.push(*value);
+                    }
+                    Some(&index) if constants[index] != *value => panic!("Constant {} is defined as {} and as {}", name, constants[index], value),
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+    let mut method_indexes = HashMap::new();
+    for (object, base) in order.iter().zip(&bases) {
+        for (name, symbol) in &object.symbols {
+            if let Symbol::Method(offset) = symbol {
+                if method_indexes.insert(name.as_str(), constants.len()).is_some() {
+                    panic!("Method {} is defined twice", name);
+                }
+                constants.push((program_start as usize + base + offset) as i32);
+            }
+        }
+    }
+
+    let mut main_program = Vec::with_capacity(length);
+    let mut lines = Vec::with_capacity(length);
+    for (object, base) in order.iter().zip(&bases) {
+        main_program.extend(&object.code);
+        lines.extend(object.lines.iter().map(|x| SourceLine { file: object.file.clone(), line: *x }));
+        for relocation in &object.relocations {
+            let (offset, bytes) = match relocation {
+                Relocation::ConstantIndex { offset, name } => {
+                    let index = *constant_indexes.get(name.as_str()).unwrap_or_else(|| panic!("Unknown constant: {}", name));
+                    (offset, ConstantIndex.encode(index as i64, false))
+                }
+                Relocation::MethodIndex { offset, name } => {
+                    let index = *method_indexes.get(name.as_str()).unwrap_or_else(|| panic!("Unknown method: {}", name));
+                    (offset, MethodIndex.encode(index as i64, false))
+                }
+                Relocation::LabelOffset { offset, opcode, scope, name } => {
+                    let label = object.label(scope, name).unwrap_or_else(|| panic!("Unknown label: {}", name));
+                    let bytes = LabelOffset.encode(label as i64 - *opcode as i64, false);
+                    (offset, Some(bytes.unwrap_or_else(|| panic!("Label {} is too far from the branch", name))))
+                }
+            };
+            let bytes = bytes.unwrap_or_else(|| panic!("Constant pool is too large"));
+            main_program[base + offset..base + offset + 2].copy_from_slice(&bytes);
+        }
+    }
+
+    ProcessorInfo { constants, main_program, lines }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::IjvmCommand::*;
+
+    use super::*;
+
+    /// Main program that calls `method` of the library with the constant `ten`
+    fn main_object() -> Object {
+        Object {
+            code: vec![LDC_W as i32, 0x00, 0x00, INVOKEVIRTUAL as i32, 0x00, 0x00, GOTO as i32, 0x00, 0x00],
+            lines: vec![1, 1, 1, 2, 2, 2, 3, 3, 3],
+            symbols: vec![("one".to_string(), Symbol::Constant(1)), ("loop".to_string(), Symbol::Label { scope: None, offset: 3 })],
+            relocations: vec![
+                Relocation::ConstantIndex { offset: 1, name: "ten".to_string() },
+                Relocation::MethodIndex { offset: 4, name: "method".to_string() },
+                Relocation::LabelOffset { offset: 7, opcode: 6, scope: None, name: "loop".to_string() },
+            ],
+            has_main: true,
+            ..Object::default()
+        }
+    }
+
+    fn library() -> Object {
+        Object {
+            code: vec![0x00, 0x01, 0x00, 0x00, BIPUSH as i32, 0x01, IRETURN as i32],
+            lines: vec![1, 1, 1, 1, 2, 2, 3],
+            symbols: vec![
+                ("one".to_string(), Symbol::Constant(1)),
+                ("ten".to_string(), Symbol::Constant(10)),
+                ("method".to_string(), Symbol::Method(0)),
+            ],
+            relocations: vec![],
+            has_main: false,
+            file: "library.jas".to_string(),
+        }
+    }
+
+    #[test]
+    fn main_goes_first() {
+        let info = link(&[library(), main_object()], 100);
+
+        assert_eq!(vec![1, 10, 109], info.constants);
+        assert_eq!(vec![
+            LDC_W as i32, 0x00, 0x01, INVOKEVIRTUAL as i32, 0x00, 0x02, GOTO as i32, 0xFF, 0xFD,
+            0x00, 0x01, 0x00, 0x00, BIPUSH as i32, 0x01, IRETURN as i32,
+        ], info.main_program);
+        assert_eq!(vec![1, 1, 1, 2, 2, 2, 3, 3, 3, 1, 1, 1, 1, 2, 2, 3], info.lines.iter().map(|x| x.line).collect::<Vec<_>>());
+        assert_eq!(SourceLine { file: String::new(), line: 3 }, info.lines[8]);
+        assert_eq!(SourceLine { file: "library.jas".to_string(), line: 1 }, info.lines[9]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Constant one is defined as 1 and as 2")]
+    fn conflicting_constants() {
+        let mut library = library();
+        library.symbols[0].1 = Symbol::Constant(2);
+        link(&[main_object(), library], 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown method: method")]
+    fn unknown_method() {
+        let mut library = library();
+        library.symbols.pop();
+        link(&[main_object(), library], 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Method method is defined twice")]
+    fn method_defined_twice() {
+        link(&[main_object(), library(), library()], 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected one main program, found 0")]
+    fn no_main() {
+        link(&[library()], 100);
+    }
+}
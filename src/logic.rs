@@ -0,0 +1,28 @@
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+
+/**
+ * Signal that can be passed through the logic gates.
+ *
+ * `bool` is a single wire. `u64` is the same wire in 64 independent machines (one per bit),
+ *   so every gate is evaluated for all of them at once.
+ */
+pub trait Logic: Copy + BitAnd<Output=Self> + BitOr<Output=Self> + BitXor<Output=Self> + Not<Output=Self> {
+    fn constant(value: bool) -> Self;
+}
+
+impl Logic for bool {
+    fn constant(value: bool) -> bool { value }
+}
+
+impl Logic for u64 {
+    fn constant(value: bool) -> u64 { if value { !0 } else { 0 } }
+}
+
+/// OR of all signals built as a balanced tree of two-input gates
+pub fn or_tree<T: Logic>(signals: &[T]) -> T {
+    match signals.len() {
+        0 => T::constant(false),
+        1 => signals[0],
+        len => or_tree(&signals[..len / 2]) | or_tree(&signals[len / 2..]),
+    }
+}
@@ -7,14 +7,16 @@ extern crate quickcheck_macros;
 use strum::IntoEnumIterator;
 use tree_sitter::{Language, Parser};
 
-use crate::bus::Bus32;
 use crate::main_memory::{fast_decode, fast_encode, MainMemory};
-use crate::memory::{Memory512x36, Register32, Register9};
+use crate::memory::{address_bits, ControlMemory, MpcRegister};
 use crate::microasm::MicroAsm;
 use crate::microasm::MicroAsm::Main1;
+use crate::mir_layout::MirLayout;
 use crate::parser::parse;
-use crate::processor::Mic1;
+use crate::processor::{register, Mic1};
 use crate::compiler::{ProcessorInfo, compile};
+use crate::interpreter::Ijvm;
+use crate::memory_image::Registers;
 
 mod compiler;
 mod parser;
@@ -28,6 +30,25 @@ mod bus;
 mod memory;
 mod decoders;
 mod alu;
+mod interpreter;
+mod differential;
+mod arch_state;
+mod hybrid;
+mod logic;
+mod batch;
+mod timing;
+mod verilog;
+mod fault_injection;
+mod mir_layout;
+mod electrical_rules;
+mod devices;
+mod cache;
+mod traps;
+mod memory_image;
+mod sanitizer;
+mod ijvm_conf;
+mod expression;
+mod linker;
 
 extern "C" { fn tree_sitter_jas() -> Language; }
 
@@ -58,12 +79,31 @@ fn main() {
     let mut mic1 = create_processor_from_info(&info);
 
     mic1.run_until_stop(0xFF);
+    for violation in mic1.violations.iter() {
+        eprintln!("Warning: {}", violation);
+    }
+    if let Some(fault) = mic1.fault {
+        eprintln!("Fault: {}", fault);
+    }
+    if let Some(violation) = &mic1.rule_error {
+        eprintln!("Error: {}", violation);
+    }
 
     let tos_res = fast_encode(&mic1.tos.read(true));
     print!("Result: {:?}", tos_res)
 }
 
 fn create_processor_from_info(info: &ProcessorInfo) -> Mic1 {
+    create_processor(&info.main_program, Vec::new(), constant_pool(info))
+}
+
+/// Interpreter with the same memory layout and constant pool as `create_processor_from_info`
+fn create_interpreter_from_info(info: &ProcessorInfo) -> Ijvm {
+    create_interpreter(&info.main_program, Vec::new(), constant_pool(info))
+}
+
+/// The first ten constants of the program, the rest of the pool is zero
+fn constant_pool(info: &ProcessorInfo) -> [i32; 10] {
     let mut constants = [0; 10];
     for x in 0..10 {
         if x >= info.constants.len() {
@@ -71,10 +111,50 @@ fn create_processor_from_info(info: &ProcessorInfo) -> Mic1 {
         }
         constants[x] = *info.constants.get(x).unwrap();
     }
-    create_processor(&info.main_program, Vec::new(), constants)
+    constants
 }
 
 fn create_processor(commands: &Vec<i32>, initial_stack: Vec<i32>, constants: [i32; 10]) -> Mic1 {
+    create_processor_with_control_memory(commands, initial_stack, constants, make_control_memory())
+}
+
+fn create_processor_with_control_memory(commands: &Vec<i32>, initial_stack: Vec<i32>, constants: [i32; 10], control_memory: ControlMemory) -> Mic1 {
+    create_processor_of_width(commands, initial_stack, constants, control_memory)
+}
+
+/// Processor with `W`-bit words, the microprogram is the same for any width
+fn create_processor_of_width<const W: usize>(commands: &Vec<i32>, initial_stack: Vec<i32>, constants: [i32; 10], control_memory: ControlMemory) -> Mic1<W> {
+    let (memory, stack_pointer, top_of_stack) = create_memory(commands, initial_stack, constants);
+    processor_with_memory(memory, stack_pointer as i64, top_of_stack as i64, control_memory)
+}
+
+/// Processor for the memory image, the registers are restored from the header of the image
+fn create_processor_from_image(memory: MainMemory, registers: &Registers) -> Mic1 {
+    let mut mic1 = processor_with_memory(memory, registers.sp, registers.tos, make_control_memory());
+    registers.restore(&mut mic1);
+    mic1
+}
+
+fn processor_with_memory<const W: usize>(memory: MainMemory<W>, stack_pointer: i64, top_of_stack: i64, control_memory: ControlMemory) -> Mic1<W> {
+    let tos = register(top_of_stack);
+    let pc = register(99);
+    let lv = register(STACK_START as i64);
+    let sp = register(stack_pointer);
+
+    let address_width = control_memory.layout().address_width();
+    let mut mpc = MpcRegister::new(address_width);
+    mpc.update(&address_bits(Main1 as usize, address_width), true);
+
+    Mic1::init(memory, control_memory, tos, pc, sp, lv, mpc)
+}
+
+fn create_interpreter(commands: &Vec<i32>, initial_stack: Vec<i32>, constants: [i32; 10]) -> Ijvm {
+    let (memory, stack_pointer, top_of_stack) = create_memory(commands, initial_stack, constants);
+    Ijvm::init(memory, top_of_stack, PROGRAM_START as i32, stack_pointer, STACK_START)
+}
+
+/// Returns the memory with constants, stack and program; stack pointer and top of the stack
+fn create_memory<const W: usize>(commands: &Vec<i32>, initial_stack: Vec<i32>, constants: [i32; 10]) -> (MainMemory<W>, i32, i32) {
     let mut memory = MainMemory::initialize();
 
     // Constants
@@ -99,36 +179,18 @@ fn create_processor(commands: &Vec<i32>, initial_stack: Vec<i32>, constants: [i3
         p_counter += 1;
     }
 
-    let control_memory = make_control_memory();
-
-    let mut tos = Register32::new();
-    tos.update_from_bus(&Bus32::from(fast_decode(top_of_stack)), true);
-
-    let mut pc = Register32::new();
-    pc.update_from_bus(&Bus32::from(fast_decode(99)), true);
-
-    let mut lv = Register32::new();
-    lv.update_from_bus(&Bus32::from(fast_decode(STACK_START)), true);
-
-    let mut sp = Register32::new();
-    sp.update_from_bus(&Bus32::from(fast_decode(stack_pointer)), true);
-
-    let mut mpc = Register9::new();
-    let mut mpc_data = [false; 9];
-    let decoded = fast_decode(Main1 as i32);
-    for x in 0..9 {
-        mpc_data[x] = decoded[x];
-    }
-    mpc.update(mpc_data, true);
+    (memory, stack_pointer, top_of_stack)
+}
 
-    Mic1::init(memory, control_memory, tos, pc, sp, lv, mpc)
+fn make_control_memory() -> ControlMemory {
+    make_control_memory_with_layout(MirLayout::standard())
 }
 
-fn make_control_memory() -> Memory512x36 {
-    let mut control_memory = Memory512x36::new();
+fn make_control_memory_with_layout(layout: MirLayout) -> ControlMemory {
+    let mut control_memory = ControlMemory::new(layout.clone());
 
     for command in MicroAsm::iter() {
-        control_memory.write_data(command.command(), command as usize)
+        control_memory.write_data(&command.command(&layout), command as usize)
     }
     return control_memory;
 }
@@ -137,6 +199,7 @@ fn make_control_memory() -> Memory512x36 {
 mod tests {
     use super::*;
     use crate::compiler::compile;
+    use crate::main_memory::encode;
 
     #[test]
     fn add() {
@@ -158,6 +221,37 @@ mod tests {
         assert_eq!(30, tos_res)
     }
 
+    #[test]
+    fn ishl() {
+        let commands = parse("ISHL");
+        let mut mic1 = create_processor(&commands, vec![-3, 4], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        let tos_res = fast_encode(&mic1.tos.read(true));
+        assert_eq!(-48, tos_res)
+    }
+
+    #[test]
+    fn ishr() {
+        let commands = parse("ISHR");
+        let mut mic1 = create_processor(&commands, vec![-48, 36], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        // Only the low five bits of the amount are used
+        let tos_res = fast_encode(&mic1.tos.read(true));
+        assert_eq!(-3, tos_res)
+    }
+
+    #[test]
+    fn iushr() {
+        let commands = parse("IUSHR");
+        let mut mic1 = create_processor(&commands, vec![-1, 28], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        let tos_res = fast_encode(&mic1.tos.read(true));
+        assert_eq!(0xF, tos_res)
+    }
+
     #[test]
     fn iload() {
         let commands = parse("ILOAD 0x00");
@@ -308,6 +402,26 @@ mod tests {
         assert_stack(vec![1, 2, 3, 4, 5, 2], &mic1)
     }
 
+    #[test]
+    fn wide_iinc() {
+        let commands = parse("WIDE\nIINC 1 -300\nWIDE\nIINC 0 1000\nDUP");
+        let mut mic1 = create_processor(&commands, vec![1, 2, 3], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        assert_stack(vec![1001, -298, 3, 3], &mic1)
+    }
+
+    #[test]
+    fn wide_index_above_byte() {
+        // 0x180 would be negative if the low byte was sign-extended, the local is after the program
+        let commands = parse("BIPUSH 0x05\nWIDE ISTORE 0x180\nWIDE IINC 0x180 2\nWIDE ILOAD 0x180");
+        let mut mic1 = create_processor(&commands, vec![1], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        assert_stack(vec![1, 7], &mic1);
+        assert_eq!(7, mic1.main_memory.read_number(STACK_START as usize + 0x180));
+    }
+
     #[test]
     fn wide_istore() {
         let commands = parse("WIDE\nISTORE 0x0 0x0");
@@ -374,6 +488,25 @@ mod tests {
         assert_stack(vec![1, 2, 8, 4], &mic1)
     }
 
+    #[test]
+    fn iinc_negative() {
+        let commands = parse("IINC 0x01 -1
+IINC 0x00 0x80");
+        let mut mic1 = create_processor(&commands, vec![1, 10], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        assert_stack(vec![-127, 9], &mic1)
+    }
+
+    #[test]
+    fn bipush_negative() {
+        let commands = parse("BIPUSH -3\nBIPUSH 0x80\nBIPUSH 0x7F");
+        let mut mic1 = create_processor(&commands, vec![0], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        assert_stack(vec![0, -3, -128, 127], &mic1)
+    }
+
     #[test]
     fn goto() {
         let commands = parse("GOTO 0x00 0x03\nIINC 0x00 0x01\nIADD");
@@ -392,6 +525,30 @@ mod tests {
         assert_stack(vec![1, 2, 7], &mic1)
     }
 
+    #[test]
+    fn far_goto() {
+        // The low byte of 0x80 is negative as a signed byte, 300 doesn't fit into a byte
+        for offset in [0x80, 300] {
+            let filler = offset - 3;
+            let program = format!("GOTO {}\n{}{}BIPUSH 0x07", offset, "IINC 0x00 0x01\n".repeat(filler / 3), "NOP\n".repeat(filler % 3));
+            let commands = parse(&program);
+            let mut mic1 = create_processor(&commands, vec![1], [0; 10]);
+            mic1.run(commands.len() + 1, PROGRAM_START);
+
+            assert_stack(vec![1, 7], &mic1)
+        }
+    }
+
+    #[test]
+    fn far_goto_back() {
+        // -240 is 0xFF10, the low byte is positive as a signed byte
+        let commands = parse(&format!("GOTO 243\nBIPUSH 0x07\nGOTO 241\n{}NOP\nGOTO -240\nNOP", "IINC 0x00 0x01\n".repeat(78)));
+        let mut mic1 = create_processor(&commands, vec![1], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+
+        assert_stack(vec![1, 7], &mic1)
+    }
+
     #[test]
     fn iflt() {
         let commands = parse("IFLT 0x00 0x05\nIINC 0x00 0x01\nIADD");
@@ -525,6 +682,60 @@ mod tests {
         assert_eq!(tree.root_node().to_sexp(), "(source_file (main_program))");
     }
 
+    #[test]
+    fn program_from_asm_with_wide() {
+        let variables: Vec<String> = (0..300).map(|x| format!("v{}", x)).collect();
+        let source = format!(".main\n.var\n{}\n.end-var\nBIPUSH 0x05\nISTORE v299\nIINC v299 -1000\nILOAD v299\n.end-main\n", variables.join("\n"));
+        let compiled = compile(&source, PROGRAM_START as u32, Some(0xFF));
+        let mut mic1 = create_processor_from_info(&compiled);
+        mic1.run_until_stop(0xFF);
+
+        assert_eq!(-995, fast_encode(&mic1.tos.get()));
+    }
+
+    #[test]
+    fn program_from_asm_with_named_increment() {
+        let source = r#"
+           .constant
+               start 'A'
+               step -2
+           .end-constant
+           .main
+           .var
+               i
+           .end-var
+               BIPUSH start
+               ISTORE i
+               IINC i step
+               ILOAD i
+           .end-main
+        "#;
+        let compiled = compile(source, PROGRAM_START as u32, Some(0xFF));
+        let mut mic1 = create_processor_from_info(&compiled);
+        mic1.run_until_stop(0xFF);
+
+        assert_eq!('A' as i32 - 2, fast_encode(&mic1.tos.get()));
+    }
+
+    #[test]
+    fn program_from_asm_with_negative_bipush() {
+        let source = r#"
+           .constant
+               minus -5
+           .end-constant
+           .main
+               BIPUSH minus
+               BIPUSH -3
+               IADD
+           .end-main
+        "#;
+        let compiled = compile(source, PROGRAM_START as u32, Some(0xFF));
+        let mut mic1 = create_processor_from_info(&compiled);
+        mic1.run_until_stop(0xFF);
+
+        assert_eq!(-8, fast_encode(&mic1.tos.get()));
+    }
+
     #[test]
     fn program_from_asm() {
         let source = r#"
@@ -541,6 +752,22 @@ mod tests {
         assert_stack(vec![3], &mic1);
     }
 
+    #[test]
+    fn interpreter_from_info() {
+        let method = PROGRAM_START as i32 + 8;
+        let program = parse("BIPUSH 0x00\nBIPUSH 0x03\nINVOKEVIRTUAL 0x00 0x01\n0xFF\n0x00 0x02\n0x00 0x00\nILOAD 0x01\nDUP\nIADD\nIRETURN");
+        let info = ProcessorInfo { constants: vec![0, method], main_program: program, lines: Vec::new() };
+
+        let mut mic1 = create_processor_from_info(&info);
+        mic1.run_until_stop(0xFF);
+        let mut ijvm = create_interpreter_from_info(&info);
+        ijvm.run_until_stop(0xFF);
+
+        let (slow, fast) = (mic1.arch_state(), ijvm.arch_state());
+        assert_eq!(6, fast.tos);
+        assert_eq!((slow.tos, slow.sp, slow.lv, slow.memory), (fast.tos, fast.sp, fast.lv, fast.memory));
+    }
+
     #[test]
     fn program_from_asm_with_function() {
         let source = r#"
@@ -583,12 +810,53 @@ mod tests {
         assert_stack(vec![12, 3, 107, 10, 3], &mic1);
     }
 
+    /// Runs the program until the 0xFF instruction, returns the stack and the amount of cycles
+    fn run_of_width<const W: usize>(program: &str, initial_stack: Vec<i32>, constants: [i32; 10]) -> (Vec<i64>, usize) {
+        let commands = parse(program);
+        let mut mic1 = create_processor_of_width::<W>(&commands, initial_stack, constants, make_control_memory());
+        while mic1.main_memory.read_number(encode(&mic1.pc.get()) as usize) != 0xFF {
+            mic1.step_instruction();
+        }
+
+        let stack_ptr = encode(&mic1.sp.get()) as usize;
+        let stack = (STACK_START as usize..=stack_ptr).map(|x| mic1.main_memory.read_word(x)).collect();
+        (stack, mic1.cycles)
+    }
+
+    #[test]
+    fn same_microprogram_for_any_width() {
+        let count_down = "DUP\nIFEQ 0x00 0x09\nBIPUSH 0x01\nISUB\nGOTO 0xFF 0xF9\n0xFF";
+        let expected = run_of_width::<32>(count_down, vec![7, 5], [0; 10]);
+        assert_eq!(vec![7, 0], expected.0);
+        assert_eq!(expected, run_of_width::<16>(count_down, vec![7, 5], [0; 10]));
+        assert_eq!(expected, run_of_width::<64>(count_down, vec![7, 5], [0; 10]));
+
+        let call = "BIPUSH 0x03\nINVOKEVIRTUAL 0x00 0x02\nIADD\n0xFF\n0x00 0x01\n0x00 0x00\nBIPUSH 0x03\nIRETURN";
+        let constants = [0xCA, 0x11, PROGRAM_START as i32 + 0x07, 4, 4, 5, 6, 0, 0, 0];
+        let expected = run_of_width::<32>(call, vec![1, 2, 3, 4], constants);
+        assert_eq!(expected, run_of_width::<16>(call, vec![1, 2, 3, 4], constants));
+        assert_eq!(expected, run_of_width::<64>(call, vec![1, 2, 3, 4], constants));
+    }
+
+    #[test]
+    fn word_width() {
+        let (stack, _) = run_of_width::<16>("IADD\n0xFF", vec![0x7FFF, 1], [0; 10]);
+        assert_eq!(vec![-0x8000], stack);
+        let (stack, _) = run_of_width::<32>("IADD\n0xFF", vec![0x7FFF, 1], [0; 10]);
+        assert_eq!(vec![0x8000], stack);
+
+        let (stack, _) = run_of_width::<32>("IADD\n0xFF", vec![i32::MAX, i32::MAX], [0; 10]);
+        assert_eq!(vec![-2], stack);
+        let (stack, _) = run_of_width::<64>("IADD\n0xFF", vec![i32::MAX, i32::MAX], [0; 10]);
+        assert_eq!(vec![0xFFFF_FFFE], stack);
+    }
+
     fn assert_stack(expected_stack: Vec<i32>, mic1: &Mic1) {
         let stack_ptr = fast_encode(&mic1.sp.get());
         let stack_size = stack_ptr - STACK_START + 1;
         let mut real_stack = Vec::new();
         for x in 0..stack_size {
-            real_stack.push(fast_encode(&mic1.main_memory.read(fast_decode(x + STACK_START))));
+            real_stack.push(mic1.main_memory.read_number((x + STACK_START) as usize));
         }
 
         assert_eq!(expected_stack, real_stack);
@@ -1,71 +1,304 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cache::Cache;
 use crate::main_memory::ReadState::{NoRead, ReadInitialized, ReadInProgress};
+use crate::traps::FaultKind;
+
+pub const MEMORY_SIZE: usize = 512;
+
+/**
+ * Peripheral that is mapped to a range of addresses of the main memory.
+ *
+ * Offsets are counted from the start of the range. Values are sign-extended words of the datapath width.
+ */
+pub trait Device {
+    /// Called when the read is finished and the value goes to MDR or MBR
+    fn read(&mut self, offset: usize) -> i64;
 
-pub struct MainMemory {
-    cells: [i32; 512],
+    /// Called in the cycle of the write strobe
+    fn write(&mut self, offset: usize, value: i64);
 
-    pub first_reading: Vec<(i32, ReadState)>,
-    pub second_reading: Vec<(i32, ReadState)>,
+    /// Called in the cycle of the read strobe, e.g. to start a conversion
+    fn read_requested(&mut self, _offset: usize) {}
+
+    /// Cycles between the read strobe and the value, the memory cells have 1
+    fn read_latency(&self) -> usize { 1 }
+
+    /// Called once per cycle, before the reads are checked
+    fn tick(&mut self) {}
 }
 
-impl MainMemory {
-    pub fn initialize() -> MainMemory { MainMemory { cells: [0; 512], first_reading: Vec::new(), second_reading: Vec::new() } }
+/// Keeps a handle to the device after it's attached to the memory
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&mut self, offset: usize) -> i64 { self.borrow_mut().read(offset) }
+    fn write(&mut self, offset: usize, value: i64) { self.borrow_mut().write(offset, value) }
+    fn read_requested(&mut self, offset: usize) { self.borrow_mut().read_requested(offset) }
+    fn read_latency(&self) -> usize { self.borrow().read_latency() }
+    fn tick(&mut self) { self.borrow_mut().tick() }
+}
 
-    pub fn write_data(&mut self, data: i32, addr: usize) {
-        self.cells[addr] = data
-    }
+struct Region {
+    start: usize,
+    size: usize,
+    device: Box<dyn Device>,
+}
+
+/**
+ * Memory of `W`-bit words, every cell keeps the sign-extended value of the word.
+ *
+ * Reads and writes through the datapath to an attached region go to its device, the cells under it are not used.
+ *   `read_number`, `read_word`, `write_data` and `write_word` always access the cells.
+ * Caches of the data port (MAR) and of the fetch port (PC) are optional, devices are not cached.
+ *   A miss stalls the processor, `BatchMic1` doesn't support stalls.
+ * Accesses through the datapath to other addresses are dropped, the first of them is kept until `take_fault`.
+ * Latencies are counted in whole cycles between the request and the result. With the default read latency
+ *   the value requested in cycle `k` is in MDR or MBR in cycle `k + 2`, the microprogram relies on that.
+ */
+pub struct MainMemory<const W: usize = 32> {
+    cells: [i64; MEMORY_SIZE],
+    regions: Vec<Region>,
+
+    pub read_latency: usize,
+    /// Written values get into the cells after this amount of cycles
+    pub write_latency: usize,
+    /// Address, value and cycles left
+    writing: Vec<(usize, i64, usize)>,
 
-    pub fn write(&mut self, data: [bool; 32], addr: [bool; 32], enabled: bool) {
-        if !enabled { return; }
-        self.cells[fast_encode(&addr) as usize] = fast_encode(&data)
+    pub data_cache: Option<Cache>,
+    pub fetch_cache: Option<Cache>,
+    /// Cycles left before the processor can go on
+    stall: usize,
+
+    pub first_reading: Vec<PendingRead>,
+    pub second_reading: Vec<PendingRead>,
+
+    /// Kind and address of the dropped access
+    fault: Option<(FaultKind, i64)>,
+}
+
+pub struct PendingRead {
+    pub address: i64,
+    pub state: ReadState,
+    /// Cycles since the read was requested
+    pub age: usize,
+}
+
+impl<const W: usize> MainMemory<W> {
+    pub fn initialize() -> MainMemory<W> { MainMemory { cells: [0; MEMORY_SIZE], regions: Vec::new(), read_latency: 1, write_latency: 0, writing: Vec::new(), data_cache: None, fetch_cache: None, stall: 0, first_reading: Vec::new(), second_reading: Vec::new(), fault: None } }
+
+    /// Maps `size` addresses starting at `start` to the device
+    pub fn attach(&mut self, start: usize, size: usize, device: Box<dyn Device>) {
+        if size == 0 {
+            panic!("Region at {} is empty", start);
+        }
+        for region in self.regions.iter() {
+            if start < region.start + region.size && region.start < start + size {
+                panic!("Region {}..{} overlaps with {}..{}", start, start + size, region.start, region.start + region.size);
+            }
+        }
+        self.regions.push(Region { start, size, device });
     }
 
-    pub fn read(&self, addr: [bool; 32]) -> [bool; 32] {
-        let i_addr = fast_encode(&addr);
-        let data = self.cells[i_addr as usize];
-        fast_decode(data)
+    fn region(&self, addr: usize) -> Option<&Region> {
+        self.regions.iter().find(|x| x.start <= addr && addr < x.start + x.size)
     }
 
-    pub fn read_number(&self, addr: usize) -> i32 { self.cells[addr] }
+    /// Device of the address and the offset in its region
+    fn device(&mut self, addr: usize) -> Option<(&mut Box<dyn Device>, usize)> {
+        self.regions.iter_mut()
+            .find(|x| x.start <= addr && addr < x.start + x.size)
+            .map(|x| (&mut x.device, addr - x.start))
+    }
 
-    pub fn request_first_read(&mut self, addr: [bool; 32], enabled: bool) {
-        if !enabled { return; }
-        self.first_reading.push((fast_encode(&addr), ReadInitialized));
+    /// The address is a memory cell or is mapped to a device
+    pub fn valid(&self, addr: i64) -> bool {
+        0 <= addr && (addr < MEMORY_SIZE as i64 || self.region(addr as usize).is_some())
     }
 
-    pub fn request_second_read(&mut self, addr: [bool; 32], enabled: bool) {
-        if !enabled { return; }
-        self.second_reading.push((fast_encode(&addr), ReadInitialized));
+    /// Records the fault if the address is invalid
+    fn check_address(&mut self, addr: i64, kind: FaultKind) -> bool {
+        if self.valid(addr) {
+            return true;
+        }
+        if self.fault.is_none() {
+            self.fault = Some((kind, addr));
+        }
+        false
     }
 
-    pub fn check_first_read(&mut self) -> ([bool; 32], bool) {
-        let mut res = [false; 32];
-        let mut enabled = false;
-        for i in 0..self.first_reading.len() {
-            if self.first_reading[i].1 == ReadInProgress {
-                self.first_reading[i].1 = NoRead;
-                res = self.read(fast_decode(self.first_reading[i].0));
-                enabled = true;
-            } else if self.first_reading[i].1 == ReadInitialized {
-                self.first_reading[i].1 = ReadInProgress;
+    pub fn take_fault(&mut self) -> Option<(FaultKind, i64)> { self.fault.take() }
+
+    /// Advances the devices and the writes by one cycle
+    pub fn tick(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.tick();
+        }
+        for write in self.writing.iter_mut() {
+            write.2 -= 1;
+            if write.2 == 0 {
+                self.cells[write.0] = write.1;
             }
         }
-        self.first_reading.retain(|x| x.1 != NoRead);
-        return (res, enabled);
+        self.writing.retain(|x| x.2 != 0);
+    }
+
+    /**
+     * Ready signal of the memory: all requested values are in MDR and MBR at the beginning of the next cycle
+     *   and all writes are finished by then. Should be checked after the requests of the cycle.
+     */
+    pub fn ready(&self) -> bool {
+        let arrives = |x: &PendingRead| match x.state {
+            ReadInitialized => self.latency_of(x.address) == 0,
+            ReadInProgress(left) => left == 0,
+            NoRead => true,
+        };
+        self.first_reading.iter().all(arrives) && self.second_reading.iter().all(arrives) && self.writing.iter().all(|x| x.2 <= 1)
+    }
+
+    /**
+     * True if the read into MDR (`first`) or MBR is still going on, but it was requested two or more cycles ago.
+     *   The microprogram that uses the register now expects the value that hasn't arrived yet.
+     */
+    pub fn late_read(&self, first: bool) -> bool {
+        let reading = if first { &self.first_reading } else { &self.second_reading };
+        reading.iter().any(|x| x.age >= 2)
+    }
+
+    pub fn stall_cycles(&self) -> usize { self.stall }
+
+    /// Returns true if the processor should wait in this cycle
+    pub fn stalled(&mut self) -> bool {
+        if self.stall == 0 {
+            return false;
+        }
+        self.stall -= 1;
+        true
+    }
+
+    fn access_cache(&mut self, addr: i64, write: bool, fetch: bool) {
+        if self.device(addr as usize).is_some() {
+            return;
+        }
+        let cache = if fetch { &mut self.fetch_cache } else { &mut self.data_cache };
+        if let Some(cache) = cache {
+            self.stall += cache.access(addr as usize, write);
+        }
+    }
+
+    pub fn from_cells(cells: &[i32]) -> MainMemory<W> {
+        let mut memory = MainMemory::initialize();
+        for (addr, data) in cells.iter().enumerate() {
+            memory.write_data(*data, addr);
+        }
+        memory
+    }
+
+    /// Values of the cells, words wider than 32 bits are truncated
+    pub fn cells(&self) -> Vec<i32> { self.cells.iter().map(|x| *x as i32).collect() }
+
+    pub fn write_data(&mut self, data: i32, addr: usize) {
+        self.write_word(data as i64, addr)
+    }
+
+    /// The value is truncated to the word width
+    pub fn write_word(&mut self, data: i64, addr: usize) {
+        self.cells[addr] = encode(&decode::<W>(data))
+    }
+
+    pub fn write(&mut self, data: [bool; W], addr: [bool; W], enabled: bool) {
+        if !enabled || !self.check_address(encode(&addr), FaultKind::Write) { return; }
+        self.access_cache(encode(&addr), true, false);
+        let addr = encode(&addr) as usize;
+        let latency = self.write_latency;
+        match self.device(addr) {
+            Some((device, offset)) => device.write(offset, encode(&data)),
+            None if latency == 0 => self.cells[addr] = encode(&data),
+            None => self.writing.push((addr, encode(&data), latency)),
+        }
     }
 
-    pub fn check_second_read(&mut self) -> ([bool; 32], bool) {
-        let mut res = [false; 32];
+    pub fn read(&mut self, addr: [bool; W]) -> [bool; W] {
+        let i_addr = encode(&addr) as usize;
+        let data = match self.device(i_addr) {
+            Some((device, offset)) => device.read(offset),
+            None => self.cells[i_addr],
+        };
+        decode(data)
+    }
+
+    fn latency_of(&self, addr: i64) -> usize {
+        match self.region(addr as usize) {
+            Some(region) => region.device.read_latency(),
+            None => self.read_latency,
+        }
+    }
+
+    fn start_read(&mut self, addr: i64) {
+        if let Some((device, offset)) = self.device(addr as usize) {
+            device.read_requested(offset);
+        }
+    }
+
+    /// Panics outside of the cells, see `get`
+    pub fn read_number(&self, addr: usize) -> i32 { self.cells[addr] as i32 }
+
+    /// Panics outside of the cells, see `get`
+    pub fn read_word(&self, addr: usize) -> i64 { self.cells[addr] }
+
+    /// Cell of the address, `None` outside of the cells; devices aren't accessed
+    pub fn get(&self, addr: i64) -> Option<i64> {
+        if (0..MEMORY_SIZE as i64).contains(&addr) { Some(self.cells[addr as usize]) } else { None }
+    }
+
+    pub fn request_first_read(&mut self, addr: [bool; W], enabled: bool) {
+        if !enabled || !self.check_address(encode(&addr), FaultKind::Read) { return; }
+        self.access_cache(encode(&addr), false, false);
+        self.start_read(encode(&addr));
+        self.first_reading.push(PendingRead { address: encode(&addr), state: ReadInitialized, age: 0 });
+    }
+
+    pub fn request_second_read(&mut self, addr: [bool; W], enabled: bool) {
+        if !enabled || !self.check_address(encode(&addr), FaultKind::Fetch) { return; }
+        self.access_cache(encode(&addr), false, true);
+        self.start_read(encode(&addr));
+        self.second_reading.push(PendingRead { address: encode(&addr), state: ReadInitialized, age: 0 });
+    }
+
+    pub fn check_first_read(&mut self) -> ([bool; W], bool) {
+        let mut reading = std::mem::take(&mut self.first_reading);
+        let res = self.check_reads(&mut reading);
+        self.first_reading = reading;
+        res
+    }
+
+    pub fn check_second_read(&mut self) -> ([bool; W], bool) {
+        let mut reading = std::mem::take(&mut self.second_reading);
+        let res = self.check_reads(&mut reading);
+        self.second_reading = reading;
+        res
+    }
+
+    /// A read waits for the latency of its address, then the value is returned once
+    fn check_reads(&mut self, reading: &mut Vec<PendingRead>) -> ([bool; W], bool) {
+        let mut res = [false; W];
         let mut enabled = false;
-        for i in 0..self.second_reading.len() {
-            if self.second_reading[i].1 == ReadInProgress {
-                self.second_reading[i].1 = NoRead;
-                res = self.read(fast_decode(self.second_reading[i].0));
-                enabled = true;
-            } else if self.second_reading[i].1 == ReadInitialized {
-                self.second_reading[i].1 = ReadInProgress;
+        for pending in reading.iter_mut() {
+            pending.age += 1;
+            if pending.state == ReadInitialized {
+                pending.state = ReadInProgress(self.latency_of(pending.address));
+            }
+            match pending.state {
+                ReadInProgress(0) => {
+                    pending.state = NoRead;
+                    res = self.read(decode(pending.address));
+                    enabled = true;
+                }
+                ReadInProgress(left) => pending.state = ReadInProgress(left - 1),
+                _ => {}
             }
         }
-        self.second_reading.retain(|x| x.1 != NoRead);
+        reading.retain(|x| x.state != NoRead);
         return (res, enabled);
     }
 }
@@ -88,9 +321,31 @@ pub fn fast_encode(data: &[bool; 32]) -> i32 {
     res
 }
 
+/// Bits of the `W`-bit two's complement value, higher bits of the number are dropped
+pub fn decode<const W: usize>(number: i64) -> [bool; W] {
+    let mut res = [false; W];
+    for i in 0..W.min(64) {
+        res[i] = number >> i & 1 == 1;
+    }
+    res
+}
+
+/// Value of the `W`-bit word, the highest bit is the sign
+pub fn encode<const W: usize>(data: &[bool; W]) -> i64 {
+    let mut res = 0i64;
+    for i in 0..W.min(64) {
+        res |= (data[i] as i64) << i;
+    }
+    if W < 64 && data[W - 1] {
+        res |= -1i64 << W;
+    }
+    res
+}
+
 #[derive(PartialEq)]
 pub enum ReadState {
     ReadInitialized,
-    ReadInProgress,
+    /// Amount of cycles left before the value is returned
+    ReadInProgress(usize),
     NoRead,
 }
@@ -1,5 +1,9 @@
-use crate::bus::{Bus32, Bus36, Bus9};
-use crate::decoders::decoder_9x512;
+use std::rc::Rc;
+
+use crate::bus::{AddressBus, Bus, MirBus};
+use crate::decoders::decoder;
+use crate::logic::{Logic, or_tree};
+use crate::mir_layout::MirLayout;
 
 #[derive(Copy, Clone)]
 pub struct DLatch {
@@ -15,113 +19,144 @@ impl DLatch {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct Register36 {
-    pub registers: [DLatch; 36]
+/// Latches of the register word that take the bus value when enabled
+pub fn update_word<T: Logic, const W: usize>(register: &mut [T; W], bus: &[T; W], enabled: T) {
+    for i in 0..W {
+        register[i] = bus[i] & enabled | register[i] & !enabled;
+    }
 }
 
-impl Register36 {
-    pub fn new() -> Register36 { Register36 { registers: [DLatch::new(); 36] } }
+/// Register of the microinstruction, it is as wide as the layout
+#[derive(Clone)]
+pub struct MirRegister {
+    pub registers: Vec<DLatch>,
+    pub layout: Rc<MirLayout>,
+}
+
+impl MirRegister {
+    pub fn new(layout: Rc<MirLayout>) -> MirRegister { MirRegister { registers: vec![DLatch::new(); layout.width()], layout } }
 
-    pub fn update_from_bus(&mut self, input: &Bus36, enabled: bool) {
-        for i in 0..36 {
+    pub fn update_from_bus(&mut self, input: &MirBus, enabled: bool) {
+        for i in 0..self.registers.len() {
             self.registers[i].update(input.data[i], enabled);
         }
     }
 
-    pub fn get(self) -> [bool; 36] {
-        let mut res = [false; 36];
-        for i in 0..36 {
-            res[i] = self.registers[i].state;
-        }
-        res
+    pub fn get(&self) -> Vec<bool> {
+        self.registers.iter().map(|x| x.state).collect()
     }
 
-    pub fn read(self, enabled: bool) -> [bool; 36] {
+    pub fn read(&self, enabled: bool) -> Vec<bool> {
         let mut res = self.get();
-        for i in 0..36 {
+        for i in 0..res.len() {
             res[i] = res[i] && enabled
         }
         res
     }
 }
 
+/// Register of the `W`-bit datapath
 #[derive(Copy, Clone)]
-pub struct Register32 {
-    pub registers: [DLatch; 32]
+pub struct Register<const W: usize> {
+    pub registers: [DLatch; W]
 }
 
-impl Register32 {
-    pub fn new() -> Register32 { Register32 { registers: [DLatch::new(); 32] } }
+pub type Register32 = Register<32>;
+
+impl<const W: usize> Register<W> {
+    pub fn new() -> Register<W> { Register { registers: [DLatch::new(); W] } }
 
-    pub fn update_from_bus(&mut self, input: &Bus32, enabled: bool) {
-        for i in 0..32 {
+    pub fn update_from_bus(&mut self, input: &Bus<W>, enabled: bool) {
+        for i in 0..W {
             self.registers[i].update(input.data[i], enabled);
         }
     }
 
-    pub fn get(self) -> [bool; 32] {
-        let mut res = [false; 32];
-        for i in 0..32 {
+    pub fn get(self) -> [bool; W] {
+        let mut res = [false; W];
+        for i in 0..W {
             res[i] = self.registers[i].state;
         }
         res
     }
 
-    pub fn read(self, enabled: bool) -> [bool; 32] {
+    pub fn read(self, enabled: bool) -> [bool; W] {
         let mut res = self.get();
-        for i in 0..32 {
+        for i in 0..W {
             res[i] = res[i] && enabled
         }
         res
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct Register9 {
-    pub registers: [DLatch; 9]
+/// MPC, it is as wide as the `addr` field of the microinstruction
+#[derive(Clone)]
+pub struct MpcRegister {
+    pub registers: Vec<DLatch>
 }
 
-impl Register9 {
-    pub fn new() -> Register9 { Register9 { registers: [DLatch::new(); 9] } }
+impl MpcRegister {
+    pub fn new(width: usize) -> MpcRegister { MpcRegister { registers: vec![DLatch::new(); width] } }
 
-    pub fn update(&mut self, data: [bool; 9], enabled: bool) {
-        for i in 0..9 {
+    pub fn update(&mut self, data: &[bool], enabled: bool) {
+        for i in 0..self.registers.len() {
             self.registers[i].update(data[i], enabled);
         }
     }
 
-    pub fn get(self) -> [bool; 9] {
-        let mut res = [false; 9];
-        for i in 0..9 {
-            res[i] = self.registers[i].state;
-        }
-        res
+    pub fn get(&self) -> Vec<bool> {
+        self.registers.iter().map(|x| x.state).collect()
     }
 }
 
-pub struct Memory512x36 {
-    cells: [Register36; 512]
+/// Lowest `width` bits of the address
+pub fn address_bits(address: usize, width: usize) -> Vec<bool> {
+    (0..width).map(|i| address & (1 << i) != 0).collect()
+}
+
+/// Control store with `2^n` microinstructions, where `n` is the width of the `addr` field
+#[derive(Clone)]
+pub struct ControlMemory {
+    layout: Rc<MirLayout>,
+    cells: Vec<MirRegister>,
 }
 
-impl Memory512x36 {
-    pub fn new() -> Memory512x36 { Memory512x36 { cells: [Register36::new(); 512] } }
+impl ControlMemory {
+    pub fn new(layout: MirLayout) -> ControlMemory {
+        let layout = Rc::new(layout);
+        ControlMemory { cells: vec![MirRegister::new(layout.clone()); layout.control_store_size()], layout }
+    }
+
+    pub fn layout(&self) -> &Rc<MirLayout> { &self.layout }
 
-    pub fn write_data(&mut self, data: [bool; 36], addr: usize) {
-        let bus36 = Bus36::from(data);
-        let mut register = Register36::new();
-        register.update_from_bus(&bus36, true);
+    pub fn write_data(&mut self, data: &[bool], addr: usize) {
+        if data.len() != self.layout.width() {
+            panic!("Microinstruction has {} bits, but the layout has {}", data.len(), self.layout.width());
+        }
+        let bus = MirBus::from(data.to_vec());
+        let mut register = MirRegister::new(self.layout.clone());
+        register.update_from_bus(&bus, true);
         self.cells[addr] = register
     }
 
-    pub fn get(&self, address: Bus9) -> Bus36 {
-        let mut res_array = [false; 36];
-        let decoded_address = decoder_9x512(address.data);
-        for i in 0..512 {
-            for k in 0..36 {
-                res_array[k] = res_array[k] || self.cells[i].registers[k].state && decoded_address[i]
-            }
+    pub fn get(&self, address: AddressBus) -> MirBus {
+        MirBus { data: self.get_word(&address.data) }
+    }
+
+    /// Microinstruction at the address
+    pub fn read_address(&self, address: usize) -> Vec<bool> {
+        self.get(AddressBus::from(address_bits(address, self.layout.address_width()))).data
+    }
+
+    pub fn get_word<T: Logic>(&self, address: &[T]) -> Vec<T> {
+        let decoded_address = decoder(address);
+        let mut res = Vec::new();
+        for k in 0..self.layout.width() {
+            let selected: Vec<T> = self.cells.iter().zip(decoded_address.iter())
+                .map(|(cell, selected)| T::constant(cell.registers[k].state) & *selected)
+                .collect();
+            res.push(or_tree(&selected));
         }
-        Bus36 { data: res_array }
+        res
     }
 }
@@ -0,0 +1,408 @@
+use std::fs;
+use std::path::Path;
+
+use crate::main_memory::{decode, encode, MainMemory, MEMORY_SIZE};
+use crate::memory::Register;
+use crate::processor::{register, Mic1};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    /// The header and the cells, words one after another, the lowest byte first
+    RawLittleEndian,
+    /// The header and the cells, words one after another, the highest byte first
+    RawBigEndian,
+    /// The header line `REGS: WORD WORD ...` and lines `ADDR: WORD WORD ...` of 8 words, lines of zeros are skipped.
+    ///   `#` starts a comment.
+    Hex,
+    /// Data records of little-endian words, records of zeros are skipped. The header is at the linear address 0x10000.
+    IntelHex,
+}
+
+/// Registers of the processor, they are saved in the header of the image.
+///
+/// Memory reads and writes in progress are not saved, so the image should be taken between microinstructions that
+///   don't wait for the memory, e.g. at Main1.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub mar: i64,
+    pub mdr: i64,
+    pub pc: i64,
+    pub mbr: i64,
+    pub sp: i64,
+    pub lv: i64,
+    pub cpp: i64,
+    pub tos: i64,
+    pub opc: i64,
+    pub h: i64,
+    pub mpc: usize,
+}
+
+/// Names of the header words in the order of the header
+pub const HEADER: [&str; 11] = ["MAR", "MDR", "PC", "MBR", "SP", "LV", "CPP", "TOS", "OPC", "H", "MPC"];
+
+impl Registers {
+    pub fn of<const W: usize>(mic1: &Mic1<W>) -> Registers {
+        let value = |x: &Register<W>| encode(&x.get());
+        Registers {
+            mar: value(&mic1.mar),
+            mdr: value(&mic1.mdr),
+            pc: value(&mic1.pc),
+            mbr: value(&mic1.mbr),
+            sp: value(&mic1.sp),
+            lv: value(&mic1.lv),
+            cpp: value(&mic1.cpp),
+            tos: value(&mic1.tos),
+            opc: value(&mic1.opc),
+            h: value(&mic1.h),
+            mpc: mic1.mpc_address(),
+        }
+    }
+
+    pub fn restore<const W: usize>(&self, mic1: &mut Mic1<W>) {
+        mic1.mar = register(self.mar);
+        mic1.mdr = register(self.mdr);
+        mic1.pc = register(self.pc);
+        mic1.mbr = register(self.mbr);
+        mic1.sp = register(self.sp);
+        mic1.lv = register(self.lv);
+        mic1.cpp = register(self.cpp);
+        mic1.tos = register(self.tos);
+        mic1.opc = register(self.opc);
+        mic1.h = register(self.h);
+        mic1.set_mpc(self.mpc);
+    }
+
+    fn words(&self) -> Vec<i64> {
+        vec![self.mar, self.mdr, self.pc, self.mbr, self.sp, self.lv, self.cpp, self.tos, self.opc, self.h, self.mpc as i64]
+    }
+
+    /// Words of the header are truncated to the word width like the cells
+    fn from_words<const W: usize>(words: &[i64]) -> Registers {
+        if words.len() != HEADER.len() {
+            panic!("Header has {} words, expected {}", words.len(), HEADER.len());
+        }
+        let value = |i: usize| encode(&decode::<W>(words[i]));
+        Registers {
+            mar: value(0),
+            mdr: value(1),
+            pc: value(2),
+            mbr: value(3),
+            sp: value(4),
+            lv: value(5),
+            cpp: value(6),
+            tos: value(7),
+            opc: value(8),
+            h: value(9),
+            mpc: words[10] as usize,
+        }
+    }
+}
+
+const WORDS_PER_LINE: usize = 8;
+const BYTES_PER_RECORD: usize = 16;
+/// Upper half of the linear address of the header in Intel HEX
+const HEADER_SEGMENT: usize = 0x0001;
+
+fn word_bytes<const W: usize>() -> usize { W.div_ceil(8) }
+
+/// The registers and the contents of the memory cells, devices are not saved
+pub fn dump<const W: usize>(memory: &MainMemory<W>, registers: &Registers, format: ImageFormat) -> Vec<u8> {
+    let header = registers.words();
+    let words: Vec<i64> = (0..MEMORY_SIZE).map(|x| memory.read_word(x)).collect();
+    let bytes = |words: &[i64]| words.iter().flat_map(|x| little_endian::<W>(*x)).collect::<Vec<u8>>();
+    match format {
+        ImageFormat::RawLittleEndian => header.iter().chain(words.iter()).flat_map(|x| little_endian::<W>(*x)).collect(),
+        ImageFormat::RawBigEndian => header.iter().chain(words.iter()).flat_map(|x| little_endian::<W>(*x).into_iter().rev()).collect(),
+        ImageFormat::Hex => hex_listing::<W>(&header, &words).into_bytes(),
+        ImageFormat::IntelHex => intel_hex(&bytes(&header), &bytes(&words)).into_bytes(),
+    }
+}
+
+/// Memory with the cells from the image and the registers from its header, cells after the end of the image are zeros
+pub fn load<const W: usize>(image: &[u8], format: ImageFormat) -> (MainMemory<W>, Registers) {
+    let (header, words) = match format {
+        ImageFormat::RawLittleEndian | ImageFormat::RawBigEndian => {
+            let mut words = raw_words::<W>(image, format == ImageFormat::RawBigEndian);
+            if words.len() < HEADER.len() {
+                panic!("Image of {} words has no header", words.len());
+            }
+            let cells = words.split_off(HEADER.len());
+            (words, cells)
+        }
+        ImageFormat::Hex => {
+            let (header, cells) = parse_hex_listing(&text(image));
+            (header.unwrap_or_else(|| panic!("Image has no header")), cells)
+        }
+        ImageFormat::IntelHex => {
+            let (header, cells) = parse_intel_hex(&text(image));
+            let mut header = header.unwrap_or_else(|| panic!("Image has no header"));
+            // Zeros at the end of the header are skipped like the cells
+            header.resize(header.len().max(HEADER.len() * word_bytes::<W>()), 0);
+            (raw_words::<W>(&header, false), raw_words::<W>(&cells, false))
+        }
+    };
+    if words.len() > MEMORY_SIZE {
+        panic!("Image has {} words, the memory has {}", words.len(), MEMORY_SIZE);
+    }
+    let mut memory = MainMemory::initialize();
+    for (addr, word) in words.iter().enumerate() {
+        memory.write_word(*word, addr);
+    }
+    (memory, Registers::from_words::<W>(&header))
+}
+
+pub fn save_image<const W: usize>(path: &Path, mic1: &Mic1<W>, format: ImageFormat) {
+    fs::write(path, dump(&mic1.main_memory, &Registers::of(mic1), format)).unwrap_or_else(|e| panic!("Cannot write {}: {}", path.display(), e));
+}
+
+pub fn load_image<const W: usize>(path: &Path, format: ImageFormat) -> (MainMemory<W>, Registers) {
+    let image = fs::read(path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    load(&image, format)
+}
+
+fn little_endian<const W: usize>(word: i64) -> Vec<u8> {
+    (0..word_bytes::<W>()).map(|x| (word >> (8 * x).min(63)) as u8).collect()
+}
+
+fn raw_words<const W: usize>(image: &[u8], big_endian: bool) -> Vec<i64> {
+    let size = word_bytes::<W>();
+    if !image.len().is_multiple_of(size) {
+        panic!("Image of {} bytes is not made of {}-byte words", image.len(), size);
+    }
+    image.chunks(size).map(|bytes| {
+        let mut word = 0i64;
+        for (i, byte) in bytes.iter().enumerate() {
+            let byte = if big_endian { bytes[size - 1 - i] } else { *byte };
+            word |= (byte as i64) << (8 * i);
+        }
+        word
+    }).collect()
+}
+
+fn text(image: &[u8]) -> String {
+    String::from_utf8(image.to_vec()).unwrap_or_else(|_| panic!("Image is not a text"))
+}
+
+fn hex_listing<const W: usize>(header: &[i64], words: &[i64]) -> String {
+    let digits = word_bytes::<W>() * 2;
+    let mask = if W >= 64 { -1 } else { (1i64 << W) - 1 };
+    let hex = |values: &[i64]| values.iter().map(|x| format!("{:0width$X}", x & mask, width = digits)).collect::<Vec<String>>().join(" ");
+    let mut listing = format!("# {} words of {} bits\n# {}\nREGS: {}\n", words.len(), W, HEADER.join(" "), hex(header));
+    for (line, chunk) in words.chunks(WORDS_PER_LINE).enumerate() {
+        if chunk.iter().all(|x| *x == 0) {
+            continue;
+        }
+        listing += &format!("{:04X}: {}\n", line * WORDS_PER_LINE, hex(chunk));
+    }
+    listing
+}
+
+/// Words of the header and of the cells
+fn parse_hex_listing(listing: &str) -> (Option<Vec<i64>>, Vec<i64>) {
+    let mut header = None;
+    let mut words = Vec::new();
+    for line in listing.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (address, values) = line.split_once(':').unwrap_or_else(|| panic!("Unexpected line of the listing: {}", line));
+        if address.trim() == "REGS" {
+            header = Some(values.split_whitespace().map(hex_number).collect());
+            continue;
+        }
+        let start = hex_number(address.trim()) as usize;
+        for (address, value) in (start..).zip(values.split_whitespace()) {
+            if words.len() <= address {
+                words.resize(address + 1, 0);
+            }
+            words[address] = hex_number(value);
+        }
+    }
+    (header, words)
+}
+
+fn hex_number(text: &str) -> i64 {
+    u64::from_str_radix(text, 16).unwrap_or_else(|_| panic!("Unexpected hex number: {}", text)) as i64
+}
+
+fn intel_hex(header: &[u8], bytes: &[u8]) -> String {
+    let mut records = data_records(bytes);
+    records += &record(0, 0x04, &[(HEADER_SEGMENT >> 8) as u8, HEADER_SEGMENT as u8]);
+    records += &data_records(header);
+    records += &record(0, 0x01, &[]);
+    records
+}
+
+fn data_records(bytes: &[u8]) -> String {
+    let mut records = String::new();
+    for (index, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+        if chunk.iter().all(|x| *x == 0) {
+            continue;
+        }
+        records += &record(index * BYTES_PER_RECORD, 0x00, chunk);
+    }
+    records
+}
+
+/// `:LLAAAATT` with data and checksum, the checksum makes the sum of all bytes zero
+fn record(address: usize, kind: u8, data: &[u8]) -> String {
+    if address > 0xFFFF {
+        panic!("Address {} doesn't fit into a record", address);
+    }
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)).wrapping_neg();
+    bytes.push(checksum);
+    let hex: Vec<String> = bytes.iter().map(|x| format!("{:02X}", x)).collect();
+    format!(":{}\n", hex.concat())
+}
+
+/// Bytes of the header and of the cells, the header is in the segment `HEADER_SEGMENT` of extended linear addresses
+fn parse_intel_hex(text: &str) -> (Option<Vec<u8>>, Vec<u8>) {
+    let mut header = None;
+    let mut image = Vec::new();
+    let mut segment = 0;
+    for line in text.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let digits = line.strip_prefix(':').unwrap_or_else(|| panic!("Record doesn't start with a colon: {}", line));
+        if digits.len() % 2 != 0 || digits.len() < 10 {
+            panic!("Unexpected record: {}", line);
+        }
+        let bytes: Vec<u8> = (0..digits.len()).step_by(2).map(|x| hex_number(&digits[x..x + 2]) as u8).collect();
+        if bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0 {
+            panic!("Wrong checksum of the record: {}", line);
+        }
+        let length = bytes[0] as usize;
+        if bytes.len() != length + 5 {
+            panic!("Wrong length of the record: {}", line);
+        }
+        let address = (bytes[1] as usize) << 8 | bytes[2] as usize;
+        match bytes[3] {
+            0x00 => {
+                let data = match segment {
+                    0 => &mut image,
+                    HEADER_SEGMENT => header.get_or_insert_with(Vec::new),
+                    _ => panic!("Unexpected segment {:04X}: {}", segment, line),
+                };
+                if data.len() < address + length {
+                    data.resize(address + length, 0);
+                }
+                data[address..address + length].copy_from_slice(&bytes[4..4 + length]);
+            }
+            0x01 => break,
+            0x04 if length == 2 => {
+                segment = (bytes[4] as usize) << 8 | bytes[5] as usize;
+                if segment == HEADER_SEGMENT {
+                    header.get_or_insert_with(Vec::new);
+                }
+            }
+            kind => panic!("Unsupported record type {:02X}: {}", kind, line),
+        }
+    }
+    (header, image)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_processor, create_processor_from_image, PROGRAM_START};
+    use crate::main_memory::encode;
+    use crate::parser::parse;
+
+    use super::*;
+
+    const FORMATS: [ImageFormat; 4] = [ImageFormat::RawLittleEndian, ImageFormat::RawBigEndian, ImageFormat::Hex, ImageFormat::IntelHex];
+
+    fn memory<const W: usize>(values: &[(u16, i64)]) -> MainMemory<W> {
+        let mut memory = MainMemory::initialize();
+        for (addr, value) in values {
+            memory.write_word(*value, *addr as usize % MEMORY_SIZE);
+        }
+        memory
+    }
+
+    fn round_trip<const W: usize>(values: &[(u16, i64)], header: &[i64]) -> bool {
+        let memory: MainMemory<W> = memory(values);
+        let mut words = header.to_vec();
+        words.resize(HEADER.len(), 0);
+        words[10] &= 0x1FF;
+        let registers = Registers::from_words::<W>(&words);
+        FORMATS.iter().all(|format| {
+            let (loaded, loaded_registers) = load::<W>(&dump(&memory, &registers, *format), *format);
+            (0..MEMORY_SIZE).all(|x| loaded.read_word(x) == memory.read_word(x)) && loaded_registers == registers
+        })
+    }
+
+    #[quickcheck]
+    fn quick_round_trip(values: Vec<(u16, i64)>, header: Vec<i64>) -> bool {
+        round_trip::<16>(&values, &header) && round_trip::<32>(&values, &header) && round_trip::<64>(&values, &header)
+    }
+
+    #[test]
+    fn byte_order() {
+        let memory: MainMemory = memory(&[(0, 0x0102_0304)]);
+        let header = 4 * HEADER.len();
+        assert_eq!(vec![4, 3, 2, 1], dump(&memory, &Registers::default(), ImageFormat::RawLittleEndian)[header..header + 4].to_vec());
+        assert_eq!(vec![1, 2, 3, 4], dump(&memory, &Registers::default(), ImageFormat::RawBigEndian)[header..header + 4].to_vec());
+        assert_eq!(2 * (HEADER.len() + MEMORY_SIZE), dump(&MainMemory::<16>::initialize(), &Registers::default(), ImageFormat::RawLittleEndian).len());
+    }
+
+    #[test]
+    fn hex_listing() {
+        let memory: MainMemory = memory(&[(0, 10), (17, -1)]);
+        let registers = Registers { pc: 0x63, sp: 0x0B, lv: 0x0A, tos: -1, mpc: 1, ..Registers::default() };
+        let listing = String::from_utf8(dump(&memory, &registers, ImageFormat::Hex)).unwrap();
+        assert_eq!("# 512 words of 32 bits\n\
+                    # MAR MDR PC MBR SP LV CPP TOS OPC H MPC\n\
+                    REGS: 00000000 00000000 00000063 00000000 0000000B 0000000A 00000000 FFFFFFFF 00000000 00000000 00000001\n\
+                    0000: 0000000A 00000000 00000000 00000000 00000000 00000000 00000000 00000000\n\
+                    0010: 00000000 FFFFFFFF 00000000 00000000 00000000 00000000 00000000 00000000\n", listing);
+
+        let (loaded, loaded_registers): (MainMemory, Registers) = load(b"REGS: 0 0 63 0 B A 0 FFFFFFFF 0 0 1\n0064: 10 FF # BIPUSH\n\n0003: 7\n", ImageFormat::Hex);
+        assert_eq!(vec![0x10, 0xFF], vec![loaded.read_number(100), loaded.read_number(101)]);
+        assert_eq!(7, loaded.read_number(3));
+        assert_eq!(registers, loaded_registers);
+    }
+
+    #[test]
+    #[should_panic(expected = "Image has no header")]
+    fn hex_listing_without_header() {
+        load::<32>(b"0064: 10 FF\n", ImageFormat::Hex);
+    }
+
+    #[test]
+    fn intel_hex_records() {
+        let memory: MainMemory = memory(&[(0, 10)]);
+        let registers = Registers { pc: 0x63, ..Registers::default() };
+        let records = String::from_utf8(dump(&memory, &registers, ImageFormat::IntelHex)).unwrap();
+        assert_eq!(":100000000A000000000000000000000000000000E6\n\
+                    :020000040001F9\n\
+                    :10000000000000000000000063000000000000008D\n\
+                    :00000001FF\n", records);
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong checksum of the record: :0400000001000000FA")]
+    fn intel_hex_checksum() {
+        load::<32>(b":0400000001000000FA\n", ImageFormat::IntelHex);
+    }
+
+    #[test]
+    fn image_runs_like_the_program() {
+        let commands = parse("BIPUSH 0x05\nIADD\nBIPUSH 0x03\nISUB\nBIPUSH 0x02\nIADD");
+        let mut compiled = create_processor(&commands, vec![1, 2], [0; 10]);
+        // Capture the processor in the middle of the program
+        compiled.step_instruction();
+        compiled.step_instruction();
+        compiled.step_instruction();
+        let image = dump(&compiled.main_memory, &Registers::of(&compiled), ImageFormat::IntelHex);
+        compiled.run(commands.len() + 1, PROGRAM_START);
+
+        let (memory, registers) = load(&image, ImageFormat::IntelHex);
+        let mut loaded = create_processor_from_image(memory, &registers);
+        assert_eq!(registers, Registers::of(&loaded));
+        loaded.run(commands.len() + 1, PROGRAM_START);
+        assert_eq!(6, encode(&loaded.tos.get()));
+        assert_eq!(Registers::of(&compiled), Registers::of(&loaded));
+        assert!((0..MEMORY_SIZE).all(|x| compiled.main_memory.read_word(x) == loaded.main_memory.read_word(x)));
+    }
+}
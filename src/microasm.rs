@@ -1,8 +1,12 @@
+use std::collections::HashMap;
+
+use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
 use crate::asm::IjvmCommand::*;
-use crate::main_memory::fast_decode;
+use crate::mir_layout::{MirLayout, JAM_BIT};
 use crate::microasm::MicroAsm::*;
+use crate::parser::number;
 
 //noinspection SpellCheckingInspection
 /**
@@ -35,6 +39,18 @@ pub enum MicroAsm {
     ior2 = IOR as isize + 1,
     ior3 = IOR as isize + 2,
 
+    ishl1 = ISHL as isize,
+    ishl2 = ISHL as isize + 1,
+    ishl3 = ISHL as isize + 2 + 0x12,
+
+    ishr1 = ISHR as isize,
+    ishr2 = ISHR as isize + 1,
+    ishr3 = ISHR as isize + 2 + 0x11,
+
+    iushr1 = IUSHR as isize,
+    iushr2 = IUSHR as isize + 1,
+    iushr3 = IUSHR as isize + 2 + 0x10,
+
     dup1 = DUP as isize,
     dup2 = DUP as isize + 1,
 
@@ -56,15 +72,16 @@ pub enum MicroAsm {
     iload1 = ILOAD as isize,
     iload2 = ILOAD as isize + 1,
     iload3 = ILOAD as isize + 2,
-    iload4 = ILOAD as isize + 3,
+    iload4 = ILOAD as isize + 3 + 5,
     iload5 = ILOAD as isize + 4,
 
     istore1 = ISTORE as isize,
     istore2 = ISTORE as isize + 1,
     istore3 = ISTORE as isize + 2,
-    istore4 = ISTORE as isize + 3,
-    istore5 = ISTORE as isize + 4,
-    istore6 = ISTORE as isize + 5,
+    istore4 = ISTORE as isize + 3 + 2,
+    istore5 = ISTORE as isize + 4 + 2,
+    istore6 = ISTORE as isize + 5 + 2,
+    istore7 = ISTORE as isize + 6 + 2,
 
     wide1 = WIDE as isize,
     wide2 = WIDE as isize + 1,
@@ -76,6 +93,17 @@ pub enum MicroAsm {
     wide_istore2 = ISTORE as isize + 0x100 + 1,
     wide_istore3 = ISTORE as isize + 0x100 + 2,
     wide_istore4 = ISTORE as isize + 0x100 + 3,
+    wide_iinc1 = IINC as isize + 0x100,
+    wide_iinc2 = IINC as isize + 0x100 + 1,
+    wide_iinc3 = IINC as isize + 0x100 + 2,
+    wide_iinc4 = IINC as isize + 0x100 + 3,
+    wide_iinc5 = IINC as isize + 0x100 + 4,
+    wide_iinc6 = IINC as isize + 0x100 + 5,
+    wide_iinc7 = IINC as isize + 0x100 + 6,
+    wide_iinc8 = IINC as isize + 0x100 + 7,
+    wide_iinc9 = IINC as isize + 0x100 + 8,
+    wide_iinc10 = IINC as isize + 0x100 + 9,
+    wide_iinc11 = IINC as isize + 0x100 + 10,
 
     ldc_w1 = LDC_W as isize,
     ldc_w2 = LDC_W as isize + 1,
@@ -100,11 +128,13 @@ pub enum MicroAsm {
     iflt2 = IFLT as isize + 1,
     iflt3 = IFLT as isize + 2,
     iflt4 = IFLT as isize + 3,
+    iflt5 = IFLT as isize + 4 + 2,
 
     ifeq1 = IFEQ as isize,
     ifeq2 = IFEQ as isize + 1,
     ifeq3 = IFEQ as isize + 2 + 5,
     ifeq4 = IFEQ as isize + 3 + 6,
+    ifeq5 = IFEQ as isize + 4 + 6,
 
     if_icmpeq1 = IF_ICMPEQ as isize,
     if_icmpeq2 = IF_ICMPEQ as isize + 1 + 13,
@@ -149,227 +179,736 @@ pub enum MicroAsm {
     ireturn6 = IRETURN as isize + 5 + 41,
     ireturn7 = IRETURN as isize + 6 + 42,
     ireturn8 = IRETURN as isize + 7 + 43,
+
+    // Stack checks jump to the next microinstruction + 0x100
+    iadd_underflow = IADD as isize + 1 + 0x100,
+    isub_underflow = ISUB as isize + 1 + 0x100,
+    iand_underflow = IAND as isize + 1 + 0x100,
+    ior_underflow = IOR as isize + 1 + 0x100,
+    ishl_underflow = ISHL as isize + 1 + 0x100,
+    ishr_underflow = ISHR as isize + 1 + 0x100,
+    iushr_underflow = IUSHR as isize + 1 + 0x100,
+    pop_underflow = POP as isize + 2 + 3 + 0x100,
+    istore_underflow = ISTORE as isize + 3 + 2 + 0x100,
+    iflt_underflow = IFLT as isize + 1 + 0x100,
+    ifeq_underflow = IFEQ as isize + 1 + 0x100,
+    if_icmpeq_underflow = IF_ICMPEQ as isize + 1 + 13 + 0x100,
+    dup_overflow = DUP as isize + 1 + 0x100,
+    bipush_overflow = BIPUSH as isize + 1 + 0x100,
+    iload_overflow = ILOAD as isize + 3 + 5 + 0x100,
+    invokevirtual_overflow1 = INVOKEVIRTUAL as isize + 17 + 6 + 0x100,
+    invokevirtual_overflow2 = INVOKEVIRTUAL as isize + 19 + 8 + 0x100,
+
+    // Error microroutines loop forever, the processor stops on them
+    stack_overflow1 = 0x1FE,
+    stack_underflow1 = 0x1FF,
 }
 
 impl MicroAsm {
     //noinspection SpellCheckingInspection
-    pub fn command(&self) -> [bool; 36] {
+    pub fn command(&self, layout: &MirLayout) -> Vec<bool> {
         match *self {
-            Main1 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().jmpc().get(),
-
-            nop1 => Cb::new().next_command(Main1),
-
-            iadd1 => Cb::new().r_sp().alu_b_dec().w_mar().w_sp().read().next_command(iadd2),
-            iadd2 => Cb::new().r_tos().alu_b().w_h().next_command(iadd3),
-            iadd3 => Cb::new().r_mdr().alu_sum().w_mdr().w_tos().write().finish(),
-
-            isub1 => Cb::new().r_sp().alu_b_dec().w_mar().w_sp().read().next_command(isub2),
-            isub2 => Cb::new().r_tos().alu_b().w_h().next_command(isub3),
-            isub3 => Cb::new().r_mdr().alu_sub().w_mdr().w_tos().write().finish(),
-
-            iand1 => Cb::new().r_sp().alu_b_dec().w_mar().w_sp().read().next_command(iand2),
-            iand2 => Cb::new().r_tos().alu_b().w_h().next_command(iand3),
-            iand3 => Cb::new().r_mdr().alu_and().w_mdr().w_tos().write().finish(),
-
-            ior1 => Cb::new().r_sp().alu_b_dec().w_mar().w_sp().read().next_command(ior2),
-            ior2 => Cb::new().r_tos().alu_b().w_h().next_command(ior3),
-            ior3 => Cb::new().r_mdr().alu_or().w_mdr().w_tos().write().finish(),
-
-            dup1 => Cb::new().r_sp().alu_b_inc().w_sp().w_mar().next_command(dup2),
-            dup2 => Cb::new().r_tos().alu_b().w_mdr().write().finish(),
-
-            pop1 => Cb::new().r_sp().alu_b_dec().w_sp().w_mar().read().next_command(pop2),
-            pop2 => Cb::new().next_command(pop3), // Waiting for read
-            pop3 => Cb::new().r_mdr().alu_b().w_tos().finish(),
-
-            swap1 => Cb::new().r_sp().alu_b_dec().w_mar().read().next_command(swap2),
-            swap2 => Cb::new().r_sp().alu_b().w_mar().next_command(swap3),
-            swap3 => Cb::new().r_mdr().alu_b().w_h().write().next_command(swap4),
-            swap4 => Cb::new().r_tos().alu_b().w_mdr().next_command(swap5),
-            swap5 => Cb::new().r_sp().alu_b_dec().w_mar().write().next_command(swap6),
-            swap6 => Cb::new().alu_a().w_tos().finish(),
-
-            bipush1 => Cb::new().r_sp().alu_b_inc().w_sp().w_mar().next_command(bipush2),
-            bipush2 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(bipush3),
-            bipush3 => Cb::new().r_mbr().alu_b().w_tos().w_mdr().write().finish(),
-
-            iload1 => Cb::new().r_lv().alu_b().w_h().next_command(iload2),
-            iload2 => Cb::new().r_mbru().alu_sum().w_mar().read().next_command(iload3),
-            iload3 => Cb::new().r_sp().alu_b_inc().w_sp().w_mar().next_command(iload4),
-            iload4 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().write().next_command(iload5),
-            iload5 => Cb::new().r_mdr().alu_b().w_tos().finish(),
-
-            istore1 => Cb::new().r_lv().alu_b().w_h().next_command(istore2),
-            istore2 => Cb::new().r_mbru().alu_sum().w_mar().next_command(istore3),
-            istore3 => Cb::new().r_tos().alu_b().w_mdr().write().next_command(istore4),
-            istore4 => Cb::new().r_sp().alu_b_dec().w_sp().w_mar().read().next_command(istore5),
-            istore5 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(istore6),
-            istore6 => Cb::new().r_mdr().alu_b().w_tos().finish(),
-
-            wide1 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(wide2),
-            wide2 => Cb::new().jmpc().next_command_wide_jump(),
-            wide_iload1 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(wide_iload2),
-            wide_iload2 => Cb::new().r_mbru().alu_b().sll8().w_h().next_command(wide_iload3),
-            wide_iload3 => Cb::new().r_mbru().alu_or().w_h().next_command(wide_iload4),
-            wide_iload4 => Cb::new().r_lv().alu_sum().w_mar().read().next_command(iload3),
-            wide_istore1 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(wide_istore2),
-            wide_istore2 => Cb::new().r_mbru().alu_b().sll8().w_h().next_command(wide_istore3),
-            wide_istore3 => Cb::new().r_mbru().alu_or().w_h().next_command(wide_istore4),
-            wide_istore4 => Cb::new().r_lv().alu_sum().w_mar().read().next_command(istore3),
-
-            ldc_w1 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(ldc_w2),
-            ldc_w2 => Cb::new().r_mbru().alu_b().sll8().w_h().next_command(ldc_w3),
-            ldc_w3 => Cb::new().r_mbru().alu_or().w_h().next_command(ldc_w4),
-            ldc_w4 => Cb::new().r_cpp().alu_sum().w_mar().read().next_command(iload3),
-
-            iinc1 => Cb::new().r_lv().alu_b().w_h().next_command(iinc2),
-            iinc2 => Cb::new().r_mbru().alu_sum().w_mar().read().next_command(iinc3),
-            iinc3 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(iinc4),
-            iinc4 => Cb::new().r_mdr().alu_b().w_h().next_command(iinc5),
-            iinc5 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(iinc6),
-            iinc6 => Cb::new().r_mbr().alu_sum().w_mdr().write().finish(),
-
-            goto1 => Cb::new().r_pc().alu_b_dec().w_opc().next_command(goto2),
-            goto2 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(goto3),
-            goto3 => Cb::new().r_mbr().alu_b().sll8().w_h().next_command(goto4),
-            goto4 => Cb::new().r_mbru().alu_or().w_h().next_command(goto5),
-            goto5 => Cb::new().r_opc().alu_sum().w_pc().fetch().next_command(goto6),
-            goto6 => Cb::new().finish(),
-
-            iflt1 => Cb::new().r_sp().alu_b_dec().w_sp().w_mar().read().next_command(iflt2),
-            iflt2 => Cb::new().r_tos().alu_b().w_opc().next_command(iflt3),
-            iflt3 => Cb::new().r_mdr().alu_b().w_tos().next_command(iflt4),
-            iflt4 => Cb::new().r_opc().alu_b().jamn().next_command(F),
-
-            ifeq1 => Cb::new().r_sp().alu_b_dec().w_sp().w_mar().read().next_command(ifeq2),
-            ifeq2 => Cb::new().r_tos().alu_b().w_opc().next_command(ifeq3),
-            ifeq3 => Cb::new().r_mdr().alu_b().w_tos().next_command(ifeq4),
-            ifeq4 => Cb::new().r_opc().alu_b().jamz().next_command(F),
-
-            if_icmpeq1 => Cb::new().r_sp().alu_b_dec().w_sp().w_mar().read().next_command(if_icmpeq2),
-            if_icmpeq2 => Cb::new().r_sp().alu_b_dec().w_sp().w_mar().next_command(if_icmpeq3),
-            if_icmpeq3 => Cb::new().r_mdr().alu_b().w_h().read().next_command(if_icmpeq4),
-            if_icmpeq4 => Cb::new().r_tos().alu_b().w_opc().next_command(if_icmpeq5),
-            if_icmpeq5 => Cb::new().r_mdr().alu_b().w_tos().next_command(if_icmpeq6),
-            if_icmpeq6 => Cb::new().r_opc().alu_sub().jamz().next_command(F),
-
-            T => Cb::new().r_pc().alu_b_dec().w_opc().fetch().next_command(goto2),
-            F => Cb::new().r_pc().alu_b_inc().w_pc().next_command(F2),
-            F2 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(F3),
-            F3 => Cb::new().finish(),
-
-            invokevirtual1 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual2),
-            invokevirtual2 => Cb::new().r_mbru().alu_b().sll8().w_h().next_command(invokevirtual3),
-            invokevirtual3 => Cb::new().r_mbru().alu_or().w_h().next_command(invokevirtual4),
-            invokevirtual4 => Cb::new().r_cpp().alu_sum().w_mar().read().next_command(invokevirtual5),
-            invokevirtual5 => Cb::new().r_pc().alu_b_inc().w_opc().next_command(invokevirtual6),
-            invokevirtual6 => Cb::new().r_mdr().alu_b().w_pc().fetch().next_command(invokevirtual7),
-            invokevirtual7 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual8),
-            invokevirtual8 => Cb::new().r_mbru().alu_b().sll8().w_h().next_command(invokevirtual9),
-            invokevirtual9 => Cb::new().r_mbru().alu_or().w_h().next_command(invokevirtual10),
-            invokevirtual10 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual11),
-            invokevirtual11 => Cb::new().r_sp().alu_sub().w_tos().next_command(invokevirtual12),
-            invokevirtual12 => Cb::new().r_tos().alu_b_inc().w_mar().w_tos().next_command(invokevirtual13),
-            invokevirtual13 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual14),
-            invokevirtual14 => Cb::new().r_mbru().alu_b().sll8().w_h().next_command(invokevirtual15),
-            invokevirtual15 => Cb::new().r_mbru().alu_or().w_h().next_command(invokevirtual16),
-            invokevirtual16 => Cb::new().r_sp().alu_sum_inc().w_mdr().write().next_command(invokevirtual17),
-            invokevirtual17 => Cb::new().r_mdr().alu_b().w_sp().w_mar().next_command(invokevirtual18),
-            invokevirtual18 => Cb::new().r_opc().alu_b().w_mdr().write().next_command(invokevirtual19),
-            invokevirtual19 => Cb::new().r_sp().alu_b_inc().w_sp().w_mar().next_command(invokevirtual20),
-            invokevirtual20 => Cb::new().r_lv().alu_b().w_mdr().write().next_command(invokevirtual21),
-            invokevirtual21 => Cb::new().r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual22),
-            invokevirtual22 => Cb::new().r_tos().alu_b().w_lv().finish(),
-
-            ireturn1 => Cb::new().r_lv().alu_b().w_sp().w_mar().read().next_command(ireturn2),
-            ireturn2 => Cb::new().next_command(ireturn3),
-            ireturn3 => Cb::new().r_mdr().alu_b().w_mar().w_lv().read().next_command(ireturn4),
-            ireturn4 => Cb::new().r_lv().alu_b_inc().w_mar().next_command(ireturn5),
-            ireturn5 => Cb::new().r_mdr().alu_b().w_pc().read().fetch().next_command(ireturn6),
-            ireturn6 => Cb::new().r_sp().alu_b().w_mar().next_command(ireturn7),
-            ireturn7 => Cb::new().r_mdr().alu_b().w_lv().next_command(ireturn8),
-            ireturn8 => Cb::new().r_tos().alu_b().w_mdr().write().finish(),
+            Main1 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().jmpc().get(),
+
+            nop1 => Cb::new(layout).next_command(Main1),
+
+            iadd1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().w_sp().read().jams().next_command(iadd2),
+            iadd2 => Cb::new(layout).r_tos().alu_b().w_h().next_command(iadd3),
+            iadd3 => Cb::new(layout).r_mdr().alu_sum().w_mdr().w_tos().write().finish(),
+
+            isub1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().w_sp().read().jams().next_command(isub2),
+            isub2 => Cb::new(layout).r_tos().alu_b().w_h().next_command(isub3),
+            isub3 => Cb::new(layout).r_mdr().alu_sub().w_mdr().w_tos().write().finish(),
+
+            iand1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().w_sp().read().jams().next_command(iand2),
+            iand2 => Cb::new(layout).r_tos().alu_b().w_h().next_command(iand3),
+            iand3 => Cb::new(layout).r_mdr().alu_and().w_mdr().w_tos().write().finish(),
+
+            ior1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().w_sp().read().jams().next_command(ior2),
+            ior2 => Cb::new(layout).r_tos().alu_b().w_h().next_command(ior3),
+            ior3 => Cb::new(layout).r_mdr().alu_or().w_mdr().w_tos().write().finish(),
+
+            ishl1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().w_sp().read().jams().next_command(ishl2),
+            ishl2 => Cb::new(layout).r_tos().alu_b().w_h().next_command(ishl3),
+            ishl3 => Cb::new(layout).r_mdr().alu_b().sll().shift_by_h().w_mdr().w_tos().write().finish(),
+
+            ishr1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().w_sp().read().jams().next_command(ishr2),
+            ishr2 => Cb::new(layout).r_tos().alu_b().w_h().next_command(ishr3),
+            ishr3 => Cb::new(layout).r_mdr().alu_b().sra().shift_by_h().w_mdr().w_tos().write().finish(),
+
+            iushr1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().w_sp().read().jams().next_command(iushr2),
+            iushr2 => Cb::new(layout).r_tos().alu_b().w_h().next_command(iushr3),
+            iushr3 => Cb::new(layout).r_mdr().alu_b().srl().shift_by_h().w_mdr().w_tos().write().finish(),
+
+            dup1 => Cb::new(layout).r_sp().alu_b_inc().w_sp().w_mar().jams().next_command(dup2),
+            dup2 => Cb::new(layout).r_tos().alu_b().w_mdr().write().finish(),
+
+            pop1 => Cb::new(layout).r_sp().alu_b_dec().w_sp().w_mar().read().next_command(pop2),
+            pop2 => Cb::new(layout).r_sp().alu_b_inc().jams().next_command(pop3), // Waiting for read, checking the old SP
+            pop3 => Cb::new(layout).r_mdr().alu_b().w_tos().finish(),
+
+            swap1 => Cb::new(layout).r_sp().alu_b_dec().w_mar().read().next_command(swap2),
+            swap2 => Cb::new(layout).r_sp().alu_b().w_mar().next_command(swap3),
+            swap3 => Cb::new(layout).r_mdr().alu_b().w_h().write().next_command(swap4),
+            swap4 => Cb::new(layout).r_tos().alu_b().w_mdr().next_command(swap5),
+            swap5 => Cb::new(layout).r_sp().alu_b_dec().w_mar().write().next_command(swap6),
+            swap6 => Cb::new(layout).alu_a().w_tos().finish(),
+
+            bipush1 => Cb::new(layout).r_sp().alu_b_inc().w_sp().w_mar().jams().next_command(bipush2),
+            bipush2 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(bipush3),
+            bipush3 => Cb::new(layout).r_mbru().alu_b().w_tos().w_mdr().write().finish(),
+
+            iload1 => Cb::new(layout).r_lv().alu_b().w_h().next_command(iload2),
+            iload2 => Cb::new(layout).r_mbru().alu_sum().w_mar().read().next_command(iload3),
+            iload3 => Cb::new(layout).r_sp().alu_b_inc().w_sp().w_mar().jams().next_command(iload4),
+            iload4 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().write().next_command(iload5),
+            iload5 => Cb::new(layout).r_mdr().alu_b().w_tos().finish(),
+
+            istore1 => Cb::new(layout).r_lv().alu_b().w_h().next_command(istore2),
+            istore2 => Cb::new(layout).r_mbru().alu_sum().w_mar().next_command(istore3),
+            istore3 => Cb::new(layout).r_sp().alu_b().jams().next_command(istore4),
+            istore4 => Cb::new(layout).r_tos().alu_b().w_mdr().write().next_command(istore5),
+            istore5 => Cb::new(layout).r_sp().alu_b_dec().w_sp().w_mar().read().next_command(istore6),
+            istore6 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(istore7),
+            istore7 => Cb::new(layout).r_mdr().alu_b().w_tos().finish(),
+
+            wide1 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(wide2),
+            wide2 => Cb::new(layout).jmpc().next_command_wide_jump(),
+            wide_iload1 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(wide_iload2),
+            wide_iload2 => Cb::new(layout).r_mbr().alu_b().sll8().w_h().next_command(wide_iload3),
+            wide_iload3 => Cb::new(layout).r_mbr().alu_or().w_h().next_command(wide_iload4),
+            wide_iload4 => Cb::new(layout).r_lv().alu_sum().w_mar().read().next_command(iload3),
+            wide_istore1 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(wide_istore2),
+            wide_istore2 => Cb::new(layout).r_mbr().alu_b().sll8().w_h().next_command(wide_istore3),
+            wide_istore3 => Cb::new(layout).r_mbr().alu_or().w_h().next_command(wide_istore4),
+            wide_istore4 => Cb::new(layout).r_lv().alu_sum().w_mar().read().next_command(istore3),
+            // 16-bit index without the sign and 16-bit increment with the sign
+            wide_iinc1 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(wide_iinc2),
+            wide_iinc2 => Cb::new(layout).r_mbr().alu_b().sll8().w_h().next_command(wide_iinc3),
+            wide_iinc3 => Cb::new(layout).r_mbr().alu_or().w_h().next_command(wide_iinc4),
+            wide_iinc4 => Cb::new(layout).r_lv().alu_sum().w_mar().read().next_command(wide_iinc5),
+            wide_iinc5 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(wide_iinc6),
+            wide_iinc6 => Cb::new(layout).r_mdr().alu_b().w_opc().next_command(wide_iinc7),
+            wide_iinc7 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(wide_iinc8),
+            wide_iinc8 => Cb::new(layout).r_mbru().alu_b().sll8().w_h().next_command(wide_iinc9),
+            wide_iinc9 => Cb::new(layout).r_mbr().alu_or().w_h().next_command(wide_iinc10),
+            wide_iinc10 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(wide_iinc11),
+            wide_iinc11 => Cb::new(layout).r_opc().alu_sum().w_mdr().write().finish(),
+
+            ldc_w1 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(ldc_w2),
+            ldc_w2 => Cb::new(layout).r_mbru().alu_b().sll8().w_h().next_command(ldc_w3),
+            ldc_w3 => Cb::new(layout).r_mbru().alu_or().w_h().next_command(ldc_w4),
+            ldc_w4 => Cb::new(layout).r_cpp().alu_sum().w_mar().read().next_command(iload3),
+
+            iinc1 => Cb::new(layout).r_lv().alu_b().w_h().next_command(iinc2),
+            iinc2 => Cb::new(layout).r_mbru().alu_sum().w_mar().read().next_command(iinc3),
+            iinc3 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(iinc4),
+            iinc4 => Cb::new(layout).r_mdr().alu_b().w_h().next_command(iinc5),
+            iinc5 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(iinc6),
+            iinc6 => Cb::new(layout).r_mbru().alu_sum().w_mdr().write().finish(),
+
+            goto1 => Cb::new(layout).r_pc().alu_b_dec().w_opc().next_command(goto2),
+            goto2 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(goto3),
+            goto3 => Cb::new(layout).r_mbru().alu_b().sll8().w_h().next_command(goto4),
+            goto4 => Cb::new(layout).r_mbr().alu_or().w_h().next_command(goto5),
+            goto5 => Cb::new(layout).r_opc().alu_sum().w_pc().fetch().next_command(goto6),
+            goto6 => Cb::new(layout).finish(),
+
+            iflt1 => Cb::new(layout).r_sp().alu_b().jams().next_command(iflt2),
+            iflt2 => Cb::new(layout).r_sp().alu_b_dec().w_sp().w_mar().read().next_command(iflt3),
+            iflt3 => Cb::new(layout).r_tos().alu_b().w_opc().next_command(iflt4),
+            iflt4 => Cb::new(layout).r_mdr().alu_b().w_tos().next_command(iflt5),
+            iflt5 => Cb::new(layout).r_opc().alu_b().jamn().next_command(F),
+
+            ifeq1 => Cb::new(layout).r_sp().alu_b().jams().next_command(ifeq2),
+            ifeq2 => Cb::new(layout).r_sp().alu_b_dec().w_sp().w_mar().read().next_command(ifeq3),
+            ifeq3 => Cb::new(layout).r_tos().alu_b().w_opc().next_command(ifeq4),
+            ifeq4 => Cb::new(layout).r_mdr().alu_b().w_tos().next_command(ifeq5),
+            ifeq5 => Cb::new(layout).r_opc().alu_b().jamz().next_command(F),
+
+            if_icmpeq1 => Cb::new(layout).r_sp().alu_b_dec().w_sp().w_mar().read().jams().next_command(if_icmpeq2),
+            if_icmpeq2 => Cb::new(layout).r_sp().alu_b_dec().w_sp().w_mar().next_command(if_icmpeq3),
+            if_icmpeq3 => Cb::new(layout).r_mdr().alu_b().w_h().read().next_command(if_icmpeq4),
+            if_icmpeq4 => Cb::new(layout).r_tos().alu_b().w_opc().next_command(if_icmpeq5),
+            if_icmpeq5 => Cb::new(layout).r_mdr().alu_b().w_tos().next_command(if_icmpeq6),
+            if_icmpeq6 => Cb::new(layout).r_opc().alu_sub().jamz().next_command(F),
+
+            T => Cb::new(layout).r_pc().alu_b_dec().w_opc().fetch().next_command(goto2),
+            F => Cb::new(layout).r_pc().alu_b_inc().w_pc().next_command(F2),
+            F2 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(F3),
+            F3 => Cb::new(layout).finish(),
+
+            invokevirtual1 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual2),
+            invokevirtual2 => Cb::new(layout).r_mbru().alu_b().sll8().w_h().next_command(invokevirtual3),
+            invokevirtual3 => Cb::new(layout).r_mbru().alu_or().w_h().next_command(invokevirtual4),
+            invokevirtual4 => Cb::new(layout).r_cpp().alu_sum().w_mar().read().next_command(invokevirtual5),
+            invokevirtual5 => Cb::new(layout).r_pc().alu_b_inc().w_opc().next_command(invokevirtual6),
+            invokevirtual6 => Cb::new(layout).r_mdr().alu_b().w_pc().fetch().next_command(invokevirtual7),
+            invokevirtual7 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual8),
+            invokevirtual8 => Cb::new(layout).r_mbru().alu_b().sll8().w_h().next_command(invokevirtual9),
+            invokevirtual9 => Cb::new(layout).r_mbru().alu_or().w_h().next_command(invokevirtual10),
+            invokevirtual10 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual11),
+            invokevirtual11 => Cb::new(layout).r_sp().alu_sub().w_tos().next_command(invokevirtual12),
+            invokevirtual12 => Cb::new(layout).r_tos().alu_b_inc().w_mar().w_tos().next_command(invokevirtual13),
+            invokevirtual13 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual14),
+            invokevirtual14 => Cb::new(layout).r_mbru().alu_b().sll8().w_h().next_command(invokevirtual15),
+            invokevirtual15 => Cb::new(layout).r_mbru().alu_or().w_h().next_command(invokevirtual16),
+            invokevirtual16 => Cb::new(layout).r_sp().alu_sum_inc().w_mdr().write().next_command(invokevirtual17),
+            invokevirtual17 => Cb::new(layout).r_mdr().alu_b().w_sp().w_mar().jams().next_command(invokevirtual18),
+            invokevirtual18 => Cb::new(layout).r_opc().alu_b().w_mdr().write().next_command(invokevirtual19),
+            invokevirtual19 => Cb::new(layout).r_sp().alu_b_inc().w_sp().w_mar().jams().next_command(invokevirtual20),
+            invokevirtual20 => Cb::new(layout).r_lv().alu_b().w_mdr().write().next_command(invokevirtual21),
+            invokevirtual21 => Cb::new(layout).r_pc().alu_b_inc().w_pc().fetch().next_command(invokevirtual22),
+            invokevirtual22 => Cb::new(layout).r_tos().alu_b().w_lv().finish(),
+
+            ireturn1 => Cb::new(layout).r_lv().alu_b().w_sp().w_mar().read().next_command(ireturn2),
+            ireturn2 => Cb::new(layout).next_command(ireturn3),
+            ireturn3 => Cb::new(layout).r_mdr().alu_b().w_mar().w_lv().read().next_command(ireturn4),
+            ireturn4 => Cb::new(layout).r_lv().alu_b_inc().w_mar().next_command(ireturn5),
+            ireturn5 => Cb::new(layout).r_mdr().alu_b().w_pc().read().fetch().next_command(ireturn6),
+            ireturn6 => Cb::new(layout).r_sp().alu_b().w_mar().next_command(ireturn7),
+            ireturn7 => Cb::new(layout).r_mdr().alu_b().w_lv().next_command(ireturn8),
+            ireturn8 => Cb::new(layout).r_tos().alu_b().w_mdr().write().finish(),
+
+            iadd_underflow | isub_underflow | iand_underflow | ior_underflow | ishl_underflow | ishr_underflow | iushr_underflow
+            | pop_underflow | istore_underflow | iflt_underflow | ifeq_underflow | if_icmpeq_underflow
+            => Cb::new(layout).next_command(stack_underflow1),
+            dup_overflow | bipush_overflow | iload_overflow | invokevirtual_overflow1 | invokevirtual_overflow2
+            => Cb::new(layout).next_command(stack_overflow1),
+            stack_overflow1 => Cb::new(layout).next_command(stack_overflow1),
+            stack_underflow1 => Cb::new(layout).next_command(stack_underflow1),
         }
     }
 }
 
-struct Cb {
-    command: [bool; 36],
+enum Next<'t> {
+    Label(&'t str),
+    /// Address for the false JAM condition and the one `MirLayout::jam_offset` above it for the true condition
+    Pair(&'t str, &'t str),
+    /// JMPC
+    Mbr,
 }
 
-impl Cb {
-    fn new() -> Cb { Cb { command: [false; 36] } }
+struct MicroLine<'t> {
+    label: &'t str,
+    address: Option<usize>,
+    operations: Vec<&'t str>,
+    next: Next<'t>,
+}
 
-    fn finish(&mut self) -> [bool; 36] {
-        self.next_command(Main1);
-        return self.command;
+/**
+ * Microroutine written as text, the first microinstruction is placed at `entry`, e.g. at the opcode.
+ *
+ * Every line is `label: operation operation ...; goto next`, operations are the methods of the builder,
+ *   e.g. `imul1: r_sp alu_b_dec w_sp w_mar read; goto imul2`. `by N` sets the shift amount.
+ *   Without `goto` the routine returns to Main1, `goto (MBR)` sets JMPC.
+ *   `goto a or b` is for JAM conditions: `b` is placed 0x100 above `a` and is taken when the condition holds.
+ * Labels can be placed with `@`, e.g. `done @0x1F0: ...`, others take free addresses of the control store.
+ *   `MicroAsm` names can be used as the targets too, a pair with such a target has to fit its address.
+ *   `used` marks the occupied addresses and is updated.
+ */
+pub fn assemble_microroutine(text: &str, layout: &MirLayout, entry: usize, used: &mut [bool]) -> Vec<(usize, Vec<bool>)> {
+    let lines: Vec<MicroLine> = text.lines()
+        .map(|x| x.split('#').next().unwrap().trim())
+        .filter(|x| !x.is_empty())
+        .map(parse_micro_line)
+        .collect();
+    if lines.is_empty() {
+        panic!("Microroutine is empty");
     }
 
-    fn next_command(&mut self, addr: MicroAsm) -> [bool; 36] {
-        let decoded = fast_decode(addr as i32);
-        for x in 0..9 {
-            self.command[x] = decoded[x];
+    let mut addresses: HashMap<&str, usize> = HashMap::new();
+    for (index, line) in lines.iter().enumerate() {
+        let address = if index == 0 { Some(entry) } else { line.address };
+        if let Some(address) = address {
+            place(line.label, address, &mut addresses, used);
+        }
+    }
+    let defined: Vec<&str> = lines.iter().map(|x| x.label).collect();
+    // Labels of other routines can't be moved
+    let fixed = |label: &str, addresses: &HashMap<&str, usize>| -> Option<usize> {
+        if defined.contains(&label) {
+            addresses.get(label).cloned()
+        } else {
+            Some(micro_asm_address(label))
+        }
+    };
+    for line in &lines {
+        if let Next::Pair(false_label, true_label) = line.next {
+            let offset = layout.jam_offset()
+                .unwrap_or_else(|| panic!("{}-bit NEXT_ADDRESS has no bit for the JAM condition of {}", layout.address_width(), line.label));
+            match (fixed(false_label, &addresses), fixed(true_label, &addresses)) {
+                (Some(f), Some(t)) => if f | offset != t {
+                    panic!("{} should be 0x{:X} above {}", true_label, offset, false_label);
+                },
+                (Some(f), None) => {
+                    if f & offset != 0 {
+                        panic!("{} at 0x{:03X} can't be the false target of a JAM condition", false_label, f);
+                    }
+                    place(true_label, f | offset, &mut addresses, used);
+                }
+                (None, Some(t)) => {
+                    if t & offset == 0 {
+                        panic!("{} at 0x{:03X} can't be the true target of a JAM condition", true_label, t);
+                    }
+                    place(false_label, t & !offset, &mut addresses, used);
+                }
+                (None, None) => {
+                    let free = (0..used.len()).filter(|x| x & offset == 0).find(|x| !used[*x] && !used[x | offset])
+                        .unwrap_or_else(|| panic!("No free pair of addresses for {}", false_label));
+                    place(false_label, free, &mut addresses, used);
+                    place(true_label, free | offset, &mut addresses, used);
+                }
+            }
         }
-        return self.command;
     }
+    // Addresses of the opcodes are taken last, they are the entries of other instructions
+    let opcodes = used.len().min(1 << JAM_BIT);
+    for line in &lines {
+        if !addresses.contains_key(line.label) {
+            let free = (opcodes..used.len()).chain(0..opcodes).find(|x| !used[*x])
+                .unwrap_or_else(|| panic!("No free address for {}", line.label));
+            place(line.label, free, &mut addresses, used);
+        }
+    }
+
+    let target = |label: &str| -> usize {
+        match addresses.get(label) {
+            Some(t) if defined.contains(&label) => *t,
+            _ => micro_asm_address(label),
+        }
+    };
+    lines.iter().map(|line| {
+        let mut cb = Cb::new(layout);
+        let mut operations = line.operations.iter();
+        while let Some(operation) = operations.next() {
+            if *operation == "by" {
+                let amount = operations.next().unwrap_or_else(|| panic!("Shift amount of {} is missing", line.label));
+                cb.by(number(amount) as usize);
+            } else {
+                cb.operation(operation);
+            }
+        }
+        let word = match line.next {
+            Next::Label(label) | Next::Pair(label, _) => cb.set("addr", target(label)).get(),
+            Next::Mbr => cb.jmpc().get(),
+        };
+        (addresses[line.label], word)
+    }).collect()
+}
 
-    fn next_command_wide_jump(&mut self) -> [bool; 36] {
-        self.command[8] = true;
-        return self.command;
+fn micro_asm_address(label: &str) -> usize {
+    MicroAsm::iter().find(|x| format!("{:?}", x) == label).map(|x| x as usize)
+        .unwrap_or_else(|| panic!("Unknown microinstruction {}", label))
+}
+
+fn place<'t>(label: &'t str, address: usize, addresses: &mut HashMap<&'t str, usize>, used: &mut [bool]) {
+    if address >= used.len() || used[address] {
+        panic!("Address 0x{:03X} of {} is already used", address, label);
     }
+    used[address] = true;
+    addresses.insert(label, address);
+}
 
-    fn jmpc(&mut self) -> &mut Cb { self.bit(9) }
-    fn jamn(&mut self) -> &mut Cb { self.bit(10) }
-    fn jamz(&mut self) -> &mut Cb { self.bit(11) }
-    fn sll8(&mut self) -> &mut Cb { self.bit(12) }
-    fn sra1(&mut self) -> &mut Cb { self.bit(13) }
+fn parse_micro_line(line: &str) -> MicroLine<'_> {
+    let (head, body) = line.split_once(':').unwrap_or_else(|| panic!("Microinstruction without a label: {}", line));
+    let mut head = head.split_whitespace();
+    let label = head.next().unwrap_or_else(|| panic!("Microinstruction without a label: {}", line));
+    let address = head.next().map(|x| {
+        let address = x.strip_prefix('@').unwrap_or_else(|| panic!("Unexpected address {} of {}", x, label));
+        number(address) as usize
+    });
+
+    let (operations, next) = match body.split_once(';') {
+        Some((operations, next)) => (operations, next.trim()),
+        None => (body, "goto Main1"),
+    };
+    let target: Vec<&str> = next.strip_prefix("goto").unwrap_or_else(|| panic!("Unexpected next address of {}: {}", label, next))
+        .split_whitespace().collect();
+    let next = match target.as_slice() {
+        ["(MBR)"] => Next::Mbr,
+        [target] => Next::Label(target),
+        [false_target, "or", true_target] => Next::Pair(false_target, true_target),
+        _ => panic!("Unexpected next address of {}: {}", label, next),
+    };
+    MicroLine { label, address, operations: operations.split_whitespace().collect(), next }
+}
+
+/// Builder of the microinstruction, bits are placed according to the layout
+struct Cb<'a> {
+    layout: &'a MirLayout,
+    command: Vec<bool>,
+}
+
+impl<'a> Cb<'a> {
+    fn new(layout: &'a MirLayout) -> Cb<'a> { Cb { layout, command: vec![false; layout.width()] } }
+
+    fn finish(&mut self) -> Vec<bool> {
+        self.next_command(Main1)
+    }
+
+    fn next_command(&mut self, addr: MicroAsm) -> Vec<bool> {
+        self.set("addr", addr as usize);
+        return self.get();
+    }
+
+    fn next_command_wide_jump(&mut self) -> Vec<bool> {
+        self.bit("addr", 8);
+        return self.get();
+    }
+
+    fn jmpc(&mut self) -> &mut Cb<'a> { self.bit("jmpc", 0) }
+    fn jamn(&mut self) -> &mut Cb<'a> { self.bit("jamn", 0) }
+    fn jamz(&mut self) -> &mut Cb<'a> { self.bit("jamz", 0) }
+    fn jamc(&mut self) -> &mut Cb<'a> { self.bit("jamc", 0) }
+    fn jamv(&mut self) -> &mut Cb<'a> { self.bit("jamv", 0) }
+    fn jamr(&mut self) -> &mut Cb<'a> { self.bit("jamr", 0) }
+    fn jams(&mut self) -> &mut Cb<'a> { self.bit("jams", 0) }
+    fn sll8(&mut self) -> &mut Cb<'a> { self.bit("sll8", 0) }
+    fn sra1(&mut self) -> &mut Cb<'a> { self.bit("sra1", 0) }
+    fn srl1(&mut self) -> &mut Cb<'a> { self.bit("srl1", 0) }
+
+    // Barrel shifter, the amount is set with `by` or `shift_by_h`
+    fn sll(&mut self) -> &mut Cb<'a> { self.set("shift_op", 1) }
+    fn srl(&mut self) -> &mut Cb<'a> { self.set("shift_op", 2) }
+    fn sra(&mut self) -> &mut Cb<'a> { self.set("shift_op", 3) }
+    fn rol(&mut self) -> &mut Cb<'a> { self.set("shift_op", 4) }
+    fn ror(&mut self) -> &mut Cb<'a> { self.set("shift_op", 5) }
+    fn shift_by_h(&mut self) -> &mut Cb<'a> { self.bit("shift_by_h", 0) }
+    fn by(&mut self, amount: usize) -> &mut Cb<'a> { self.set("shift_amount", amount) }
 
     // ALU
-    fn f0(&mut self) -> &mut Cb { self.bit(14) }
-    fn f1(&mut self) -> &mut Cb { self.bit(15) }
-    fn ena(&mut self) -> &mut Cb { self.bit(16) }
-    fn enb(&mut self) -> &mut Cb { self.bit(17) }
-    fn inva(&mut self) -> &mut Cb { self.bit(18) }
-    fn inc(&mut self) -> &mut Cb { self.bit(19) }
+    fn f0(&mut self) -> &mut Cb<'a> { self.bit("alu", 0) }
+    fn f1(&mut self) -> &mut Cb<'a> { self.bit("alu", 1) }
+    fn ena(&mut self) -> &mut Cb<'a> { self.bit("alu", 2) }
+    fn enb(&mut self) -> &mut Cb<'a> { self.bit("alu", 3) }
+    fn inva(&mut self) -> &mut Cb<'a> { self.bit("alu", 4) }
+    fn inc(&mut self) -> &mut Cb<'a> { self.bit("alu", 5) }
 
     // ALU helper
-    fn alu_b_dec(&mut self) -> &mut Cb { self.f0().f1().enb().inva() }
-    fn alu_b_inc(&mut self) -> &mut Cb { self.f0().f1().enb().inc() }
-    fn alu_sum(&mut self) -> &mut Cb { self.f0().f1().ena().enb() }
-    fn alu_sum_inc(&mut self) -> &mut Cb { self.f0().f1().ena().enb().inc() }
-    fn alu_sub(&mut self) -> &mut Cb { self.f0().f1().ena().enb().inva().inc() }
-    fn alu_and(&mut self) -> &mut Cb { self.ena().enb() }
-    fn alu_or(&mut self) -> &mut Cb { self.f1().ena().enb() }
-    fn alu_b(&mut self) -> &mut Cb { self.f1().enb() }
-    fn alu_a(&mut self) -> &mut Cb { self.f1().ena() }
-
-    fn w_h(&mut self) -> &mut Cb { self.bit(20) }
-    fn w_opc(&mut self) -> &mut Cb { self.bit(21) }
-    fn w_tos(&mut self) -> &mut Cb { self.bit(22) }
-    fn w_cpp(&mut self) -> &mut Cb { self.bit(23) }
-    fn w_lv(&mut self) -> &mut Cb { self.bit(24) }
-    fn w_sp(&mut self) -> &mut Cb { self.bit(25) }
-    fn w_pc(&mut self) -> &mut Cb { self.bit(26) }
-    fn w_mdr(&mut self) -> &mut Cb { self.bit(27) }
-    fn w_mar(&mut self) -> &mut Cb { self.bit(28) }
-    fn write(&mut self) -> &mut Cb { self.bit(29) }
-    fn read(&mut self) -> &mut Cb { self.bit(30) }
-    fn fetch(&mut self) -> &mut Cb { self.bit(31) }
-
-    fn r_mdr(&mut self) -> &mut Cb { return self; }
-    fn r_pc(&mut self) -> &mut Cb { self.bit(32) }
-    fn r_mbr(&mut self) -> &mut Cb { self.bit(33) }
-    fn r_mbru(&mut self) -> &mut Cb { self.bit(32).bit(33) }
-    fn r_sp(&mut self) -> &mut Cb { self.bit(34) }
-    fn r_lv(&mut self) -> &mut Cb { self.bit(32).bit(34) }
-    fn r_cpp(&mut self) -> &mut Cb { self.bit(33).bit(34) }
-    fn r_tos(&mut self) -> &mut Cb { self.bit(32).bit(33).bit(34) }
-    fn r_opc(&mut self) -> &mut Cb { self.bit(35) }
-
-    fn get(&self) -> [bool; 36] { self.command }
-
-    fn bit(&mut self, i: usize) -> &mut Cb {
-        self.command[i] = true;
+    fn alu_b_dec(&mut self) -> &mut Cb<'a> { self.f0().f1().enb().inva() }
+    fn alu_b_inc(&mut self) -> &mut Cb<'a> { self.f0().f1().enb().inc() }
+    fn alu_sum(&mut self) -> &mut Cb<'a> { self.f0().f1().ena().enb() }
+    fn alu_sum_inc(&mut self) -> &mut Cb<'a> { self.f0().f1().ena().enb().inc() }
+    fn alu_sub(&mut self) -> &mut Cb<'a> { self.f0().f1().ena().enb().inva().inc() }
+    fn alu_and(&mut self) -> &mut Cb<'a> { self.ena().enb() }
+    fn alu_or(&mut self) -> &mut Cb<'a> { self.f1().ena().enb() }
+    fn alu_b(&mut self) -> &mut Cb<'a> { self.f1().enb() }
+    fn alu_a(&mut self) -> &mut Cb<'a> { self.f1().ena() }
+
+    fn w_h(&mut self) -> &mut Cb<'a> { self.bit("c", 0) }
+    fn w_opc(&mut self) -> &mut Cb<'a> { self.bit("c", 1) }
+    fn w_tos(&mut self) -> &mut Cb<'a> { self.bit("c", 2) }
+    fn w_cpp(&mut self) -> &mut Cb<'a> { self.bit("c", 3) }
+    fn w_lv(&mut self) -> &mut Cb<'a> { self.bit("c", 4) }
+    fn w_sp(&mut self) -> &mut Cb<'a> { self.bit("c", 5) }
+    fn w_pc(&mut self) -> &mut Cb<'a> { self.bit("c", 6) }
+    fn w_mdr(&mut self) -> &mut Cb<'a> { self.bit("c", 7) }
+    fn w_mar(&mut self) -> &mut Cb<'a> { self.bit("c", 8) }
+    fn write(&mut self) -> &mut Cb<'a> { self.bit("write", 0) }
+    fn read(&mut self) -> &mut Cb<'a> { self.bit("read", 0) }
+    fn fetch(&mut self) -> &mut Cb<'a> { self.bit("fetch", 0) }
+
+    fn r_mdr(&mut self) -> &mut Cb<'a> { self.set("b", 0) }
+    fn r_pc(&mut self) -> &mut Cb<'a> { self.set("b", 1) }
+    fn r_mbr(&mut self) -> &mut Cb<'a> { self.set("b", 2) }
+    fn r_mbru(&mut self) -> &mut Cb<'a> { self.set("b", 3) }
+    fn r_sp(&mut self) -> &mut Cb<'a> { self.set("b", 4) }
+    fn r_lv(&mut self) -> &mut Cb<'a> { self.set("b", 5) }
+    fn r_cpp(&mut self) -> &mut Cb<'a> { self.set("b", 6) }
+    fn r_tos(&mut self) -> &mut Cb<'a> { self.set("b", 7) }
+    fn r_opc(&mut self) -> &mut Cb<'a> { self.set("b", 8) }
+
+    fn get(&self) -> Vec<bool> { self.command.clone() }
+
+    /// Operation by the name of the method
+    fn operation(&mut self, name: &str) -> &mut Cb<'a> {
+        match name {
+            "jmpc" => self.jmpc(),
+            "jamn" => self.jamn(),
+            "jamz" => self.jamz(),
+            "jamc" => self.jamc(),
+            "jamv" => self.jamv(),
+            "jamr" => self.jamr(),
+            "jams" => self.jams(),
+            "sll8" => self.sll8(),
+            "sra1" => self.sra1(),
+            "srl1" => self.srl1(),
+            "sll" => self.sll(),
+            "srl" => self.srl(),
+            "sra" => self.sra(),
+            "rol" => self.rol(),
+            "ror" => self.ror(),
+            "shift_by_h" => self.shift_by_h(),
+            "f0" => self.f0(),
+            "f1" => self.f1(),
+            "ena" => self.ena(),
+            "enb" => self.enb(),
+            "inva" => self.inva(),
+            "inc" => self.inc(),
+            "alu_b_dec" => self.alu_b_dec(),
+            "alu_b_inc" => self.alu_b_inc(),
+            "alu_sum" => self.alu_sum(),
+            "alu_sum_inc" => self.alu_sum_inc(),
+            "alu_sub" => self.alu_sub(),
+            "alu_and" => self.alu_and(),
+            "alu_or" => self.alu_or(),
+            "alu_b" => self.alu_b(),
+            "alu_a" => self.alu_a(),
+            "w_h" => self.w_h(),
+            "w_opc" => self.w_opc(),
+            "w_tos" => self.w_tos(),
+            "w_cpp" => self.w_cpp(),
+            "w_lv" => self.w_lv(),
+            "w_sp" => self.w_sp(),
+            "w_pc" => self.w_pc(),
+            "w_mdr" => self.w_mdr(),
+            "w_mar" => self.w_mar(),
+            "write" => self.write(),
+            "read" => self.read(),
+            "fetch" => self.fetch(),
+            "r_mdr" => self.r_mdr(),
+            "r_pc" => self.r_pc(),
+            "r_mbr" => self.r_mbr(),
+            "r_mbru" => self.r_mbru(),
+            "r_sp" => self.r_sp(),
+            "r_lv" => self.r_lv(),
+            "r_cpp" => self.r_cpp(),
+            "r_tos" => self.r_tos(),
+            "r_opc" => self.r_opc(),
+            _ => panic!("Unknown micro operation {}", name),
+        }
+    }
+
+    fn bit(&mut self, name: &str, index: usize) -> &mut Cb<'a> {
+        let field = self.layout.get(name);
+        if index >= field.width {
+            panic!("Field {} has only {} bits", name, field.width);
+        }
+        self.command[field.position + index] = true;
+        return self;
+    }
+
+    /// Replaces the value of the field
+    fn set(&mut self, name: &str, value: usize) -> &mut Cb<'a> {
+        let field = self.layout.get(name).clone();
+        if value >> field.width != 0 {
+            panic!("Value {} doesn't fit into the field {}", value, name);
+        }
+        for i in 0..field.width {
+            self.command[field.position + i] = value >> i & 1 == 1;
+        }
         return self;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use crate::{create_processor_with_control_memory, make_control_memory, PROGRAM_START};
+    use crate::alu::AluFlags;
+    use crate::electrical_rules::{RuleViolation, Violation};
+    use crate::main_memory::{encode, fast_decode, fast_encode, Device, MainMemory};
+    use crate::memory::{address_bits, ControlMemory, MpcRegister, Register};
+    use crate::parser::parse;
+    use crate::processor::Mic1;
+    use crate::processor_elements::MirFields;
+    use crate::shifter::{barrel_word, srl1_word};
+
+    use super::*;
+
+    fn next_address(command: Vec<bool>, c: bool, v: bool) -> i32 {
+        next_address_when_ready(command, c, v, false)
+    }
+
+    fn next_address_when_ready(command: Vec<bool>, c: bool, v: bool, ready: bool) -> i32 {
+        let layout = MirLayout::standard();
+        let next = MirFields::new(&layout, command).next_address(&[false; 32], &AluFlags { n: false, z: false, c, v }, ready, false);
+        let mut address = 0;
+        for i in 0..9 {
+            address |= (next[i] as i32) << i;
+        }
+        address
+    }
+
+    #[test]
+    fn jam_on_carry() {
+        let layout = &MirLayout::standard();
+        let command = Cb::new(layout).r_opc().alu_sub().jamc().next_command(F);
+        assert_eq!(F as i32, next_address(command.clone(), false, true));
+        assert_eq!(F as i32 | 0x100, next_address(command, true, false));
+    }
+
+    fn shift(command: Vec<bool>, data: i32, h: i32) -> i32 {
+        let layout = MirLayout::standard();
+        let fields = MirFields::new(&layout, command);
+        let shifted = srl1_word(fast_decode(data), fields.srl1());
+        fast_encode(&barrel_word(shifted, fields.shift_op(), fields.shift_amount(&fast_decode(h))))
+    }
+
+    #[test]
+    fn shifter_fields() {
+        let layout = &MirLayout::standard();
+        assert_eq!(0x7FFF_FFFF, shift(Cb::new(layout).srl1().get(), -1, 0));
+        assert_eq!(0x0000_0011, shift(Cb::new(layout).rol().by(4).get(), 0x1000_0001, 0));
+        assert_eq!(0x1000_0001, shift(Cb::new(layout).ror().by(4).get(), 0x0000_0011, 0));
+        assert_eq!(-8, shift(Cb::new(layout).sll().by(31).shift_by_h().get(), -1, 3));
+        assert_eq!(-1, shift(Cb::new(layout).sra().by(3).get(), -8, 0));
+    }
+
+    #[test]
+    fn fields_follow_the_layout() {
+        let layout = &MirLayout::standard().resize("addr", 10).field("spare", 2);
+        let command = Cb::new(layout).r_tos().alu_b().w_h().next_command(T);
+        assert_eq!(layout.width(), command.len());
+
+        let fields = MirFields::new(layout, command);
+        assert_eq!(T as usize, fields.addr().iter().enumerate().map(|(i, x)| (*x as usize) << i).sum());
+        assert_eq!([true, true, true, false], fields.b_bus_controls());
+        assert!(fields.c_bus_controls().h());
+        assert_eq!(vec![false, false], fields.field("spare"));
+    }
+
+    #[test]
+    fn narrow_next_address() {
+        let layout = MirLayout::standard().resize("addr", 6);
+        let command = Cb::new(&layout).r_tos().alu_b_inc().w_tos().jmpc().jamz().get();
+        let mut control_memory = ControlMemory::new(layout.clone());
+        for address in 0..layout.control_store_size() {
+            control_memory.write_data(&command, address);
+        }
+
+        let mut mpc = MpcRegister::new(layout.address_width());
+        mpc.update(&address_bits(5, layout.address_width()), true);
+        let mut mic1 = Mic1::init(MainMemory::initialize(), control_memory, Register::new(), Register::new(), Register::new(), Register::new(), mpc);
+        for _ in 0..3 {
+            mic1.execute_command();
+        }
+        assert_eq!(3, fast_encode(&mic1.tos.get()));
+        assert_eq!(0, mic1.mpc_address());
+    }
+
+    #[test]
+    fn pair_with_micro_asm_target() {
+        let layout = MirLayout::standard();
+        let mut used = vec![false; layout.control_store_size()];
+        let words = assemble_microroutine("test1: r_tos alu_b jamz; goto test2 or T\ntest2: r_tos alu_b_inc w_tos", &layout, 0x70, &mut used);
+        assert_eq!(vec![0x70, F as usize], words.iter().map(|x| x.0).collect::<Vec<_>>());
+        assert!(!used[T as usize]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Main1 at 0x001 can't be the true target of a JAM condition")]
+    fn pair_with_main1() {
+        let layout = MirLayout::standard();
+        let mut used = vec![false; layout.control_store_size()];
+        assemble_microroutine("test1: r_tos alu_b jamz; goto test2 or Main1\ntest2: r_tos alu_b_inc w_tos", &layout, 0x70, &mut used);
+    }
+
+    #[test]
+    fn pair_in_wider_address() {
+        let layout = MirLayout::standard().resize("addr", 10);
+        // Pairs below 0x100 are taken
+        let mut used = vec![false; layout.control_store_size()];
+        used[..0x100].fill(true);
+        let words = assemble_microroutine("test1: r_tos alu_b jamz; goto test2 or test3\ntest2: f1 w_tos\ntest3: r_tos alu_b_inc w_tos", &layout, 0x270, &mut used);
+        assert_eq!(vec![0x270, 0x200, 0x300], words.iter().map(|x| x.0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic(expected = "8-bit NEXT_ADDRESS has no bit for the JAM condition of test1")]
+    fn pair_without_jam_bit() {
+        let layout = MirLayout::standard().resize("addr", 8);
+        let mut used = vec![false; layout.control_store_size()];
+        assemble_microroutine("test1: r_tos alu_b jamz; goto test2 or test3\ntest2: f1 w_tos\ntest3: r_tos alu_b_inc w_tos", &layout, 0x70, &mut used);
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no field jamc")]
+    fn missing_field() {
+        Cb::new(&MirLayout::new().field("addr", 9)).jamc();
+    }
+
+    #[test]
+    fn jam_on_overflow() {
+        let layout = &MirLayout::standard();
+        let command = Cb::new(layout).r_opc().alu_sum().jamv().next_command(F);
+        assert_eq!(F as i32, next_address(command.clone(), true, false));
+        assert_eq!(F as i32 | 0x100, next_address(command, false, true));
+    }
+
+    #[test]
+    fn jam_on_ready() {
+        let layout = &MirLayout::standard();
+        let command = Cb::new(layout).jamr().next_command(F);
+        assert_eq!(F as i32, next_address_when_ready(command.clone(), false, false, false));
+        assert_eq!(F as i32 | 0x100, next_address_when_ready(command, false, false, true));
+    }
+
+    /// Word of the stack that answers after three cycles
+    struct Slow(i64);
+
+    impl Device for Slow {
+        fn read(&mut self, _offset: usize) -> i64 { self.0 }
+        fn write(&mut self, _offset: usize, _value: i64) {}
+        fn read_latency(&self) -> usize { 3 }
+    }
+
+    /// Adds 40 from the slow word to 2 on the top of the stack
+    fn slow_iadd(wait: bool) -> Mic1 {
+        let layout = MirLayout::standard();
+        let mut control_memory = make_control_memory();
+        if wait {
+            // iadd2 goes to a free address that waits for the memory, then continues with iadd3
+            let used: Vec<usize> = MicroAsm::iter().map(|x| x as usize).collect();
+            let address = (2..0x100).find(|x| !used.contains(x) && !used.contains(&(x | 0x100))).unwrap();
+            control_memory.write_data(&Cb::new(&layout).r_tos().alu_b().w_h().set("addr", address).get(), iadd2 as usize);
+            control_memory.write_data(&Cb::new(&layout).jamr().set("addr", address).get(), address);
+            control_memory.write_data(&iadd3.command(&layout), address | 0x100);
+        }
+
+        let commands = parse("IADD");
+        let mut mic1 = create_processor_with_control_memory(&commands, vec![1, 2], [0; 10], control_memory);
+        let sp = encode(&mic1.sp.get()) as usize;
+        mic1.main_memory.attach(sp - 1, 1, Box::new(Slow(40)));
+        mic1.run(commands.len() + 1, PROGRAM_START);
+        mic1
+    }
+
+    #[test]
+    fn data_not_ready() {
+        let mic1 = slow_iadd(false);
+        assert_ne!(42, encode(&mic1.tos.get()));
+        assert_eq!(Violation::DataNotReady("MDR"), mic1.violations[0].violation);
+        assert_eq!(iadd3 as usize, mic1.violations[0].mpc);
+    }
+
+    #[test]
+    fn wait_for_memory() {
+        let fast = slow_iadd(false).cycles;
+        let mic1 = slow_iadd(true);
+        assert_eq!(42, encode(&mic1.tos.get()));
+        assert_eq!(Vec::<RuleViolation>::new(), mic1.violations);
+        assert!(mic1.cycles > fast);
+    }
+
+    #[test]
+    fn fault_handler() {
+        // The handler counts down OPC forever
+        let layout = MirLayout::standard();
+        let used: Vec<usize> = MicroAsm::iter().map(|x| x as usize).collect();
+        let handler = (2..0x200).find(|x| !used.contains(x)).unwrap();
+        let mut control_memory = make_control_memory();
+        control_memory.write_data(&Cb::new(&layout).r_opc().alu_b_dec().w_opc().set("addr", handler).get(), handler);
+
+        let commands = parse("ILOAD 0x80");
+        let mut mic1 = create_processor_with_control_memory(&commands, vec![1, 2], [0; 10], control_memory).with_fault_handler(handler);
+        mic1.run_n_times(20);
+
+        assert_eq!(iload2 as usize, mic1.fault.unwrap().mpc);
+        assert!(!mic1.halted());
+        assert_eq!(handler, mic1.mpc_address());
+        assert!(encode(&mic1.opc.get()) < -10);
+    }
+}
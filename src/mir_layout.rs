@@ -0,0 +1,165 @@
+/// Bit of NEXT_ADDRESS that is OR-ed with the JAM conditions, the bits below it are OR-ed with MBR on JMPC
+pub const JAM_BIT: usize = 8;
+
+/// Named group of bits of the microinstruction, bit `position` is the lowest bit of the field
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirField {
+    pub name: String,
+    pub position: usize,
+    pub width: usize,
+}
+
+/**
+ * Encoding of the microinstruction: names, positions and widths of its fields.
+ *
+ * The layout defines the width of MIR, the width of MPC (the `addr` field) and so the depth
+ *   of the control store. Datapath controls that are missing in the layout are always off,
+ *   e.g. a layout without the barrel shifter fields runs microprograms that don't use it.
+ *
+ * Fields that are read by the datapath:
+ *   `addr`, `jmpc`, `jamn`, `jamz`, `jamc`, `jamv`, `jamr` (jump if the memory is ready),
+ *   `jams` (jump if the C bus value is out of the stack limits),
+ *   `alu` (F0, F1, ENA, ENB, INVA, INC), `c` (H, OPC, TOS, CPP, LV, SP, PC, MDR, MAR),
+ *   `write`, `read`, `fetch`, `b` (B bus source code),
+ *   `sll8`, `sra1`, `srl1`, `shift_op`, `shift_amount`, `shift_by_h`.
+ * Other fields are kept in the control store but do nothing.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirLayout {
+    fields: Vec<MirField>,
+}
+
+impl MirLayout {
+    pub fn new() -> MirLayout { MirLayout { fields: Vec::new() } }
+
+    /// Layout used by the microprogram from `microasm.rs`
+    pub fn standard() -> MirLayout {
+        MirLayout::new()
+            .field("addr", 9)
+            .field("jmpc", 1)
+            .field("jamn", 1)
+            .field("jamz", 1)
+            .field("sll8", 1)
+            .field("sra1", 1)
+            .field("alu", 6)
+            .field("c", 9)
+            .field("write", 1)
+            .field("read", 1)
+            .field("fetch", 1)
+            .field("b", 4)
+            .field("jamc", 1)
+            .field("jamv", 1)
+            .field("srl1", 1)
+            .field("shift_op", 3)
+            .field("shift_amount", 5)
+            .field("shift_by_h", 1)
+            .field("jamr", 1)
+            .field("jams", 1)
+    }
+
+    /// Adds the field right after the highest bit of the layout
+    pub fn field(self, name: &str, width: usize) -> MirLayout {
+        let position = self.width();
+        self.field_at(name, position, width)
+    }
+
+    pub fn field_at(mut self, name: &str, position: usize, width: usize) -> MirLayout {
+        if width == 0 {
+            panic!("Field {} is empty", name);
+        }
+        if self.find(name).is_some() {
+            panic!("Field {} is already defined", name);
+        }
+        for field in self.fields.iter() {
+            if position < field.position + field.width && field.position < position + width {
+                panic!("Field {} overlaps with {}", name, field.name);
+            }
+        }
+        self.fields.push(MirField { name: name.to_string(), position, width });
+        self
+    }
+
+    /// Changes the width of the field, fields above it are moved
+    pub fn resize(mut self, name: &str, width: usize) -> MirLayout {
+        let field = self.get(name).clone();
+        let end = field.position + field.width;
+        for other in self.fields.iter_mut() {
+            if other.name == field.name {
+                other.width = width;
+            } else if other.position >= end {
+                other.position = other.position + width - field.width;
+            }
+        }
+        self
+    }
+
+    pub fn fields(&self) -> &[MirField] { &self.fields }
+
+    pub fn find(&self, name: &str) -> Option<&MirField> {
+        self.fields.iter().find(|x| x.name == name)
+    }
+
+    pub fn get(&self, name: &str) -> &MirField {
+        self.find(name).unwrap_or_else(|| panic!("There is no field {} in the microinstruction", name))
+    }
+
+    /// Amount of bits in the microinstruction
+    pub fn width(&self) -> usize {
+        self.fields.iter().map(|x| x.position + x.width).max().unwrap_or(0)
+    }
+
+    /// Width of MPC
+    pub fn address_width(&self) -> usize { self.get("addr").width }
+
+    /// Amount of microinstructions in the control store
+    pub fn control_store_size(&self) -> usize { 1 << self.address_width() }
+
+    /// Distance between the false and the true target of a JAM condition, `None` if NEXT_ADDRESS has no JAM bit
+    pub fn jam_offset(&self) -> Option<usize> {
+        if self.address_width() > JAM_BIT { Some(1 << JAM_BIT) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_layout() {
+        let layout = MirLayout::standard();
+        assert_eq!(50, layout.width());
+        assert_eq!(512, layout.control_store_size());
+        assert_eq!(&MirField { name: "alu".to_string(), position: 14, width: 6 }, layout.get("alu"));
+        assert_eq!(32, layout.get("b").position);
+        assert_eq!(47, layout.get("shift_by_h").position);
+    }
+
+    #[test]
+    fn wider_address() {
+        let layout = MirLayout::standard().resize("addr", 11);
+        assert_eq!(52, layout.width());
+        assert_eq!(2048, layout.control_store_size());
+        assert_eq!(11, layout.get("jmpc").position);
+        assert_eq!(34, layout.get("b").position);
+    }
+
+    #[test]
+    fn explicit_positions() {
+        let layout = MirLayout::new().field_at("addr", 4, 8).field_at("jmpc", 0, 1).field("b", 5);
+        assert_eq!(17, layout.width());
+        assert_eq!(256, layout.control_store_size());
+        assert_eq!(12, layout.get("b").position);
+    }
+
+    #[test]
+    #[should_panic(expected = "Field jamz overlaps with addr")]
+    fn overlapping_fields() {
+        MirLayout::new().field("addr", 9).field_at("jamz", 8, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no field addr")]
+    fn no_address() {
+        MirLayout::new().field("jmpc", 1).control_store_size();
+    }
+}
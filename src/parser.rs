@@ -1,28 +1,119 @@
-use crate::asm::IjvmCommand;
+use crate::asm::IjvmCommand::WIDE;
+use crate::asm::Isa;
 
+/**
+ * Bytes of the program with an instruction or raw bytes on every line.
+ *
+ * If the amount of operands after the mnemonic matches the ISA table, every operand is encoded with its size,
+ *   e.g. `GOTO -3` gives three bytes. Otherwise every number is a single byte, e.g. `GOTO 0xFF 0xFD`.
+ * Numbers are decimal, hex with `0x`, binary with `0b` or octal with a leading zero.
+ */
 pub fn parse(program: &str) -> Vec<i32> {
+    parse_with_isa(program, &Isa::standard())
+}
+
+pub fn parse_with_isa(program: &str, isa: &Isa) -> Vec<i32> {
     let mut res = Vec::new();
+    let mut wide = false;
 
     let program_lines = program.split("\n");
     let program_lines = program_lines.filter(|x| !x.is_empty());
     for line in program_lines {
-        let mut commands = line.split(" ").filter(|x| !x.is_empty());
+        let tokens: Vec<&str> = line.split(" ").filter(|x| !x.is_empty()).collect();
 
-        for command in commands {
-            let command_or_address = match IjvmCommand::parse(command) {
-                Some(t) => t as i32,
-                None => i32::from_str_radix(command.trim_start_matches("0x"), 16).unwrap()
+        let mut position = 0;
+        while position < tokens.len() {
+            let instruction = match isa.by_name(tokens[position]) {
+                Some(t) => t,
+                None => {
+                    res.push(byte(tokens[position]));
+                    position += 1;
+                    continue;
+                }
             };
-            res.push(command_or_address);
+            res.push(instruction.opcode);
+            let operands: Vec<&str> = tokens[position + 1..].iter().take_while(|x| isa.by_name(x).is_none()).cloned().collect();
+            if operands.len() == instruction.operands.len() {
+                for (kind, operand) in instruction.operands.iter().zip(&operands) {
+                    let value = number(operand);
+                    let bytes = kind.encode(value, wide)
+                        .unwrap_or_else(|| panic!("Operand {} of {} is out of range", operand, instruction.name));
+                    res.extend(bytes);
+                }
+            } else {
+                res.extend(operands.iter().map(|x| byte(x)));
+            }
+            wide = instruction.opcode == WIDE as i32;
+            position += 1 + operands.len();
         }
     }
 
     res
 }
 
+/// Line of every byte of the program, starting from 1
+pub fn source_lines(program: &str) -> Vec<usize> {
+    let isa = Isa::standard();
+    let mut res = Vec::new();
+    let mut wide = false;
+    for (number, line) in program.split("\n").enumerate() {
+        // WIDE changes the size of the operands on the next line
+        let bytes = if wide { parse_with_isa(&format!("WIDE\n{}", line), &isa).len() - 1 } else { parse_with_isa(line, &isa).len() };
+        res.extend((0..bytes).map(|_| number + 1));
+        wide = line.split(" ").filter(|x| !x.is_empty()).last() == Some("WIDE");
+    }
+    res
+}
+
+/// Decimal, hex, binary or octal number with an optional minus, or a character literal like `'A'` or `'\n'`
+pub fn number(text: &str) -> i64 {
+    if let Some(literal) = text.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')) {
+        return character(literal).unwrap_or_else(|| panic!("Unexpected character literal: {}", text));
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(t) => (true, t),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse()
+    };
+    let value = value.unwrap_or_else(|_| panic!("Unexpected number: {}", text));
+    if negative { -value } else { value }
+}
+
+fn character(literal: &str) -> Option<i64> {
+    let mut chars = literal.chars();
+    let value = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            x @ ('\\' | '\'' | '"') => x,
+            _ => return None,
+        },
+        x => x,
+    };
+    if chars.next().is_some() { None } else { Some(value as i64) }
+}
+
+fn byte(text: &str) -> i32 {
+    let value = number(text);
+    if !(-0x80..=0xFF).contains(&value) {
+        panic!("Byte {} is out of range", text);
+    }
+    (value & 0xFF) as i32
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::asm::IjvmCommand::{IADD, ILOAD};
+    use crate::asm::IjvmCommand::{BIPUSH, GOTO, IADD, IINC, ILOAD, LDC_W};
 
     use super::*;
 
@@ -57,4 +148,29 @@ mod tests {
         assert_eq!(0x02, *res.get(3).unwrap());
         assert_eq!(IADD as i32, *res.get(4).unwrap());
     }
+
+    #[test]
+    fn lines_of_bytes() {
+        assert_eq!(vec![1, 1, 3, 3, 3], source_lines("ILOAD 0x01\n\nGOTO 0x00 0x03"));
+        assert_eq!(vec![1, 2, 2, 2], source_lines("WIDE\nILOAD 1"));
+    }
+
+    #[test]
+    fn operands_by_kind() {
+        assert_eq!(vec![BIPUSH as i32, 0xFD, GOTO as i32, 0xFF, 0xFA], parse("BIPUSH -3\nGOTO -6"));
+        assert_eq!(vec![IINC as i32, 10, 0x7F, LDC_W as i32, 0x00, 0x08], parse("IINC 012 127\nLDC_W 0b1000"));
+        assert_eq!(vec![WIDE as i32, ILOAD as i32, 0x01, 0x2C], parse("WIDE ILOAD 300"));
+    }
+
+    #[test]
+    fn characters() {
+        assert_eq!(vec![BIPUSH as i32, 0x41, BIPUSH as i32, 0x0A], parse("BIPUSH 'A'\nBIPUSH '\\n'"));
+        assert_eq!(0x27, number("'\\''"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Operand 256 of IINC is out of range")]
+    fn operand_out_of_range() {
+        parse("IINC 1 256");
+    }
 }
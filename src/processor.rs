@@ -2,80 +2,178 @@ use std::collections::VecDeque;
 
 use strum::IntoEnumIterator;
 
-use crate::alu::{alu_32, AluControl};
+use crate::alu::{alu, Adder, AluControl, AluFlags};
+use crate::arch_state::ArchState;
 use crate::asm::IjvmCommand::NOP;
-use crate::bus::{Bus32, Bus9};
+use crate::bus::{AddressBus, Bus};
 use crate::decoders::decoder_4x9;
-use crate::main_memory::{fast_decode, fast_encode, MainMemory, ReadState};
+use crate::electrical_rules::{check_cycle, check_data_ready, RuleChecking, RuleViolation};
+use crate::fault_injection::{HardwareFault, Line, RegisterName, Signal};
+use crate::main_memory::{decode, encode, fast_encode, MainMemory, ReadState, MEMORY_SIZE};
 use crate::main_memory::ReadState::{NoRead, ReadInitialized, ReadInProgress};
-use crate::memory::{Memory512x36, Register32, Register36, Register9};
+use crate::memory::{address_bits, ControlMemory, MirRegister, MpcRegister, Register};
 use crate::microasm::MicroAsm;
-use crate::microasm::MicroAsm::{invokevirtual14, invokevirtual15, nop1, wide2, wide_iload1, Main1};
-use crate::processor_elements::{BBusControls, CBusControls};
-use crate::shifter::{sll8, sra1};
+use crate::microasm::MicroAsm::{invokevirtual14, invokevirtual15, nop1, stack_overflow1, stack_underflow1, wide2, wide_iload1, Main1};
+use crate::mir_layout::MirLayout;
+use crate::processor_elements::{stack_limits, BBusControls, CBusControls, MirFields};
+use crate::shifter::{barrel, sll8, sra1, srl1};
+use crate::traps::{Fault, FaultKind};
 use crate::STACK_START;
 
-pub struct Mic1 {
-    mir: Register36,
-    mpc: Register9,
-
-    pub mar: Register32,
-    pub mdr: Register32,
-    pub pc: Register32,
-    pub mbr: Register32,
-    pub sp: Register32,
-    pub lv: Register32,
-    pub cpp: Register32,
-    pub tos: Register32,
-    pub opc: Register32,
-    pub h: Register32,
-
-    control_memory: Memory512x36,
+/// Strobes of the data port in the last cycle and the address in MAR, both are off in a stalled cycle
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DataRequest {
+    pub read: bool,
+    pub write: bool,
+    pub address: i64,
+}
 
-    pub main_memory: MainMemory,
+/**
+ * Mic-1 with `W`-bit registers, buses and memory words.
+ *
+ * The microprogram doesn't depend on the word width, so e.g. a 16-bit processor runs the same control store.
+ *   The control store address and the shift amount are still taken from the lowest bits of MBR and H.
+ */
+pub struct Mic1<const W: usize = 32> {
+    mir: MirRegister,
+    mpc: MpcRegister,
+
+    pub mar: Register<W>,
+    pub mdr: Register<W>,
+    pub pc: Register<W>,
+    pub mbr: Register<W>,
+    pub sp: Register<W>,
+    pub lv: Register<W>,
+    pub cpp: Register<W>,
+    pub tos: Register<W>,
+    pub opc: Register<W>,
+    pub h: Register<W>,
+
+    /// The lowest cell of the stack, a value can only be taken from the stack if SP is at least this
+    pub stack_base: Register<W>,
+    /// The highest cell of the stack
+    pub stack_limit: Register<W>,
+
+    control_memory: ControlMemory,
+
+    pub main_memory: MainMemory<W>,
+
+    /// Adder inside of the ALU
+    pub adder: Adder,
+
+    faults: Vec<HardwareFault>,
+
+    pub rule_checking: RuleChecking,
+    /// Electrical rule violations found with `RuleChecking::Warn`
+    pub violations: Vec<RuleViolation>,
+    /// The violation that halted the processor with `RuleChecking::Error`
+    pub rule_error: Option<RuleViolation>,
+
+    /// The last access to an invalid address
+    pub fault: Option<Fault>,
+    /// Microinstruction that is executed after a fault, without it the processor stops
+    pub fault_handler: Option<usize>,
+
+    pub data_request: DataRequest,
+
+    /// Amount of executed microinstructions
+    pub cycles: usize,
 }
 
-impl Mic1 {
-    pub fn init(main_memory: MainMemory, control_memory: Memory512x36, tos: Register32, pc: Register32, sp: Register32, lv: Register32, mpc: Register9) -> Mic1 {
+impl<const W: usize> Mic1<W> {
+    /// The processor starts before the main program: the stack begins at its frame (LV) and may grow up to PC
+    pub fn init(main_memory: MainMemory<W>, control_memory: ControlMemory, tos: Register<W>, pc: Register<W>, sp: Register<W>, lv: Register<W>, mpc: MpcRegister) -> Mic1<W> {
         Mic1 {
-            mir: Register36::new(),
+            mir: MirRegister::new(control_memory.layout().clone()),
             mpc,
-            mar: Register32::new(),
-            mdr: Register32::new(),
+            mar: Register::new(),
+            mdr: Register::new(),
             pc,
-            mbr: Register32::new(),
+            mbr: Register::new(),
             sp,
             lv,
-            cpp: Register32::new(),
+            cpp: Register::new(),
             tos,
-            opc: Register32::new(),
-            h: Register32::new(),
+            opc: Register::new(),
+            h: Register::new(),
+            stack_base: lv,
+            stack_limit: pc,
             control_memory,
             main_memory,
+            adder: Adder::Ripple,
+            faults: Vec::new(),
+            rule_checking: RuleChecking::Warn,
+            violations: Vec::new(),
+            rule_error: None,
+            fault: None,
+            fault_handler: None,
+            data_request: DataRequest::default(),
+            cycles: 0,
+        }
+    }
+
+    pub fn with_adder(mut self, adder: Adder) -> Mic1<W> {
+        self.adder = adder;
+        self
+    }
+
+    pub fn with_fault_handler(mut self, address: usize) -> Mic1<W> {
+        if address >= self.layout().control_store_size() {
+            panic!("Fault handler {} is out of the control store", address);
+        }
+        self.fault_handler = Some(address);
+        self
+    }
+
+    /// The processor got a fault and there is no handler or broke an electrical rule, nothing is executed anymore
+    pub fn halted(&self) -> bool {
+        if self.rule_error.is_some() {
+            return true;
+        }
+        match self.fault {
+            Some(Fault { kind: FaultKind::StackOverflow, .. }) | Some(Fault { kind: FaultKind::StackUnderflow, .. }) => true,
+            Some(_) => self.fault_handler.is_none(),
+            None => false,
+        }
+    }
+
+    /// Stuck control store bits change the microprogram, other faults are applied every cycle
+    pub fn inject(&mut self, fault: HardwareFault) {
+        if let HardwareFault::StuckAt { signal: Signal::ControlStore { address, bit }, value } = fault {
+            let layout = self.control_memory.layout();
+            if address >= layout.control_store_size() || bit >= layout.width() {
+                panic!("Control store bit {} at {} is out of range", bit, address);
+            }
+            let mut word = self.control_memory.read_address(address);
+            word[bit] = value;
+            self.control_memory.write_data(&word, address);
+        } else {
+            self.faults.push(fault);
         }
     }
 
     pub fn run(&mut self, len_of_command: usize, program_start: usize) {
         let last_command = len_of_command + 1 + program_start;
         let mut pc_counter = 0;
-        while pc_counter < last_command {
+        while pc_counter < last_command && !self.halted() {
             self.execute_command();
-            pc_counter = fast_encode(&self.pc.get()) as usize;
+            pc_counter = encode(&self.pc.get()) as usize;
         }
     }
 
     pub fn run_until_stop(&mut self, stop_instruction: i32) {
         let mut pc_counter = 0;
         let mut hit_stop = false;
-        while !(hit_stop && self.get_current_command() == Main1) {
+        while !(hit_stop && self.get_current_command() == Main1) && !self.halted() {
             self.execute_command();
-            pc_counter = fast_encode(&self.pc.get()) as usize;
-            hit_stop = hit_stop || self.main_memory.read_number(pc_counter) == stop_instruction;
+            pc_counter = encode(&self.pc.get()) as usize;
+            hit_stop = hit_stop || pc_counter < MEMORY_SIZE && self.main_memory.read_number(pc_counter) == stop_instruction;
         }
 
         // Finish last command
+        self.wait_stalls();
         self.execute_command();
-        while self.get_current_command() != Main1 {
+        while self.get_current_command() != Main1 && !self.halted() {
             self.execute_command();
         }
     }
@@ -84,12 +182,64 @@ impl Mic1 {
         let mut protect_counter = 0;
         while protect_counter < len_of_command {
             self.execute_command();
-            fast_encode(&self.pc.get()) as usize;
+            encode(&self.pc.get()) as usize;
             protect_counter += 1;
         }
     }
 
+    /// Executes microinstructions until the processor gets back to Main1, i.e. runs one IJVM instruction
+    pub fn step_instruction(&mut self) {
+        self.wait_stalls();
+        self.execute_command();
+        while self.mpc_address() != Main1 as usize && !self.halted() {
+            self.execute_command();
+        }
+    }
+
+    /// Cycles of a stall that started at the end of the previous instruction
+    fn wait_stalls(&mut self) {
+        while self.main_memory.stall_cycles() > 0 && !self.halted() {
+            self.execute_command();
+        }
+    }
+
+    pub fn layout(&self) -> &MirLayout { &self.mir.layout }
+
+    /// Address of the next microinstruction
+    pub fn set_mpc(&mut self, address: usize) {
+        if address >= self.layout().control_store_size() {
+            panic!("MPC {} is out of the control store", address);
+        }
+        let width = self.layout().address_width();
+        self.mpc.update(&address_bits(address, width), true);
+    }
+
+    pub fn mpc_address(&self) -> usize {
+        let mpc = self.mpc.get();
+        let mut address = 0;
+        for i in 0..mpc.len() {
+            address |= (mpc[i] as usize) << i;
+        }
+        address
+    }
+
     pub fn execute_command(&mut self) {
+        if self.halted() {
+            return;
+        }
+        self.cycles += 1;
+        let mpc = self.mpc_address();
+        self.data_request = DataRequest::default();
+
+        for fault in self.faults.iter() {
+            if let HardwareFault::MemoryUpset { address, bit, cycle } = *fault {
+                if cycle == self.cycles {
+                    let value = self.main_memory.read_word(address);
+                    self.main_memory.write_word(value ^ 1 << bit, address);
+                }
+            }
+        }
+
         // Debugging info
         // println!("-----------------------");
         // self.print_stack();
@@ -102,76 +252,156 @@ impl Mic1 {
         // Mic1::print_reg(&self.mdr, "MDR: ");
         // Mic1::print_reg(&self.mbr, "MBR: ");
 
+        // Cache misses freeze the processor, the devices keep going
+        self.main_memory.tick();
+        if self.main_memory.stalled() {
+            return;
+        }
+
         // Update registers from the main memory
         let (data, enabled) = self.main_memory.check_first_read();
-        self.mdr.update_from_bus(&Bus32::from(data), enabled);
+        self.mdr.update_from_bus(&Bus::from(data), enabled);
         let (data, enabled) = self.main_memory.check_second_read();
-        self.mbr.update_from_bus(&Bus32::from(data), enabled);
+        self.mbr.update_from_bus(&Bus::from(data), enabled);
+        self.force_registers();
 
         // Read new command
-        let mpc_bus = Bus9::from(self.mpc.get());
+        let mpc_bus = AddressBus::from(self.mpc.get());
         let new_command = self.control_memory.get(mpc_bus);
 
         // Write new command to mir register
         self.mir.update_from_bus(&new_command, true);
         // self.print_current_command();
+        let layout = self.mir.layout.clone();
+        let mir = MirFields::new(&layout, self.mir.get());
 
         // Create B bus
-        let b_bus_controls = self.mir.mir_b_bus_controls();
-        let decoded_b_bus_controls = BBusControls::new(decoder_4x9(b_bus_controls));
-        let b_bus = self.run_b_bus(decoded_b_bus_controls);
+        let b_bus_controls = mir.b_bus_controls();
+        let mut b_bus_enables = decoder_4x9(b_bus_controls);
+        self.force_enables(&mut b_bus_enables);
+        let decoded_b_bus_controls = BBusControls::new(b_bus_enables);
+        self.check_rules(mpc, &mir, &decoded_b_bus_controls);
+        if self.rule_error.is_some() {
+            return;
+        }
+        let mut b_bus = self.run_b_bus(decoded_b_bus_controls);
+        self.force_line(Line::BBus, &mut b_bus);
 
         // Create A bus
-        let a_bus = Bus32::from(self.h.read(true));
+        let h = self.h.read(true);
+        let a_bus = Bus::from(h);
 
         // Calculate C bus
-        let (mut c_bus, n_bit, z_bit) = alu_32(a_bus, b_bus, self.mir.mir_alu_controls());
+        let (mut c_bus, mut flags) = alu(a_bus, b_bus, mir.alu_controls(), self.adder);
+        if self.force_line(Line::Alu, &mut c_bus) {
+            flags.n = c_bus.data[W - 1];
+            flags.z = c_bus.data.iter().all(|x| !*x);
+        }
 
         // Shifting
-        c_bus = sll8(c_bus, self.mir.mir_ssl8());
-        c_bus = sra1(c_bus, self.mir.mir_sra1());
+        c_bus = sll8(c_bus, mir.sll8());
+        c_bus = sra1(c_bus, mir.sra1());
+        c_bus = srl1(c_bus, mir.srl1());
+        c_bus = barrel(c_bus, mir.shift_op(), mir.shift_amount(&h));
+        self.force_line(Line::CBus, &mut c_bus);
 
         // Write C bus into registers
-        let c_bus_controls = self.mir.mir_c_bus_controls();
+        let c_bus_controls = mir.c_bus_controls();
         self.run_c_bus(&c_bus, c_bus_controls);
+        self.force_registers();
 
         // Initialize reads
-        self.main_memory.request_first_read(self.mar.get(), self.mir.mir_read());
-        self.main_memory.request_second_read(self.pc.get(), self.mir.mir_fetch());
+        self.main_memory.request_first_read(self.mar.get(), mir.read());
+        self.main_memory.request_second_read(self.pc.get(), mir.fetch());
 
         // Writing
-        self.main_memory.write(self.mdr.get(), self.mar.get(), self.mir.mir_write());
+        self.main_memory.write(self.mdr.get(), self.mar.get(), mir.write());
+        self.data_request = DataRequest { read: mir.read(), write: mir.write(), address: encode(&self.mar.get()) };
 
         // O operation
         // Select next command
-        let mut next_command = self.o();
-        next_command[8] |= self.f(z_bit, n_bit);
-        self.mpc.update(next_command, true);
+        let (below, above) = stack_limits(&c_bus.data, &self.stack_base.get(), &self.stack_limit.get(), self.adder);
+        let mut next_command = mir.next_address(&self.mbr.get(), &flags, self.main_memory.ready(), below || above);
+        if let Some((kind, address)) = self.main_memory.take_fault() {
+            self.fault = Some(Fault { kind, address, cycle: self.cycles, mpc, pc: encode(&self.pc.get()) });
+            if let Some(handler) = self.fault_handler {
+                next_command = (0..next_command.len()).map(|x| handler >> x & 1 == 1).collect();
+            }
+        }
+        self.mpc.update(&next_command, true);
+
+        let stack_fault = match self.mpc_address() {
+            x if x == stack_overflow1 as usize => Some(FaultKind::StackOverflow),
+            x if x == stack_underflow1 as usize => Some(FaultKind::StackUnderflow),
+            _ => None,
+        };
+        if let Some(kind) = stack_fault {
+            self.fault = Some(Fault { kind, address: encode(&self.sp.get()), cycle: self.cycles, mpc: self.mpc_address(), pc: encode(&self.pc.get()) });
+        }
 
         return;
     }
 
-    fn f(&self, z: bool, n: bool) -> bool {
-        self.mir.mir_jamz() && z || self.mir.mir_jamn() && n
+    /// Applies stuck-at faults of the line, returns true if there are any
+    fn force_line(&self, line: Line, bus: &mut Bus<W>) -> bool {
+        let mut forced = false;
+        for fault in self.faults.iter() {
+            if let HardwareFault::StuckAt { signal: Signal::Line(fault_line, bit), value } = *fault {
+                if fault_line == line {
+                    bus.data[bit] = value;
+                    forced = true;
+                }
+            }
+        }
+        forced
     }
 
-    fn o(&self) -> [bool; 9] {
-        let mut next_command = self.mir.mir_addr();
-
-        let mut mbr_value = self.mbr.get();
-        for i in 0..8 {
-            mbr_value[i] &= self.mir.mir_jmpc()
+    fn force_enables(&self, enables: &mut [bool; 9]) {
+        for fault in self.faults.iter() {
+            if let HardwareFault::StuckAt { signal: Signal::Line(Line::BBusEnable, bit), value } = *fault {
+                enables[bit] = value;
+            }
         }
+    }
 
-        for i in 0..8 {
-            next_command[i] |= mbr_value[i];
+    fn check_rules(&mut self, mpc: usize, mir: &MirFields, b_bus_controls: &BBusControls) {
+        if self.rule_checking == RuleChecking::Off {
+            return;
+        }
+        let mut violations = check_cycle(b_bus_controls, mir.alu_controls().en_b(), mir.read(), mir.write());
+        violations.extend(check_data_ready(b_bus_controls, mir.alu_controls().en_b(), mir.jmpc(), self.main_memory.late_read(true), self.main_memory.late_read(false)));
+        for violation in violations {
+            let violation = RuleViolation { cycle: self.cycles, mpc, violation };
+            if self.rule_checking == RuleChecking::Error {
+                self.rule_error = Some(violation);
+                return;
+            }
+            self.violations.push(violation);
         }
+    }
 
-        next_command
+    fn force_registers(&mut self) {
+        for fault in self.faults.iter() {
+            if let HardwareFault::StuckAt { signal: Signal::Line(Line::Register(name), bit), value } = *fault {
+                let register = match name {
+                    RegisterName::Mar => &mut self.mar,
+                    RegisterName::Mdr => &mut self.mdr,
+                    RegisterName::Pc => &mut self.pc,
+                    RegisterName::Mbr => &mut self.mbr,
+                    RegisterName::Sp => &mut self.sp,
+                    RegisterName::Lv => &mut self.lv,
+                    RegisterName::Cpp => &mut self.cpp,
+                    RegisterName::Tos => &mut self.tos,
+                    RegisterName::Opc => &mut self.opc,
+                    RegisterName::H => &mut self.h,
+                };
+                register.registers[bit].update(value, true);
+            }
+        }
     }
 
-    fn run_b_bus(&self, controls: BBusControls) -> Bus32 {
-        let mut bus = Bus32::new();
+    fn run_b_bus(&self, controls: BBusControls) -> Bus<W> {
+        let mut bus = Bus::new();
 
         bus.connect(self.mdr.read(controls.mdr()));
         bus.connect(self.pc.read(controls.pc()));
@@ -183,7 +413,7 @@ impl Mic1 {
         bus.connect(self.opc.read(controls.opc()));
 
         let mut mbru_value = self.mbr.read(controls.mbru());
-        for x in 8..32 {
+        for x in 8..W {
             mbru_value[x] = mbru_value[7];
         }
         bus.connect(mbru_value);
@@ -199,7 +429,7 @@ impl Mic1 {
         let current_mir = self.mir.read(true);
 
         for comm in MicroAsm::iter() {
-            if Mic1::arrays_equals(&comm.command(), &current_mir) {
+            if Self::arrays_equals(&comm.command(&self.mir.layout), &current_mir) {
                 return comm;
             }
         }
@@ -207,7 +437,7 @@ impl Mic1 {
         return nop1;
     }
 
-    fn run_c_bus(&mut self, bus: &Bus32, controls: CBusControls) {
+    fn run_c_bus(&mut self, bus: &Bus<W>, controls: CBusControls) {
         self.h.update_from_bus(bus, controls.h());
         self.opc.update_from_bus(bus, controls.opc());
         self.tos.update_from_bus(bus, controls.tos());
@@ -219,8 +449,11 @@ impl Mic1 {
         self.mar.update_from_bus(bus, controls.mar());
     }
 
-    fn arrays_equals(first: &[bool; 36], second: &[bool; 36]) -> bool {
-        for i in 0..36 {
+    fn arrays_equals(first: &[bool], second: &[bool]) -> bool {
+        if first.len() != second.len() {
+            return false;
+        }
+        for i in 0..first.len() {
             if first[i] != second[i] {
                 return false;
             }
@@ -228,20 +461,56 @@ impl Mic1 {
         return true;
     }
 
-    fn print_reg(reg: &Register32, str: &str) {
+    fn print_reg(reg: &Register<W>, str: &str) {
         let pc_value = reg.read(true);
-        let encoded_value = fast_encode(&pc_value);
+        let encoded_value = encode(&pc_value);
         println!("{} {:?}", str, encoded_value)
     }
 
     fn print_stack(&self) {
-        let stack_ptr = fast_encode(&self.sp.get());
-        let stack_size = stack_ptr - STACK_START + 1;
+        let stack_ptr = encode(&self.sp.get());
+        let stack_size = stack_ptr - STACK_START as i64 + 1;
         let mut real_stack = Vec::new();
         for x in 0..stack_size {
-            real_stack.push(fast_encode(&self.main_memory.read(fast_decode(x + STACK_START))));
+            real_stack.push(self.main_memory.read_word((x + STACK_START as i64) as usize));
         }
 
         println!("{:?}", real_stack);
     }
 }
+
+impl Mic1 {
+    /// Creates the processor that is about to execute the instruction at `state.pc`
+    pub fn from_arch_state(state: &ArchState, control_memory: ControlMemory) -> Mic1 {
+        let mut mpc = MpcRegister::new(control_memory.layout().address_width());
+        mpc.update(&address_bits(Main1 as usize, control_memory.layout().address_width()), true);
+
+        let mut mic1 = Mic1::init(MainMemory::from_cells(&state.memory), control_memory, register(state.tos as i64), register(state.pc as i64), register(state.sp as i64), register(state.lv as i64), mpc);
+        mic1.cpp = register(state.cpp as i64);
+        mic1.stack_base = register(state.stack_base as i64);
+        mic1.stack_limit = register(state.stack_limit as i64);
+        // Main1 expects the opcode to be already fetched
+        mic1.mbr = register(*state.memory.get(state.pc as usize).unwrap_or(&0) as i64);
+        mic1
+    }
+
+    /// Should be called between instructions, when MPC points to Main1
+    pub fn arch_state(&self) -> ArchState {
+        ArchState {
+            pc: fast_encode(&self.pc.get()),
+            sp: fast_encode(&self.sp.get()),
+            lv: fast_encode(&self.lv.get()),
+            cpp: fast_encode(&self.cpp.get()),
+            tos: fast_encode(&self.tos.get()),
+            stack_base: fast_encode(&self.stack_base.get()),
+            stack_limit: fast_encode(&self.stack_limit.get()),
+            memory: self.main_memory.cells(),
+        }
+    }
+}
+
+pub fn register<const W: usize>(value: i64) -> Register<W> {
+    let mut register = Register::new();
+    register.update_from_bus(&Bus::from(decode(value)), true);
+    register
+}
@@ -1,73 +1,188 @@
-use crate::alu::AluControl;
-use crate::memory::Register36;
+use crate::alu::{Adder, AluControl, AluFlags};
+use crate::bus::connect_word;
+use crate::logic::Logic;
+use crate::mir_layout::{MirLayout, JAM_BIT};
 
-pub struct BBusControls {
-    controls: [bool; 9]
+/// Names of the B bus sources in the order of their codes
+pub const B_BUS_SOURCES: [&str; 9] = ["MDR", "PC", "MBR", "MBRU", "SP", "LV", "CPP", "TOS", "OPC"];
+
+pub struct BBusControls<T: Logic = bool> {
+    controls: [T; 9]
+}
+
+impl<T: Logic> BBusControls<T> {
+    pub fn new(controls: [T; 9]) -> BBusControls<T> { BBusControls { controls } }
+    pub fn mdr(&self) -> T { self.controls[0] }
+    pub fn pc(&self) -> T { self.controls[1] }
+    pub fn mbr(&self) -> T { self.controls[2] }
+    pub fn mbru(&self) -> T { self.controls[3] }
+    pub fn sp(&self) -> T { self.controls[4] }
+    pub fn lv(&self) -> T { self.controls[5] }
+    pub fn cpp(&self) -> T { self.controls[6] }
+    pub fn tos(&self) -> T { self.controls[7] }
+    pub fn opc(&self) -> T { self.controls[8] }
+
+    /// Output enable of the source with the code
+    pub fn enabled(&self, code: usize) -> T { self.controls[code] }
+}
+
+/// Registers that can be put on the B bus
+pub struct BBusSources<T: Logic = bool, const W: usize = 32> {
+    pub mdr: [T; W],
+    pub pc: [T; W],
+    pub mbr: [T; W],
+    pub sp: [T; W],
+    pub lv: [T; W],
+    pub cpp: [T; W],
+    pub tos: [T; W],
+    pub opc: [T; W],
+}
+
+impl<T: Logic, const W: usize> BBusSources<T, W> {
+    /// Value of the B bus, MBRU is MBR with the sign extension
+    pub fn drive(&self, controls: &BBusControls<T>) -> [T; W] {
+        let mut bus = [T::constant(false); W];
+
+        connect_word(&mut bus, &self.mdr, controls.mdr());
+        connect_word(&mut bus, &self.pc, controls.pc());
+        connect_word(&mut bus, &self.mbr, controls.mbr());
+        connect_word(&mut bus, &self.sp, controls.sp());
+        connect_word(&mut bus, &self.lv, controls.lv());
+        connect_word(&mut bus, &self.cpp, controls.cpp());
+        connect_word(&mut bus, &self.tos, controls.tos());
+        connect_word(&mut bus, &self.opc, controls.opc());
+
+        let mut mbru_value = [T::constant(false); W];
+        for x in 0..W {
+            mbru_value[x] = self.mbr[x] & controls.mbru();
+        }
+        for x in 8..W {
+            mbru_value[x] = mbru_value[7];
+        }
+        connect_word(&mut bus, &mbru_value, T::constant(true));
+
+        bus
+    }
 }
 
-impl BBusControls {
-    pub fn new(controls: [bool; 9]) -> BBusControls { BBusControls { controls } }
-    pub fn mdr(&self) -> bool { self.controls[0] }
-    pub fn pc(&self) -> bool { self.controls[1] }
-    pub fn mbr(&self) -> bool { self.controls[2] }
-    pub fn mbru(&self) -> bool { self.controls[3] }
-    pub fn sp(&self) -> bool { self.controls[4] }
-    pub fn lv(&self) -> bool { self.controls[5] }
-    pub fn cpp(&self) -> bool { self.controls[6] }
-    pub fn tos(&self) -> bool { self.controls[7] }
-    pub fn opc(&self) -> bool { self.controls[8] }
+pub struct CBusControls<T: Logic = bool> {
+    controls: [T; 9]
 }
 
-pub struct CBusControls {
-    controls: [bool; 9]
+impl<T: Logic> CBusControls<T> {
+    pub fn new(controls: [T; 9]) -> CBusControls<T> { CBusControls { controls } }
+    pub fn h(&self) -> T { self.controls[0] }
+    pub fn opc(&self) -> T { self.controls[1] }
+    pub fn tos(&self) -> T { self.controls[2] }
+    pub fn cpp(&self) -> T { self.controls[3] }
+    pub fn lv(&self) -> T { self.controls[4] }
+    pub fn sp(&self) -> T { self.controls[5] }
+    pub fn pc(&self) -> T { self.controls[6] }
+    pub fn mdr(&self) -> T { self.controls[7] }
+    pub fn mar(&self) -> T { self.controls[8] }
 }
 
-impl CBusControls {
-    pub fn new(controls: [bool; 9]) -> CBusControls { CBusControls { controls } }
-    pub fn h(&self) -> bool { self.controls[0] }
-    pub fn opc(&self) -> bool { self.controls[1] }
-    pub fn tos(&self) -> bool { self.controls[2] }
-    pub fn cpp(&self) -> bool { self.controls[3] }
-    pub fn lv(&self) -> bool { self.controls[4] }
-    pub fn sp(&self) -> bool { self.controls[5] }
-    pub fn pc(&self) -> bool { self.controls[6] }
-    pub fn mdr(&self) -> bool { self.controls[7] }
-    pub fn mar(&self) -> bool { self.controls[8] }
+/// Fields of the microinstruction, positions of the fields are taken from the layout
+#[derive(Clone)]
+pub struct MirFields<'a, T: Logic = bool> {
+    layout: &'a MirLayout,
+    bits: Vec<T>,
 }
 
-impl Register36 {
-    pub fn mir_jmpc(self) -> bool { self.get()[9] }
-    pub fn mir_jamn(self) -> bool { self.get()[10] }
-    pub fn mir_jamz(self) -> bool { self.get()[11] }
+impl<'a, T: Logic> MirFields<'a, T> {
+    pub fn new(layout: &'a MirLayout, bits: Vec<T>) -> MirFields<'a, T> {
+        if bits.len() != layout.width() {
+            panic!("Microinstruction has {} bits, but the layout has {}", bits.len(), layout.width());
+        }
+        MirFields { layout, bits }
+    }
 
-    pub fn mir_addr(self) -> [bool; 9] {
-        let mut res = [false; 9];
-        res.copy_from_slice(&self.get()[..9]);
-        res
+    /// Bits of the field, a field missing in the layout is zero
+    pub fn field(&self, name: &str) -> Vec<T> {
+        match self.layout.find(name) {
+            Some(field) => self.bits[field.position..field.position + field.width].to_vec(),
+            None => Vec::new(),
+        }
     }
 
-    pub fn mir_b_bus_controls(self) -> [bool; 4] {
-        let mut res = [false; 4];
-        res.copy_from_slice(&self.get()[32..36]);
+    /// Lowest `N` bits of the field, missing bits are zero
+    fn word<const N: usize>(&self, name: &str) -> [T; N] {
+        let mut res = [T::constant(false); N];
+        for (i, bit) in self.field(name).into_iter().take(N).enumerate() {
+            res[i] = bit;
+        }
         res
     }
 
-    pub fn mir_write(self) -> bool { self.get()[29] }
-    pub fn mir_read(self) -> bool { self.get()[30] }
-    pub fn mir_fetch(self) -> bool { self.get()[31] }
+    fn bit(&self, name: &str) -> T { self.word::<1>(name)[0] }
 
-    pub fn mir_alu_controls(self) -> AluControl {
-        let mut code = [false; 6];
-        code.copy_from_slice(&self.get()[14..20]);
-        AluControl::from(code)
+    pub fn jmpc(&self) -> T { self.bit("jmpc") }
+    pub fn jamn(&self) -> T { self.bit("jamn") }
+    pub fn jamz(&self) -> T { self.bit("jamz") }
+    pub fn jamc(&self) -> T { self.bit("jamc") }
+    pub fn jamv(&self) -> T { self.bit("jamv") }
+    pub fn jamr(&self) -> T { self.bit("jamr") }
+    pub fn jams(&self) -> T { self.bit("jams") }
+
+    pub fn addr(&self) -> Vec<T> { self.field("addr") }
+
+    pub fn b_bus_controls(&self) -> [T; 4] { self.word("b") }
+
+    pub fn write(&self) -> T { self.bit("write") }
+    pub fn read(&self) -> T { self.bit("read") }
+    pub fn fetch(&self) -> T { self.bit("fetch") }
+
+    pub fn alu_controls(&self) -> AluControl<T> { AluControl::from(self.word("alu")) }
+
+    pub fn c_bus_controls(&self) -> CBusControls<T> { CBusControls::new(self.word("c")) }
+
+    pub fn sll8(&self) -> T { self.bit("sll8") }
+    pub fn sra1(&self) -> T { self.bit("sra1") }
+    pub fn srl1(&self) -> T { self.bit("srl1") }
+
+    /// Operation of the barrel shifter, see `barrel_word`
+    pub fn shift_op(&self) -> [T; 3] { self.word("shift_op") }
+
+    /// Shift amount of the barrel shifter, taken either from the field or from the low bits of H
+    pub fn shift_amount<const W: usize>(&self, h: &[T; W]) -> [T; 5] {
+        let from_h = self.bit("shift_by_h");
+        let amount: [T; 5] = self.word("shift_amount");
+        let mut res = [T::constant(false); 5];
+        for i in 0..5 {
+            res[i] = from_h & h[i] | !from_h & amount[i];
+        }
+        res
     }
 
-    pub fn mir_c_bus_controls(self) -> CBusControls {
-        let mut code = [false; 9];
-        code.copy_from_slice(&self.get()[20..29]);
-        CBusControls::new(code)
+    /// Next MPC: NEXT_ADDRESS, OR-ed with MBR if JMPC and bit `JAM_BIT` OR-ed with N, Z, C, V, the memory ready signal
+    ///   and the stack limits check
+    pub fn next_address<const W: usize>(&self, mbr: &[T; W], flags: &AluFlags<T>, ready: T, out_of_stack: T) -> Vec<T> {
+        let mut next_command = self.addr();
+        for i in 0..next_command.len().min(JAM_BIT) {
+            next_command[i] = next_command[i] | mbr[i] & self.jmpc();
+        }
+        if next_command.len() > JAM_BIT {
+            next_command[JAM_BIT] = next_command[JAM_BIT] | (self.jamz() & flags.z | self.jamn() & flags.n | self.jamc() & flags.c | self.jamv() & flags.v | self.jamr() & ready | self.jams() & out_of_stack);
+        }
+        next_command
     }
+}
 
-    pub fn mir_ssl8(self) -> bool { self.get()[12] }
-    pub fn mir_sra1(self) -> bool { self.get()[13] }
+/**
+ * Comparators of the stack-limit registers: the value of SP is below `base` or above `limit`.
+ *
+ * Both are signed comparisons with the adder of the ALU, the sign of the difference is corrected by the overflow.
+ */
+pub fn stack_limits<T: Logic, const W: usize>(sp: &[T; W], base: &[T; W], limit: &[T; W], adder: Adder) -> (T, T) {
+    (less(sp, base, adder), less(limit, sp, adder))
+}
+
+fn less<T: Logic, const W: usize>(a: &[T; W], b: &[T; W], adder: Adder) -> T {
+    let mut inverted = [T::constant(false); W];
+    for i in 0..W {
+        inverted[i] = !b[i];
+    }
+    let (difference, _) = adder.add(*a, inverted, T::constant(true));
+    let overflow = (a[W - 1] ^ b[W - 1]) & (a[W - 1] ^ difference[W - 1]);
+    difference[W - 1] ^ overflow
 }
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::asm::IjvmCommand::{IINC, ILOAD, INVOKEVIRTUAL, IRETURN, ISTORE, WIDE};
+use crate::compiler::SourceLine;
+use crate::main_memory::{encode, MEMORY_SIZE};
+use crate::microasm::MicroAsm::Main1;
+use crate::processor::Mic1;
+use crate::{PROGRAM_START, STACK_START};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// Read of the cell that wasn't written by the program or initialized before the run
+    UninitializedRead(i64),
+    /// Write into the program, e.g. self-modifying code or a stack over the program
+    ProgramWrite(i64),
+    /// ILOAD, ISTORE or IINC index beyond the parameters and local variables of the method
+    LocalOutOfFrame { index: i64, locals: i64 },
+    /// SP is below the beginning of the operand stack of the current method
+    StackBelowFrame { sp: i64, base: i64 },
+    /// IRETURN with other than one value on the operand stack of the method
+    ReturnDepth(i64),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::UninitializedRead(address) => write!(f, "Read of uninitialized memory at {}", address),
+            Issue::ProgramWrite(address) => write!(f, "Write into the program at {}", address),
+            Issue::LocalOutOfFrame { index, locals } => write!(f, "Local variable {} is out of the frame with {} variables", index, locals),
+            Issue::StackBelowFrame { sp, base } => write!(f, "Stack pointer {} is below the frame base {}", sp, base),
+            Issue::ReturnDepth(depth) => write!(f, "IRETURN with {} values on the stack instead of 1", depth),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub issue: Issue,
+    /// Address of the IJVM instruction
+    pub pc: i64,
+    /// Source line of the instruction, if it's in the program
+    pub line: Option<SourceLine>,
+    pub cycle: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.line {
+            Some(line) => write!(f, "{}, address {}: {}", line, self.pc, self.issue),
+            None => write!(f, "Address {}: {}", self.pc, self.issue),
+        }
+    }
+}
+
+struct Frame {
+    /// Parameters with OBJREF and local variables, unknown for the main program
+    locals: Option<i64>,
+    /// SP of the empty operand stack
+    base: i64,
+}
+
+/**
+ * Checking mode over `Mic1`: shadow state of the memory and of the method frames.
+ *
+ * Memory requests are checked every cycle, indexes of local variables and the frames between instructions.
+ *   The sanitizer only reports, the program runs the same way as without it.
+ * Memory up to the initial SP and the program are initialized before the run.
+ */
+pub struct Sanitizer {
+    pub mic1: Mic1,
+    program: Range<usize>,
+    /// Source line of every byte of the program
+    lines: Vec<SourceLine>,
+    written: Vec<bool>,
+    frames: Vec<Frame>,
+    /// Address of the instruction that is executed
+    pc: i64,
+    pub reports: Vec<Report>,
+}
+
+impl Sanitizer {
+    /// The processor should be at the beginning of the program, `lines` can be empty
+    pub fn new(mic1: Mic1, program_length: usize, lines: Vec<SourceLine>) -> Sanitizer {
+        let program = PROGRAM_START..PROGRAM_START + program_length;
+        let sp = encode(&mic1.sp.get()).max(-1) as usize;
+        let written = (0..MEMORY_SIZE).map(|x| x <= sp || program.contains(&x)).collect();
+        let frames = vec![Frame { locals: None, base: STACK_START as i64 - 1 }];
+        Sanitizer { mic1, program, lines, written, frames, pc: PROGRAM_START as i64, reports: Vec::new() }
+    }
+
+    /// Runs until the stop instruction is executed or until the processor is halted
+    pub fn run_until_stop(&mut self, stop_instruction: i32, max_instructions: usize) {
+        for _ in 0..max_instructions {
+            if self.mic1.halted() {
+                return;
+            }
+            self.step_instruction();
+            if self.cell(self.pc) == stop_instruction as i64 {
+                return;
+            }
+        }
+    }
+
+    /// Executes one IJVM instruction with the checks
+    pub fn step_instruction(&mut self) {
+        // MPC is at Main1, PC points to the opcode and MBR holds it
+        self.pc = encode(&self.mic1.pc.get());
+        let opcode = self.cell(self.pc);
+        self.check_locals(opcode);
+        let method = if opcode == INVOKEVIRTUAL as i64 { Some(self.method_locals()) } else { None };
+        if opcode == IRETURN as i64 {
+            let depth = self.sp() - self.frame().base;
+            if depth != 1 {
+                self.report(Issue::ReturnDepth(depth));
+            }
+        }
+
+        while self.mic1.main_memory.stall_cycles() > 0 && !self.mic1.halted() {
+            self.step();
+        }
+        self.step();
+        while self.mic1.mpc_address() != Main1 as usize && !self.mic1.halted() {
+            self.step();
+        }
+
+        if let Some(locals) = method {
+            self.frames.push(Frame { locals: Some(locals), base: self.sp() });
+        } else if opcode == IRETURN as i64 && self.frames.len() > 1 {
+            self.frames.pop();
+        }
+        let base = self.frame().base;
+        if self.sp() < base {
+            self.report(Issue::StackBelowFrame { sp: self.sp(), base });
+        }
+    }
+
+    fn step(&mut self) {
+        self.mic1.execute_command();
+        let request = self.mic1.data_request;
+        let address = request.address;
+        if !(0..MEMORY_SIZE as i64).contains(&address) {
+            return;
+        }
+        if request.read && !self.written[address as usize] {
+            self.report(Issue::UninitializedRead(address));
+        }
+        if request.write {
+            self.written[address as usize] = true;
+            if self.program.contains(&(address as usize)) {
+                self.report(Issue::ProgramWrite(address));
+            }
+        }
+    }
+
+    fn check_locals(&mut self, opcode: i64) {
+        let index = if opcode == WIDE as i64 && [ILOAD as i64, ISTORE as i64, IINC as i64].contains(&self.cell(self.pc + 1)) {
+            self.cell(self.pc + 2) << 8 | self.cell(self.pc + 3)
+        } else if [ILOAD as i64, ISTORE as i64, IINC as i64].contains(&opcode) {
+            // MBRU is sign-extended
+            self.cell(self.pc + 1) as i8 as i64
+        } else {
+            return;
+        };
+        if let Some(locals) = self.frame().locals {
+            if index < 0 || index >= locals {
+                self.report(Issue::LocalOutOfFrame { index, locals });
+            }
+        }
+    }
+
+    /// Parameters and local variables from the header of the invoked method
+    fn method_locals(&self) -> i64 {
+        let index = self.cell(self.pc + 1) << 8 | self.cell(self.pc + 2);
+        let method = self.cell(encode(&self.mic1.cpp.get()) + index);
+        let parameters = self.cell(method) << 8 | self.cell(method + 1);
+        let locals = self.cell(method + 2) << 8 | self.cell(method + 3);
+        parameters + locals
+    }
+
+    fn report(&mut self, issue: Issue) {
+        let line = (self.pc as usize).checked_sub(PROGRAM_START).and_then(|x| self.lines.get(x)).cloned();
+        self.reports.push(Report { issue, pc: self.pc, line, cycle: self.mic1.cycles });
+    }
+
+    fn frame(&self) -> &Frame { self.frames.last().unwrap() }
+
+    fn sp(&self) -> i64 { encode(&self.mic1.sp.get()) }
+
+    fn cell(&self, address: i64) -> i64 {
+        if (0..MEMORY_SIZE as i64).contains(&address) { self.mic1.main_memory.read_word(address as usize) } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::create_processor;
+    use crate::parser::{parse, source_lines};
+
+    use super::*;
+
+    /// Calls the method after the main program and pops the result, the method takes OBJREF and has a local variable
+    const MAIN: &str = "BIPUSH 0x00\nINVOKEVIRTUAL 0x00 0x00\nPOP\n0x00 0x01 0x00 0x01\n";
+    const METHOD: usize = PROGRAM_START + 6;
+
+    fn sanitize(program: &str, initial_stack: Vec<i32>) -> Vec<Report> {
+        let commands = parse(program);
+        let mic1 = create_processor(&commands, initial_stack, [METHOD as i32, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let lines = source_lines(program).into_iter().map(|line| SourceLine { file: String::new(), line }).collect();
+        let mut sanitizer = Sanitizer::new(mic1, commands.len(), lines);
+        sanitizer.run_until_stop(0x57, 100);
+        sanitizer.reports
+    }
+
+    fn issues(program: &str, initial_stack: Vec<i32>) -> Vec<Issue> {
+        sanitize(program, initial_stack).into_iter().map(|x| x.issue).collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        assert_eq!(Vec::<Issue>::new(), issues("BIPUSH 0x05\nDUP\nIADD\nISTORE 0x01\nILOAD 0x01\nPOP", vec![0, 0]));
+        assert_eq!(Vec::<Issue>::new(), issues(&format!("{}BIPUSH 0x07\nDUP\nISTORE 0x01\nIRETURN", MAIN), vec![0]));
+    }
+
+    #[test]
+    fn uninitialized_local() {
+        let reports = sanitize(&format!("{}ILOAD 0x01\nIRETURN", MAIN), vec![0]);
+        assert_eq!(vec![Issue::UninitializedRead(12)], reports.iter().map(|x| x.issue.clone()).collect::<Vec<_>>());
+        assert_eq!("Line 5, address 110: Read of uninitialized memory at 12", reports[0].to_string());
+    }
+
+    #[test]
+    fn line_of_included_file() {
+        let program = format!("{}ILOAD 0x01\nIRETURN", MAIN);
+        let commands = parse(&program);
+        let mic1 = create_processor(&commands, vec![0], [METHOD as i32, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let lines = source_lines(&program).into_iter().map(|line| SourceLine { file: "/lib/method.jas".to_string(), line }).collect();
+        let mut sanitizer = Sanitizer::new(mic1, commands.len(), lines);
+        sanitizer.run_until_stop(0x57, 100);
+        assert_eq!("/lib/method.jas, line 5, address 110: Read of uninitialized memory at 12", sanitizer.reports[0].to_string());
+    }
+
+    #[test]
+    fn write_into_program() {
+        // LV is 10, so the local 0x5A is the first byte of the program
+        assert_eq!(vec![Issue::ProgramWrite(PROGRAM_START as i64)], issues("BIPUSH 0x01\nISTORE 0x5A\nPOP", vec![0]));
+    }
+
+    #[test]
+    fn local_out_of_frame() {
+        let issues = issues(&format!("{}BIPUSH 0x01\nISTORE 0x02\nBIPUSH 0x01\nIRETURN", MAIN), vec![0]);
+        assert_eq!(Issue::LocalOutOfFrame { index: 2, locals: 2 }, issues[0]);
+    }
+
+    #[test]
+    fn return_depth() {
+        assert_eq!(vec![Issue::ReturnDepth(2)], issues(&format!("{}BIPUSH 0x01\nDUP\nIRETURN", MAIN), vec![0]));
+    }
+
+    #[test]
+    fn stack_below_frame() {
+        let issues = issues(&format!("{}POP\nBIPUSH 0x01\nBIPUSH 0x01\nIRETURN", MAIN), vec![0]);
+        assert_eq!(Issue::StackBelowFrame { sp: 13, base: 14 }, issues[0]);
+    }
+}
@@ -1,35 +1,93 @@
-use crate::bus::Bus32;
+use crate::bus::{Bus, Bus32};
+use crate::decoders::decoder_4x9;
+use crate::logic::Logic;
 
-fn shift(data: Bus32, left: bool, enabled: bool) -> Bus32 {
-    let mut res = Bus32::new();
+fn shift<T: Logic, const W: usize>(data: [T; W], left: bool, enabled: T) -> [T; W] {
+    let left = T::constant(left);
+    let mut res = [T::constant(false); W];
 
-    for i in 0..31 {
-        res.data[i + 1] = left && data.data[i] && enabled || !enabled && data.data[i + 1];
+    for i in 0..W - 1 {
+        res[i + 1] = left & data[i] & enabled | !enabled & data[i + 1];
     }
 
-    for i in 1..32 {
-        res.data[i - 1] = (res.data[i - 1] || !left && data.data[i]) && enabled || !enabled && data.data[i - 1];
+    for i in 1..W {
+        res[i - 1] = (res[i - 1] | !left & data[i]) & enabled | !enabled & data[i - 1];
     }
 
     res
 }
 
-pub fn sll8(data: Bus32, enabled: bool) -> Bus32 {
-    let mut res: Bus32 = data;
+pub fn sll8<const W: usize>(data: Bus<W>, enabled: bool) -> Bus<W> {
+    Bus::from(sll8_word(data.data, enabled))
+}
+
+pub fn sra1<const W: usize>(data: Bus<W>, enabled: bool) -> Bus<W> {
+    Bus::from(sra1_word(data.data, enabled))
+}
+
+pub fn srl1<const W: usize>(data: Bus<W>, enabled: bool) -> Bus<W> {
+    Bus::from(srl1_word(data.data, enabled))
+}
+
+/// Barrel shifter, `op` and `amount` are the fields of the microinstruction
+pub fn barrel<const W: usize>(data: Bus<W>, op: [bool; 3], amount: [bool; 5]) -> Bus<W> {
+    Bus::from(barrel_word(data.data, op, amount))
+}
+
+pub fn sll8_word<T: Logic, const W: usize>(data: [T; W], enabled: T) -> [T; W] {
+    let mut res = data;
     for _ in 0..8 {
         res = shift(res, true, enabled);
     }
     return res;
 }
 
-pub fn sra1(data: Bus32, enabled: bool) -> Bus32 {
+pub fn sra1_word<T: Logic, const W: usize>(data: [T; W], enabled: T) -> [T; W] {
     let mut res = shift(data, false, enabled);
-    res.data[31] = res.data[30];
+    // Sign bit is kept only when shifting
+    res[W - 1] = res[W - 2] & enabled | res[W - 1] & !enabled;
+    res
+}
+
+pub fn srl1_word<T: Logic, const W: usize>(data: [T; W], enabled: T) -> [T; W] {
+    shift(data, false, enabled)
+}
+
+/**
+ * Shifts by 0-31 bits in five stages, stage `k` shifts by `2^k` if the bit `k` of amount is set.
+ *
+ * Operation codes: 0 - no shift, 1 - SLL, 2 - SRL, 3 - SRA, 4 - ROL, 5 - ROR.
+ * Words narrower than 32 bits are shifted out completely by larger amounts and rotated modulo the width.
+ */
+pub fn barrel_word<T: Logic, const W: usize>(data: [T; W], op: [T; 3], amount: [T; 5]) -> [T; W] {
+    let ops = decoder_4x9([op[0], op[1], op[2], T::constant(false)]);
+    let enabled = ops[1] | ops[2] | ops[3] | ops[4] | ops[5];
+    let left = ops[1] | ops[4];
+    let rotate = ops[4] | ops[5];
+    let arithmetic = ops[3];
+
+    let mut res = data;
+    for k in 0..5 {
+        let distance = 1 << k;
+        let selected = amount[k] & enabled;
+        let fill = arithmetic & res[W - 1];
+
+        let mut next = [T::constant(false); W];
+        for i in 0..W {
+            let to_left = if i >= distance { res[i - distance] } else { rotate & res[(i + W - distance % W) % W] };
+            let to_right = if i + distance < W { res[i + distance] } else { rotate & res[(i + distance) % W] | fill };
+            let shifted = left & to_left | !left & to_right;
+            next[i] = selected & shifted | !selected & res[i];
+        }
+        res = next;
+    }
     res
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::main_memory::{decode, encode, fast_decode, fast_encode};
+
     use super::*;
 
     #[test]
@@ -66,4 +124,63 @@ mod tests {
             assert_eq!(result[x], res[x - 8]);
         }
     }
+
+    #[quickcheck]
+    fn sra1_check(data: i32) {
+        let shifted = sra1(Bus32::from(fast_decode(data)), true);
+        assert_eq!(data >> 1, fast_encode(&shifted.data));
+    }
+
+    #[quickcheck]
+    fn disabled_shifters(data: i32) {
+        let bus = Bus32::from(fast_decode(data));
+        assert_eq!(data, fast_encode(&sll8(bus, false).data));
+
+        let bus = Bus32::from(fast_decode(data));
+        assert_eq!(data, fast_encode(&sra1(bus, false).data));
+    }
+
+    #[quickcheck]
+    fn srl1_check(data: i32) {
+        let shifted = srl1(Bus32::from(fast_decode(data)), true);
+        assert_eq!((data as u32 >> 1) as i32, fast_encode(&shifted.data));
+    }
+
+    fn barrel_i(data: i32, op: usize, amount: u32) -> i32 {
+        let mut op_bits = [false; 3];
+        for i in 0..3 {
+            op_bits[i] = op >> i & 1 == 1;
+        }
+        let mut amount_bits = [false; 5];
+        for i in 0..5 {
+            amount_bits[i] = amount >> i & 1 == 1;
+        }
+        fast_encode(&barrel(Bus32::from(fast_decode(data)), op_bits, amount_bits).data)
+    }
+
+    #[quickcheck]
+    fn barrel_check(data: i32, amount: u32) {
+        let amount = amount & 31;
+        assert_eq!(data, barrel_i(data, 0, amount));
+        assert_eq!(data << amount, barrel_i(data, 1, amount));
+        assert_eq!((data as u32 >> amount) as i32, barrel_i(data, 2, amount));
+        assert_eq!(data >> amount, barrel_i(data, 3, amount));
+        assert_eq!(data.rotate_left(amount), barrel_i(data, 4, amount));
+        assert_eq!(data.rotate_right(amount), barrel_i(data, 5, amount));
+    }
+
+    #[quickcheck]
+    fn barrel_check_16(data: i16, amount: u32) {
+        let amount = amount & 31;
+        let barrel_16 = |op: usize| {
+            let op_bits = [op & 1 == 1, op & 2 == 2, op & 4 == 4];
+            let amount_bits = [amount & 1 == 1, amount & 2 == 2, amount & 4 == 4, amount & 8 == 8, amount & 16 == 16];
+            encode(&barrel::<16>(Bus::from(decode(data as i64)), op_bits, amount_bits).data) as i16
+        };
+        assert_eq!(data.checked_shl(amount).unwrap_or(0), barrel_16(1));
+        assert_eq!((data as u16).checked_shr(amount).unwrap_or(0) as i16, barrel_16(2));
+        assert_eq!(data >> amount.min(15), barrel_16(3));
+        assert_eq!(data.rotate_left(amount), barrel_16(4));
+        assert_eq!(data.rotate_right(amount), barrel_16(5));
+    }
 }
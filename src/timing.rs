@@ -0,0 +1,285 @@
+use std::cell::Cell;
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+
+use crate::alu::{alu_word, Adder, AluControl, AluFlags};
+use crate::decoders::decoder_4x9;
+use crate::logic::Logic;
+use crate::memory::{ControlMemory, update_word};
+use crate::mir_layout::MirLayout;
+use crate::processor_elements::{stack_limits, BBusControls, BBusSources, MirFields};
+use crate::shifter::{barrel_word, sll8_word, sra1_word, srl1_word};
+
+thread_local! {
+    static GATES: Cell<usize> = const { Cell::new(0) };
+}
+
+/**
+ * Signal that keeps the amount of gates on the longest path from the inputs.
+ *
+ * Passing `Timed` signals through the gate functions builds no circuit, but every created gate
+ *   is counted. Gates with a constant input are folded away as a synthesizer would do,
+ *   so e.g. the control store depends on the microprogram in it.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Timed {
+    pub depth: usize,
+    constant: Option<bool>,
+}
+
+impl Timed {
+    /// Signal that is stable at the beginning of the subcycle
+    pub fn input() -> Timed { Timed { depth: 0, constant: None } }
+
+    fn gate(a: Timed, b: Timed) -> Timed {
+        GATES.with(|x| x.set(x.get() + 1));
+        Timed { depth: a.depth.max(b.depth) + 1, constant: None }
+    }
+}
+
+impl Logic for Timed {
+    fn constant(value: bool) -> Timed { Timed { depth: 0, constant: Some(value) } }
+}
+
+impl BitAnd for Timed {
+    type Output = Timed;
+
+    fn bitand(self, rhs: Timed) -> Timed {
+        match (self.constant, rhs.constant) {
+            (Some(false), _) | (_, Some(false)) => Timed::constant(false),
+            (Some(true), _) => rhs,
+            (_, Some(true)) => self,
+            _ => Timed::gate(self, rhs),
+        }
+    }
+}
+
+impl BitOr for Timed {
+    type Output = Timed;
+
+    fn bitor(self, rhs: Timed) -> Timed {
+        match (self.constant, rhs.constant) {
+            (Some(true), _) | (_, Some(true)) => Timed::constant(true),
+            (Some(false), _) => rhs,
+            (_, Some(false)) => self,
+            _ => Timed::gate(self, rhs),
+        }
+    }
+}
+
+impl BitXor for Timed {
+    type Output = Timed;
+
+    fn bitxor(self, rhs: Timed) -> Timed {
+        match (self.constant, rhs.constant) {
+            (Some(a), Some(b)) => Timed::constant(a ^ b),
+            (Some(false), _) => rhs,
+            (_, Some(false)) => self,
+            (Some(true), _) => !rhs,
+            (_, Some(true)) => !self,
+            _ => Timed::gate(self, rhs),
+        }
+    }
+}
+
+impl Not for Timed {
+    type Output = Timed;
+
+    fn not(self) -> Timed {
+        match self.constant {
+            Some(value) => Timed::constant(!value),
+            None => Timed::gate(self, self),
+        }
+    }
+}
+
+/// Depth of the longest path to any of the outputs and amount of gates created by the function
+pub fn measure<F: FnOnce() -> Vec<Timed>>(circuit: F) -> (usize, usize) {
+    GATES.with(|x| x.set(0));
+    let outputs = circuit();
+    let depth = outputs.iter().map(|x| x.depth).max().unwrap_or(0);
+    (depth, GATES.with(|x| x.get()))
+}
+
+pub struct SubcycleTiming {
+    pub name: &'static str,
+    pub depth: usize,
+    pub gates: usize,
+}
+
+pub struct TimingReport {
+    pub adder: Adder,
+    pub subcycles: Vec<SubcycleTiming>,
+    /// Delay of a single gate in nanoseconds
+    pub gate_delay: f64,
+}
+
+impl TimingReport {
+    /// Subcycles go one after another, so the whole cycle is as long as all of them together
+    pub fn cycle_depth(&self) -> usize { self.subcycles.iter().map(|x| x.depth).sum() }
+
+    pub fn gates(&self) -> usize { self.subcycles.iter().map(|x| x.gates).sum() }
+
+    /// Minimum clock period in nanoseconds
+    pub fn min_clock_period(&self) -> f64 { self.cycle_depth() as f64 * self.gate_delay }
+
+    /// Maximum clock frequency in MHz
+    pub fn max_frequency(&self) -> f64 { 1000.0 / self.min_clock_period() }
+}
+
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<40} {:>6} {:>8}", "Subcycle", "Depth", "Gates")?;
+        for (i, subcycle) in self.subcycles.iter().enumerate() {
+            writeln!(f, "{:<40} {:>6} {:>8}", format!("{}. {}", i + 1, subcycle.name), subcycle.depth, subcycle.gates)?;
+        }
+        writeln!(f, "{:<40} {:>6} {:>8}", "Cycle", self.cycle_depth(), self.gates())?;
+        write!(f, "Minimum clock period with {:?} adder: {:.2} ns ({:.1} MHz) at {} ns per gate",
+               self.adder, self.min_clock_period(), self.max_frequency(), self.gate_delay)
+    }
+}
+
+/**
+ * Longest combinational path of every subcycle of the Mic-1 cycle.
+ *
+ * 1. MPC selects the microinstruction in the control store and it is loaded into MIR
+ * 2. Registers are put on the B bus (H is always on the A bus)
+ * 3. ALU and shifter compute the C bus and N, Z, C, V bits
+ * 4. C bus is latched into registers and the next MPC is computed
+ *
+ * Every subcycle starts when the previous one is finished, so its inputs are measured from zero.
+ */
+pub fn analyze(control_memory: &ControlMemory, adder: Adder, gate_delay: f64) -> TimingReport {
+    let layout = control_memory.layout();
+    let (depth, gates) = measure(|| control_memory.get_word(&vec![Timed::input(); layout.address_width()]));
+    let control_store = SubcycleTiming { name: "Control store -> MIR", depth, gates };
+
+    let (depth, gates) = measure(|| b_bus().to_vec());
+    let b_bus = SubcycleTiming { name: "Registers -> B bus", depth, gates };
+
+    let (depth, gates) = measure(|| {
+        let (c_bus, flags) = alu_word([Timed::input(); 32], [Timed::input(); 32], &AluControl::from([Timed::input(); 6]), adder);
+        let shifted = srl1_word(sra1_word(sll8_word(c_bus, Timed::input()), Timed::input()), Timed::input());
+        let mir = MirFields::new(layout, vec![Timed::input(); layout.width()]);
+        let shifted = barrel_word(shifted, mir.shift_op(), mir.shift_amount(&[Timed::input(); 32]));
+        let mut outputs = shifted.to_vec();
+        outputs.extend_from_slice(&[flags.n, flags.z, flags.c, flags.v]);
+        outputs
+    });
+    let alu = SubcycleTiming { name: "ALU and shifter -> C bus", depth, gates };
+
+    let (depth, gates) = measure(|| write_back(layout, adder));
+    let write_back = SubcycleTiming { name: "C bus -> registers, next MPC", depth, gates };
+
+    TimingReport { adder, subcycles: vec![control_store, b_bus, alu, write_back], gate_delay }
+}
+
+/// Depth and amount of gates of the adder alone
+pub fn adder_timing(adder: Adder) -> (usize, usize) {
+    measure(|| {
+        let (sum, carry) = adder.add([Timed::input(); 32], [Timed::input(); 32], Timed::input());
+        let mut outputs = sum.to_vec();
+        outputs.push(carry);
+        outputs
+    })
+}
+
+fn b_bus() -> [Timed; 32] {
+    let register = [Timed::input(); 32];
+    let sources = BBusSources { mdr: register, pc: register, mbr: register, sp: register, lv: register, cpp: register, tos: register, opc: register };
+    sources.drive(&BBusControls::new(decoder_4x9([Timed::input(); 4])))
+}
+
+fn write_back(layout: &MirLayout, adder: Adder) -> Vec<Timed> {
+    let mir = MirFields::new(layout, vec![Timed::input(); layout.width()]);
+    let controls = mir.c_bus_controls();
+    let c_bus = [Timed::input(); 32];
+    let enables = [controls.h(), controls.opc(), controls.tos(), controls.cpp(), controls.lv(),
+        controls.sp(), controls.pc(), controls.mdr(), controls.mar()];
+
+    let mut outputs = Vec::new();
+    for enabled in enables.iter() {
+        let mut register = [Timed::input(); 32];
+        update_word(&mut register, &c_bus, *enabled);
+        outputs.extend_from_slice(&register);
+    }
+
+    // Stack-limit registers are compared with the C bus
+    let (below, above) = stack_limits(&c_bus, &[Timed::input(); 32], &[Timed::input(); 32], adder);
+    let next_command = mir.next_address(&[Timed::input(); 32], &AluFlags { n: Timed::input(), z: Timed::input(), c: Timed::input(), v: Timed::input() }, Timed::input(), below | above);
+    outputs.extend_from_slice(&next_command);
+
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::make_control_memory;
+
+    use super::*;
+
+    #[test]
+    fn ripple_carry() {
+        let (depth, gates) = adder_timing(Adder::Ripple);
+        // Carry goes through two gates in every of 32 adders, the last sum bit has one more XOR
+        assert_eq!(2 * 32 + 1, depth);
+        assert_eq!(5 * 32, gates);
+    }
+
+    #[test]
+    fn faster_adders() {
+        let (ripple_depth, ripple_gates) = adder_timing(Adder::Ripple);
+        for adder in [Adder::CarryLookahead, Adder::CarrySelect].iter() {
+            let (depth, gates) = adder_timing(*adder);
+            assert!(depth < ripple_depth, "{:?}: {}", adder, depth);
+            assert!(gates > ripple_gates, "{:?}: {}", adder, gates);
+        }
+    }
+
+    #[test]
+    fn lookahead_shortens_the_cycle() {
+        let ripple = analyze(&make_control_memory(), Adder::Ripple, 1.0);
+        let lookahead = analyze(&make_control_memory(), Adder::CarryLookahead, 1.0);
+        assert!(lookahead.cycle_depth() < ripple.cycle_depth());
+    }
+
+    #[test]
+    fn decoder() {
+        let (depth, gates) = measure(|| decoder_4x9([Timed::input(); 4]).to_vec());
+        // Inverter, 2x4 decoder and the final AND
+        assert_eq!(3, depth);
+        // Two 2x4 decoders with 4 inverters and 4 ANDs, then 16 ANDs
+        assert_eq!(2 * 8 + 16, gates);
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        let (depth, gates) = measure(|| vec![Timed::input() & Timed::constant(true) | Timed::constant(false)]);
+        assert_eq!(0, depth);
+        assert_eq!(0, gates);
+    }
+
+    #[test]
+    fn gate_delay() {
+        let report = analyze(&make_control_memory(), Adder::Ripple, 0.5);
+        assert_eq!(4, report.subcycles.len());
+        assert_eq!(report.cycle_depth() as f64 * 0.5, report.min_clock_period());
+
+        let slow_report = analyze(&make_control_memory(), Adder::Ripple, 1.0);
+        assert_eq!(report.min_clock_period() * 2.0, slow_report.min_clock_period());
+    }
+
+    #[test]
+    fn alu_is_the_longest_subcycle() {
+        let report = analyze(&make_control_memory(), Adder::Ripple, 1.0);
+        let longest = report.subcycles.iter().max_by_key(|x| x.depth).unwrap();
+        assert_eq!("ALU and shifter -> C bus", longest.name);
+
+        let rendered = report.to_string();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(report.subcycles.len() + 3, lines.len());
+        assert!(lines[3].starts_with("3. ALU and shifter -> C bus"));
+        assert!(lines[3].contains(&format!(" {} ", longest.depth)));
+        assert!(lines.last().unwrap().starts_with("Minimum clock period with Ripple adder"));
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FaultKind {
+    /// Read through MAR
+    Read,
+    /// Write through MAR
+    Write,
+    /// Read of the method area through PC
+    Fetch,
+    /// The microprogram got to the stack overflow routine, the address is SP
+    StackOverflow,
+    /// The microprogram got to the stack underflow routine, the address is SP
+    StackUnderflow,
+}
+
+/**
+ * Access to an address that is neither a memory cell nor a mapped device, or a stack check of the microprogram.
+ *
+ * The access is dropped by the memory, then the processor either stops or jumps to the fault handler.
+ *   Stack faults always stop the processor.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub address: i64,
+    pub cycle: usize,
+    /// Address of the microinstruction that made the access
+    pub mpc: usize,
+    pub pc: i64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} of invalid address {} at cycle {}, MPC 0x{:03X}, PC {}", self.kind, self.address, self.cycle, self.mpc, self.pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_processor, PROGRAM_START};
+    use crate::main_memory::MEMORY_SIZE;
+    use crate::microasm::MicroAsm::{iload2, istore4, stack_overflow1, stack_underflow1};
+    use crate::parser::parse;
+    use crate::processor::Mic1;
+
+    use super::*;
+
+    fn run(program: &str) -> Mic1 {
+        let commands = parse(program);
+        let mut mic1 = create_processor(&commands, vec![1, 2], [0; 10]);
+        mic1.run(commands.len() + 1, PROGRAM_START);
+        mic1
+    }
+
+    /// Runs the program with the stack until the processor is halted, checks that the program is not overwritten
+    fn run_to_stack_fault(program: &str, stack: Vec<i32>, constants: [i32; 10]) -> Fault {
+        let commands = parse(program);
+        let mut mic1 = create_processor(&commands, stack, constants);
+        mic1.run_n_times(5000);
+        assert!(mic1.halted());
+        assert_eq!(commands, mic1.main_memory.cells()[PROGRAM_START..PROGRAM_START + commands.len()].to_vec());
+        mic1.fault.unwrap()
+    }
+
+    #[test]
+    fn read() {
+        // MBRU is sign-extended, so the index is -128
+        let mic1 = run("ILOAD 0x80\nBIPUSH 0x05");
+        let fault = mic1.fault.unwrap();
+        assert_eq!(FaultKind::Read, fault.kind);
+        assert_eq!(-118, fault.address);
+        assert_eq!(iload2 as usize, fault.mpc);
+        assert!(mic1.halted());
+        assert_eq!(format!("Read of invalid address -118 at cycle {}, MPC 0x{:03X}, PC {}", fault.cycle, fault.mpc, fault.pc), fault.to_string());
+    }
+
+    #[test]
+    fn write() {
+        let mic1 = run("ISTORE 0x80");
+        let fault = mic1.fault.unwrap();
+        assert_eq!(FaultKind::Write, fault.kind);
+        assert_eq!(-118, fault.address);
+        assert_eq!(istore4 as usize, fault.mpc);
+    }
+
+    #[test]
+    fn fetch() {
+        let mic1 = run("GOTO 0x7F 0x00");
+        let fault = mic1.fault.unwrap();
+        assert_eq!(FaultKind::Fetch, fault.kind);
+        assert!(fault.address >= MEMORY_SIZE as i64);
+        assert_eq!(fault.address, fault.pc);
+    }
+
+    #[test]
+    fn halted_processor_stays() {
+        let mut mic1 = run("ILOAD 0x80");
+        let cycles = mic1.cycles;
+        mic1.run_n_times(10);
+        mic1.step_instruction();
+        assert_eq!(cycles, mic1.cycles);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let fault = run_to_stack_fault("BIPUSH 0x01\nGOTO 0xFF 0xFE", vec![], [0; 10]);
+        assert_eq!(FaultKind::StackOverflow, fault.kind);
+        assert_eq!(PROGRAM_START as i64, fault.address);
+        assert_eq!(stack_overflow1 as usize, fault.mpc);
+    }
+
+    #[test]
+    fn deep_recursion() {
+        // The method pushes OBJREF and calls itself
+        let program = "INVOKEVIRTUAL 0x00 0x00\n0x00 0x01\n0x00 0x00\nBIPUSH 0x00\nINVOKEVIRTUAL 0x00 0x00";
+        let fault = run_to_stack_fault(program, vec![0], [PROGRAM_START as i32 + 3, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(FaultKind::StackOverflow, fault.kind);
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let programs = [("POP", vec![]), ("ISTORE 0x00", vec![]), ("IADD", vec![]), ("IADD", vec![1]), ("BIPUSH 0x01\nIF_ICMPEQ 0x00 0x03", vec![])];
+        for (program, stack) in programs {
+            let fault = run_to_stack_fault(program, stack.clone(), [0; 10]);
+            assert_eq!(FaultKind::StackUnderflow, fault.kind, "{} {:?}", program, stack);
+            assert_eq!(stack_underflow1 as usize, fault.mpc);
+        }
+        // The last value can be popped
+        let mic1 = run("POP\nPOP");
+        assert_eq!(None, mic1.fault);
+    }
+}
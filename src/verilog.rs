@@ -0,0 +1,592 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+use std::path::Path;
+
+use crate::alu::{alu_word, Adder};
+use crate::decoders::decoder_4x9;
+use crate::logic::Logic;
+use crate::main_memory::fast_encode;
+use crate::memory::ControlMemory;
+use crate::mir_layout::MirLayout;
+use crate::processor::Mic1;
+use crate::processor_elements::{stack_limits, BBusControls, BBusSources, MirFields};
+use crate::shifter::{barrel_word, sll8_word, sra1_word, srl1_word};
+use crate::{PROGRAM_START, STACK_START};
+
+/// Registers that are the inputs of the datapath
+const DATAPATH_REGISTERS: [&str; 9] = ["h", "opc", "tos", "cpp", "lv", "sp", "pc", "mdr", "mbr"];
+
+/// Registers that are compared by the testbench, in the order of `TraceStep::registers`
+pub const TRACED_REGISTERS: [&str; 10] = ["mar", "mdr", "pc", "mbr", "sp", "lv", "cpp", "tos", "opc", "h"];
+
+#[derive(Copy, Clone, Debug)]
+enum Gate {
+    And(Wire, Wire),
+    Or(Wire, Wire),
+    Xor(Wire, Wire),
+    Not(Wire),
+}
+
+#[derive(Default)]
+struct Netlist {
+    inputs: Vec<String>,
+    gates: Vec<Gate>,
+}
+
+thread_local! {
+    static NETLIST: RefCell<Netlist> = RefCell::new(Netlist::default());
+}
+
+/**
+ * Signal that records every gate it goes through into the netlist.
+ *
+ * The same gate functions that simulate the processor produce the Verilog netlist,
+ *   gates with a constant input are folded away.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Wire {
+    Constant(bool),
+    Input(usize),
+    Gate(usize),
+}
+
+impl Wire {
+    fn input(name: String) -> Wire {
+        NETLIST.with(|x| {
+            let mut netlist = x.borrow_mut();
+            netlist.inputs.push(name);
+            Wire::Input(netlist.inputs.len() - 1)
+        })
+    }
+
+    fn gate(gate: Gate) -> Wire {
+        NETLIST.with(|x| {
+            let mut netlist = x.borrow_mut();
+            netlist.gates.push(gate);
+            Wire::Gate(netlist.gates.len() - 1)
+        })
+    }
+
+    fn name(self, netlist: &Netlist) -> String {
+        match self {
+            Wire::Constant(value) => format!("1'b{}", value as u8),
+            Wire::Input(index) => netlist.inputs[index].clone(),
+            Wire::Gate(index) => format!("g{}", index),
+        }
+    }
+
+    fn constant_value(self) -> Option<bool> {
+        match self {
+            Wire::Constant(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl Logic for Wire {
+    fn constant(value: bool) -> Wire { Wire::Constant(value) }
+}
+
+impl BitAnd for Wire {
+    type Output = Wire;
+
+    fn bitand(self, rhs: Wire) -> Wire {
+        match (self.constant_value(), rhs.constant_value()) {
+            (Some(false), _) | (_, Some(false)) => Wire::Constant(false),
+            (Some(true), _) => rhs,
+            (_, Some(true)) => self,
+            _ => Wire::gate(Gate::And(self, rhs)),
+        }
+    }
+}
+
+impl BitOr for Wire {
+    type Output = Wire;
+
+    fn bitor(self, rhs: Wire) -> Wire {
+        match (self.constant_value(), rhs.constant_value()) {
+            (Some(true), _) | (_, Some(true)) => Wire::Constant(true),
+            (Some(false), _) => rhs,
+            (_, Some(false)) => self,
+            _ => Wire::gate(Gate::Or(self, rhs)),
+        }
+    }
+}
+
+impl BitXor for Wire {
+    type Output = Wire;
+
+    fn bitxor(self, rhs: Wire) -> Wire {
+        match (self.constant_value(), rhs.constant_value()) {
+            (Some(a), Some(b)) => Wire::Constant(a ^ b),
+            (Some(false), _) => rhs,
+            (_, Some(false)) => self,
+            (Some(true), _) => !rhs,
+            (_, Some(true)) => !self,
+            _ => Wire::gate(Gate::Xor(self, rhs)),
+        }
+    }
+}
+
+impl Not for Wire {
+    type Output = Wire;
+
+    fn not(self) -> Wire {
+        match self {
+            Wire::Constant(value) => Wire::Constant(!value),
+            _ => Wire::gate(Gate::Not(self)),
+        }
+    }
+}
+
+/**
+ * Combinational part of the processor: B bus, ALU, shifter and the next address logic.
+ *
+ * `registers` are in the order of `DATAPATH_REGISTERS`, MDR and MBR already contain the data
+ *   that came from the memory in this cycle. `stack` are the stack base and limit registers.
+ *   Outputs are named Verilog ports with their wires.
+ */
+fn datapath<T: Logic>(layout: &MirLayout, mir: Vec<T>, registers: &[[T; 32]; 9], mem_ready: T, stack: &[[T; 32]; 2], adder: Adder) -> Vec<(&'static str, Vec<T>)> {
+    let mir = MirFields::new(layout, mir);
+    let [h, opc, tos, cpp, lv, sp, pc, mdr, mbr] = *registers;
+
+    let sources = BBusSources { mdr, pc, mbr, sp, lv, cpp, tos, opc };
+    let b_bus = sources.drive(&BBusControls::new(decoder_4x9(mir.b_bus_controls())));
+
+    let (c_bus, flags) = alu_word(h, b_bus, &mir.alu_controls(), adder);
+    let c_bus = srl1_word(sra1_word(sll8_word(c_bus, mir.sll8()), mir.sra1()), mir.srl1());
+    let c_bus = barrel_word(c_bus, mir.shift_op(), mir.shift_amount(&h));
+
+    let (below, above) = stack_limits(&c_bus, &stack[0], &stack[1], adder);
+    let next_mpc = mir.next_address(&mbr, &flags, mem_ready, below | above);
+
+    let controls = mir.c_bus_controls();
+    let c_enable = vec![controls.h(), controls.opc(), controls.tos(), controls.cpp(), controls.lv(),
+                        controls.sp(), controls.pc(), controls.mdr(), controls.mar()];
+
+    vec![
+        ("c_bus", c_bus.to_vec()),
+        ("n", vec![flags.n]),
+        ("z", vec![flags.z]),
+        ("c", vec![flags.c]),
+        ("v", vec![flags.v]),
+        ("next_mpc", next_mpc),
+        ("c_enable", c_enable),
+        ("mem_read", vec![mir.read()]),
+        ("mem_write", vec![mir.write()]),
+        ("fetch", vec![mir.fetch()]),
+    ]
+}
+
+fn input_word<T: Logic, F: FnMut(usize) -> T>(mut wire: F) -> [T; 32] {
+    let mut res = [T::constant(false); 32];
+    for (i, bit) in res.iter_mut().enumerate() {
+        *bit = wire(i);
+    }
+    res
+}
+
+fn datapath_netlist(layout: &MirLayout, adder: Adder) -> (Netlist, Vec<(&'static str, Vec<Wire>)>) {
+    NETLIST.with(|x| x.replace(Netlist::default()));
+
+    let mir: Vec<Wire> = (0..layout.width()).map(|i| Wire::input(format!("mir[{}]", i))).collect();
+    let mut registers = [[Wire::Constant(false); 32]; 9];
+    for (register, name) in registers.iter_mut().zip(DATAPATH_REGISTERS.iter()) {
+        *register = input_word(|i| Wire::input(format!("{}[{}]", name, i)));
+    }
+
+    let mem_ready = Wire::input("mem_ready".to_string());
+    let stack = [input_word(|i| Wire::input(format!("stack_base[{}]", i))), input_word(|i| Wire::input(format!("stack_limit[{}]", i)))];
+    let outputs = datapath(layout, mir, &registers, mem_ready, &stack, adder);
+    (NETLIST.with(|x| x.replace(Netlist::default())), outputs)
+}
+
+fn port(direction: &str, name: &str, width: usize) -> String {
+    if width == 1 {
+        format!("    {} {}", direction, name)
+    } else {
+        format!("    {} [{}:0] {}", direction, width - 1, name)
+    }
+}
+
+fn datapath_module(layout: &MirLayout, adder: Adder) -> String {
+    let (netlist, outputs) = datapath_netlist(layout, adder);
+
+    let mut ports = vec![port("input", "mir", layout.width())];
+    for name in DATAPATH_REGISTERS.iter() {
+        ports.push(port("input", name, 32));
+    }
+    ports.push(port("input", "mem_ready", 1));
+    ports.push(port("input", "stack_base", 32));
+    ports.push(port("input", "stack_limit", 32));
+    for (name, wires) in outputs.iter() {
+        ports.push(port("output", name, wires.len()));
+    }
+
+    let mut res = format!("// Generated from the gate functions with {:?} adder\n", adder);
+    res += &format!("module mic1_datapath(\n{}\n);\n", ports.join(",\n"));
+    for (i, gate) in netlist.gates.iter().enumerate() {
+        let expression = match gate {
+            Gate::And(a, b) => format!("{} & {}", a.name(&netlist), b.name(&netlist)),
+            Gate::Or(a, b) => format!("{} | {}", a.name(&netlist), b.name(&netlist)),
+            Gate::Xor(a, b) => format!("{} ^ {}", a.name(&netlist), b.name(&netlist)),
+            Gate::Not(a) => format!("~{}", a.name(&netlist)),
+        };
+        res += &format!("    wire g{} = {};\n", i, expression);
+    }
+    for (name, wires) in outputs.iter() {
+        for (i, wire) in wires.iter().enumerate() {
+            if wires.len() == 1 {
+                res += &format!("    assign {} = {};\n", name, wire.name(&netlist));
+            } else {
+                res += &format!("    assign {}[{}] = {};\n", name, i, wire.name(&netlist));
+            }
+        }
+    }
+    res += "endmodule\n";
+    res
+}
+
+fn control_store_module(control_memory: &ControlMemory) -> String {
+    let layout = control_memory.layout();
+    let address_width = layout.address_width();
+    let mut res = String::from("// Control store initialised from the microprogram\n");
+    res += &format!("module mic1_control_store(\n    input [{}:0] address,\n    output reg [{}:0] data\n);\n", address_width - 1, layout.width() - 1);
+    res += "    always @(*) begin\n        case (address)\n";
+    for address in 0..layout.control_store_size() {
+        let word = control_memory.read_address(address);
+        if word.iter().all(|x| !*x) {
+            continue;
+        }
+        let bits: String = word.iter().rev().map(|x| if *x { '1' } else { '0' }).collect();
+        res += &format!("            {}'d{}: data = {}'b{};\n", address_width, address, layout.width(), bits);
+    }
+    res += &format!("            default: data = {}'b0;\n        endcase\n    end\nendmodule\n", layout.width());
+    res
+}
+
+const REGISTER_MODULES: &str = r#"// The simulator updates its DLatch arrays once per microinstruction, so registers are built from flip-flops
+module dff #(parameter INIT = 1'b0) (
+    input clk,
+    input d,
+    input update,
+    output reg q
+);
+    initial q = INIT;
+    always @(posedge clk)
+        if (update) q <= d;
+endmodule
+
+module mic1_register #(parameter WIDTH = 32, parameter [WIDTH - 1:0] INIT = 0) (
+    input clk,
+    input [WIDTH - 1:0] d,
+    input update,
+    output [WIDTH - 1:0] q
+);
+    genvar i;
+    generate
+        for (i = 0; i < WIDTH; i = i + 1) begin : flip_flops
+            dff #(.INIT(INIT[i])) flip_flop(.clk(clk), .d(d[i]), .update(update), .q(q[i]));
+        end
+    endgenerate
+endmodule
+"#;
+
+fn top_module(layout: &MirLayout) -> String {
+    let address_width = layout.address_width();
+    let mut parameters = vec![format!("    parameter [{}:0] INIT_MPC = {}'d0", address_width - 1, address_width)];
+    for name in TRACED_REGISTERS.iter() {
+        parameters.push(format!("    parameter [31:0] INIT_{} = 32'd0", name.to_uppercase()));
+    }
+    parameters.push(format!("    parameter [31:0] STACK_BASE = 32'd{}", STACK_START));
+    parameters.push(format!("    parameter [31:0] STACK_LIMIT = 32'd{}", PROGRAM_START - 1));
+
+    let mut res = format!("module mic1 #(\n{}\n) (\n", parameters.join(",\n"));
+    res += r#"    input clk,
+    // MAR is the address of both reading and writing
+    output [31:0] mem_addr,
+    output mem_read,
+    output mem_write,
+    output [31:0] mem_write_data,
+    input mem_read_valid,
+    input [31:0] mem_read_data,
+    output [31:0] fetch_addr,
+    output fetch,
+    input fetch_valid,
+    input [31:0] fetch_data,
+    // All requested data arrives in the next cycle
+    input mem_ready
+);
+"#;
+    res += &format!("    wire [{}:0] mpc, next_mpc;\n", address_width - 1);
+    res += &format!("    wire [{}:0] mir;\n", layout.width() - 1);
+    res += r#"    wire [31:0] mar, mdr, pc, mbr, sp, lv, cpp, tos, opc, h;
+    wire [31:0] c_bus;
+    wire n, z, c, v;
+    wire [8:0] c_enable;
+
+    // MDR and MBR take the data from the memory before the datapath uses them
+    wire [31:0] mdr_in = mem_read_valid ? mem_read_data : mdr;
+    wire [31:0] mbr_in = fetch_valid ? fetch_data : mbr;
+
+    mic1_control_store control_store(.address(mpc), .data(mir));
+
+    mic1_datapath datapath(
+        .mir(mir), .h(h), .opc(opc), .tos(tos), .cpp(cpp), .lv(lv), .sp(sp), .pc(pc), .mdr(mdr_in), .mbr(mbr_in), .mem_ready(mem_ready),
+        .stack_base(STACK_BASE), .stack_limit(STACK_LIMIT),
+        .c_bus(c_bus), .n(n), .z(z), .c(c), .v(v), .next_mpc(next_mpc), .c_enable(c_enable),
+        .mem_read(mem_read), .mem_write(mem_write), .fetch(fetch)
+    );
+
+    // Memory requests use the values that are written into registers in this cycle
+    wire [31:0] mdr_next = c_enable[7] ? c_bus : mdr_in;
+    assign mem_addr = c_enable[8] ? c_bus : mar;
+    assign mem_write_data = mdr_next;
+    assign fetch_addr = c_enable[6] ? c_bus : pc;
+
+"#;
+    res += &format!("    mic1_register #(.WIDTH({}), .INIT(INIT_MPC)) mpc_register(.clk(clk), .d(next_mpc), .update(1'b1), .q(mpc));\n", address_width);
+    res += r#"    // C bus
+    mic1_register #(.INIT(INIT_H)) h_register(.clk(clk), .d(c_bus), .update(c_enable[0]), .q(h));
+    mic1_register #(.INIT(INIT_OPC)) opc_register(.clk(clk), .d(c_bus), .update(c_enable[1]), .q(opc));
+    mic1_register #(.INIT(INIT_TOS)) tos_register(.clk(clk), .d(c_bus), .update(c_enable[2]), .q(tos));
+    mic1_register #(.INIT(INIT_CPP)) cpp_register(.clk(clk), .d(c_bus), .update(c_enable[3]), .q(cpp));
+    mic1_register #(.INIT(INIT_LV)) lv_register(.clk(clk), .d(c_bus), .update(c_enable[4]), .q(lv));
+    mic1_register #(.INIT(INIT_SP)) sp_register(.clk(clk), .d(c_bus), .update(c_enable[5]), .q(sp));
+    mic1_register #(.INIT(INIT_PC)) pc_register(.clk(clk), .d(c_bus), .update(c_enable[6]), .q(pc));
+    mic1_register #(.INIT(INIT_MDR)) mdr_register(.clk(clk), .d(mdr_next), .update(1'b1), .q(mdr));
+    mic1_register #(.INIT(INIT_MAR)) mar_register(.clk(clk), .d(c_bus), .update(c_enable[8]), .q(mar));
+    mic1_register #(.INIT(INIT_MBR)) mbr_register(.clk(clk), .d(mbr_in), .update(1'b1), .q(mbr));
+endmodule
+"#;
+    res
+}
+
+/// Verilog modules of the whole processor with the control store containing the microprogram
+pub fn export(control_memory: &ControlMemory, adder: Adder) -> String {
+    let layout = control_memory.layout();
+    [REGISTER_MODULES.to_string(), control_store_module(control_memory), datapath_module(layout, adder), top_module(layout)].join("\n")
+}
+
+/// State of the processor after the cycle
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceStep {
+    pub mpc: usize,
+    pub registers: [i32; 10],
+}
+
+pub struct Trace {
+    /// Width of MPC of the traced processor
+    pub mpc_width: usize,
+    pub initial: TraceStep,
+    /// Stack base and limit registers of the traced processor
+    pub stack: [i32; 2],
+    pub memory: Vec<i32>,
+    pub steps: Vec<TraceStep>,
+}
+
+fn snapshot(mic1: &Mic1) -> TraceStep {
+    let registers = [mic1.mar, mic1.mdr, mic1.pc, mic1.mbr, mic1.sp, mic1.lv, mic1.cpp, mic1.tos, mic1.opc, mic1.h];
+    let mut values = [0; 10];
+    for i in 0..10 {
+        values[i] = fast_encode(&registers[i].get());
+    }
+    TraceStep { mpc: mic1.mpc_address(), registers: values }
+}
+
+/// Runs the processor and records registers after every cycle. The processor shouldn't have pending reads.
+pub fn record_trace(mic1: &mut Mic1, cycles: usize) -> Trace {
+    let initial = snapshot(mic1);
+    let memory = mic1.main_memory.cells().to_vec();
+    let mut steps = Vec::new();
+    for _ in 0..cycles {
+        mic1.execute_command();
+        steps.push(snapshot(mic1));
+    }
+    let stack = [fast_encode(&mic1.stack_base.get()), fast_encode(&mic1.stack_limit.get())];
+    Trace { mpc_width: mic1.layout().address_width(), initial, stack, memory, steps }
+}
+
+fn hex(value: i32) -> String { format!("32'h{:08x}", value as u32) }
+
+/// Testbench that starts `mic1` from the initial state of the trace and compares every cycle with it
+pub fn testbench(trace: &Trace) -> String {
+    let mut parameters = vec![format!(".INIT_MPC({}'d{})", trace.mpc_width, trace.initial.mpc)];
+    for (name, value) in TRACED_REGISTERS.iter().zip(trace.initial.registers.iter()) {
+        parameters.push(format!(".INIT_{}({})", name.to_uppercase(), hex(*value)));
+    }
+    parameters.push(format!(".STACK_BASE({})", hex(trace.stack[0])));
+    parameters.push(format!(".STACK_LIMIT({})", hex(trace.stack[1])));
+
+    let mut res = String::from("`timescale 1ns / 1ps\nmodule mic1_tb;\n");
+    res += r#"    reg clk = 1'b0;
+    wire [31:0] mem_addr, mem_write_data, fetch_addr;
+    wire mem_read, mem_write, fetch;
+
+    reg [31:0] memory [0:511];
+    // Reads take two cycles like in MainMemory
+    reg read_1 = 1'b0, read_2 = 1'b0, fetch_1 = 1'b0, fetch_2 = 1'b0;
+    reg [31:0] read_addr_1 = 0, read_addr_2 = 0, fetch_addr_1 = 0, fetch_addr_2 = 0;
+    wire [31:0] mem_read_data = memory[read_addr_2[8:0]];
+    wire [31:0] fetch_data = memory[fetch_addr_2[8:0]];
+
+    always @(posedge clk) begin
+        read_1 <= mem_read;
+        read_addr_1 <= mem_addr;
+        read_2 <= read_1;
+        read_addr_2 <= read_addr_1;
+        fetch_1 <= fetch;
+        fetch_addr_1 <= fetch_addr;
+        fetch_2 <= fetch_1;
+        fetch_addr_2 <= fetch_addr_1;
+        if (mem_write) memory[mem_addr[8:0]] <= mem_write_data;
+    end
+
+"#;
+    res += &format!("    mic1 #({}) dut(\n", parameters.join(", "));
+    res += r#"        .clk(clk), .mem_addr(mem_addr), .mem_read(mem_read), .mem_write(mem_write), .mem_write_data(mem_write_data),
+        .mem_read_valid(read_2), .mem_read_data(mem_read_data),
+        .fetch_addr(fetch_addr), .fetch(fetch), .fetch_valid(fetch_2), .fetch_data(fetch_data),
+        // Only the requests of this cycle are not ready, the earlier ones arrive in the next cycle
+        .mem_ready(!(mem_read | fetch))
+    );
+
+    integer errors = 0;
+    integer i;
+
+    task step;
+        begin
+            #5 clk = 1'b1;
+            #5 clk = 1'b0;
+        end
+    endtask
+
+"#;
+    let arguments: Vec<String> = TRACED_REGISTERS.iter().map(|x| format!("input [31:0] {}", x)).collect();
+    res += &format!("    task check(input integer cycle, input [{}:0] mpc, {});\n        begin\n", trace.mpc_width - 1, arguments.join(", "));
+    let mut comparison = vec!["dut.mpc !== mpc".to_string()];
+    for name in TRACED_REGISTERS.iter() {
+        comparison.push(format!("dut.{} !== {}", name, name));
+    }
+    res += &format!("            if ({}) begin\n", comparison.join(" || "));
+    res += "                $display(\"Mismatch after cycle %0d, expected MPC %0d, got %0d\", cycle, mpc, dut.mpc);\n";
+    res += "                errors = errors + 1;\n            end\n        end\n    endtask\n\n";
+
+    res += "    initial begin\n        for (i = 0; i < 512; i = i + 1) memory[i] = 0;\n";
+    for (address, value) in trace.memory.iter().enumerate() {
+        if *value != 0 {
+            res += &format!("        memory[{}] = {};\n", address, hex(*value));
+        }
+    }
+    for (cycle, step) in trace.steps.iter().enumerate() {
+        let values: Vec<String> = step.registers.iter().map(|x| hex(*x)).collect();
+        res += &format!("        step; check({}, {}'d{}, {});\n", cycle + 1, trace.mpc_width, step.mpc, values.join(", "));
+    }
+    res += r#"        if (errors == 0) $display("PASSED");
+        else $display("FAILED: %0d mismatches", errors);
+        $finish;
+    end
+endmodule
+"#;
+    res
+}
+
+/// Writes `mic1.v` with the processor and `mic1_tb.v` with the testbench replaying the trace
+pub fn export_to(directory: &Path, control_memory: &ControlMemory, adder: Adder, trace: &Trace) -> io::Result<()> {
+    fs::write(directory.join("mic1.v"), export(control_memory, adder))?;
+    fs::write(directory.join("mic1_tb.v"), testbench(trace))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{create_processor, make_control_memory, make_control_memory_with_layout};
+    use crate::main_memory::fast_decode;
+    use crate::microasm::MicroAsm::Main1;
+    use crate::parser::parse;
+
+    use super::*;
+
+    fn evaluate(wire: Wire, inputs: &[bool], gates: &[bool]) -> bool {
+        match wire {
+            Wire::Constant(value) => value,
+            Wire::Input(index) => inputs[index],
+            Wire::Gate(index) => gates[index],
+        }
+    }
+
+    fn simulate(netlist: &Netlist, inputs: &[bool]) -> Vec<bool> {
+        let mut gates = Vec::new();
+        for gate in netlist.gates.iter() {
+            let value = match *gate {
+                Gate::And(a, b) => evaluate(a, inputs, &gates) & evaluate(b, inputs, &gates),
+                Gate::Or(a, b) => evaluate(a, inputs, &gates) | evaluate(b, inputs, &gates),
+                Gate::Xor(a, b) => evaluate(a, inputs, &gates) ^ evaluate(b, inputs, &gates),
+                Gate::Not(a) => !evaluate(a, inputs, &gates),
+            };
+            gates.push(value);
+        }
+        gates
+    }
+
+    #[quickcheck]
+    fn netlist_is_the_datapath(mir: u64, registers: Vec<i32>, mem_ready: bool, stack_base: i32, stack_limit: i32) {
+        let layout = MirLayout::standard();
+        let mir_bits: Vec<bool> = (0..layout.width()).map(|i| mir & (1 << i) != 0).collect();
+        let mut register_bits = [[false; 32]; 9];
+        for (i, bits) in register_bits.iter_mut().enumerate() {
+            *bits = fast_decode(*registers.get(i).unwrap_or(&0));
+        }
+
+        let (netlist, outputs) = datapath_netlist(&layout, Adder::CarryLookahead);
+        let mut inputs = mir_bits.to_vec();
+        for register in register_bits.iter() {
+            inputs.extend_from_slice(register);
+        }
+        inputs.push(mem_ready);
+        let stack = [fast_decode(stack_base), fast_decode(stack_limit)];
+        inputs.extend_from_slice(&stack[0]);
+        inputs.extend_from_slice(&stack[1]);
+        let gates = simulate(&netlist, &inputs);
+
+        let expected = datapath(&layout, mir_bits, &register_bits, mem_ready, &stack, Adder::CarryLookahead);
+        for ((name, wires), (_, values)) in outputs.iter().zip(expected.iter()) {
+            let actual: Vec<bool> = wires.iter().map(|x| evaluate(*x, &inputs, &gates)).collect();
+            assert_eq!(values, &actual, "Output: {}", name);
+        }
+    }
+
+    #[test]
+    fn control_store_rom() {
+        let layout = MirLayout::standard();
+        let verilog = control_store_module(&make_control_memory());
+        let bits: String = Main1.command(&layout).iter().rev().map(|x| if *x { '1' } else { '0' }).collect();
+        assert!(verilog.contains(&format!("9'd1: data = {}'b{};", layout.width(), bits)));
+        assert!(verilog.contains(&format!("default: data = {}'b0;", layout.width())));
+    }
+
+    #[test]
+    fn wider_control_store() {
+        let layout = MirLayout::standard().resize("addr", 10);
+        let verilog = export(&make_control_memory_with_layout(layout.clone()), Adder::Ripple);
+        assert!(verilog.contains("input [9:0] address"));
+        assert!(verilog.contains("10'd1: data = "));
+        assert!(verilog.contains(&format!("input [{}:0] mir", layout.width() - 1)));
+        assert!(verilog.contains("mic1_register #(.WIDTH(10), .INIT(INIT_MPC))"));
+    }
+
+    #[test]
+    fn trace_testbench() {
+        let mut mic1 = create_processor(&parse("BIPUSH 0x05\nIADD"), vec![1], [0; 10]);
+        let trace = record_trace(&mut mic1, 10);
+
+        assert_eq!(10, trace.steps.len());
+        assert_eq!(trace.steps[9], snapshot(&mic1));
+
+        let verilog = testbench(&trace);
+        assert_eq!(10, verilog.matches("step; check(").count());
+        assert!(verilog.contains(".INIT_PC(32'h00000063)"));
+        assert!(verilog.contains(".STACK_BASE(32'h0000000a), .STACK_LIMIT(32'h00000063)"));
+    }
+}